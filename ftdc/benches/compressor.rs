@@ -0,0 +1,165 @@
+// Copyright [2024] [Mark Benvenuto]
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Time and heap use of `BSONMetricsCompressor` for a collector with thousands of metrics
+//!
+//! Run with `cargo bench -p ftdc --bench compressor`, pass `<metrics> <samples>` after `--` to
+//! change the shape.
+
+use std::alloc::GlobalAlloc;
+use std::alloc::Layout;
+use std::alloc::System;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use std::time::Instant;
+
+use bson::doc;
+use bson::Document;
+use bson::RawDocumentBuf;
+use chrono::TimeZone;
+use chrono::Utc;
+use ftdc::writer::AddResult;
+use ftdc::writer::BSONMetricsCompressor;
+
+/// System allocator that counts allocations and tracks the peak of live bytes
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static LIVE: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+fn track_alloc(size: usize) {
+    ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+    ALLOCATED.fetch_add(size, Ordering::Relaxed);
+    let live = LIVE.fetch_add(size, Ordering::Relaxed) + size;
+    PEAK.fetch_max(live, Ordering::Relaxed);
+}
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        track_alloc(layout.size());
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        LIVE.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        LIVE.fetch_sub(layout.size(), Ordering::Relaxed);
+        track_alloc(new_size);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+/// A serverStatus like sample, most metrics are counters and a few never change
+fn sample(metrics: usize, i: i64) -> RawDocumentBuf {
+    let mut section = Document::new();
+    for m in 0..metrics {
+        let value = match m % 4 {
+            0 => 7,
+            1 => i * m as i64,
+            _ => i + m as i64,
+        };
+        section.insert(format!("metric{}", m), value);
+    }
+
+    let start = Utc.timestamp_opt(i, 0).unwrap();
+    RawDocumentBuf::from_document(&doc! {"start": start, "serverStatus": section, "end": start})
+        .unwrap()
+}
+
+fn main() {
+    // cargo bench passes --bench, only positional numbers are ours
+    let args: Vec<usize> = std::env::args()
+        .skip(1)
+        .filter_map(|a| a.parse().ok())
+        .collect();
+    let metrics = args.first().copied().unwrap_or(5000);
+    let samples = args.get(1).copied().unwrap_or(3000);
+
+    // Build the samples up front so only the compressor is measured, cycling keeps memory flat
+    let docs: Vec<RawDocumentBuf> = (0..16).map(|i| sample(metrics, i)).collect();
+
+    let mut compressor = BSONMetricsCompressor::new(300);
+
+    // Samples that went into the current block, and samples that finished one
+    let mut add = Phase::default();
+    let mut block = Phase::default();
+    let mut block_bytes = 0;
+
+    let base_live = LIVE.load(Ordering::Relaxed);
+    PEAK.store(base_live, Ordering::Relaxed);
+
+    for i in 0..samples {
+        let date = Utc.timestamp_opt(i as i64, 0).unwrap();
+
+        let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+        let allocated = ALLOCATED.load(Ordering::Relaxed);
+        let start = Instant::now();
+
+        let result = compressor.add_raw_doc(&docs[i % docs.len()], date).unwrap();
+
+        let phase = match &result {
+            AddResult::NewBlock(Some((chunk, _))) => {
+                block_bytes += chunk.len();
+                &mut block
+            }
+            AddResult::NewBlock(None) | AddResult::ExistingBlock => &mut add,
+        };
+        phase.calls += 1;
+        phase.time += start.elapsed();
+        phase.allocations += ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+        phase.allocated += ALLOCATED.load(Ordering::Relaxed) - allocated;
+        drop(result);
+    }
+
+    let peak = PEAK.load(Ordering::Relaxed) - base_live;
+
+    println!(
+        "{} metrics x {} samples, {} blocks of {} bytes",
+        metrics, samples, block.calls, block_bytes
+    );
+    add.print("add sample");
+    block.print("finish block");
+    println!("peak heap:     {} KB", peak / 1024);
+}
+
+#[derive(Default)]
+struct Phase {
+    calls: usize,
+    time: Duration,
+    allocations: usize,
+    allocated: usize,
+}
+
+impl Phase {
+    fn print(&self, name: &str) {
+        let calls = self.calls.max(1);
+        println!(
+            "{:<14} {:>6} calls, {:>10.1?} per call, {:>8.1} allocations and {:>9} bytes per call",
+            format!("{}:", name),
+            self.calls,
+            self.time / calls as u32,
+            self.allocations as f64 / calls as f64,
+            self.allocated / calls
+        );
+    }
+}
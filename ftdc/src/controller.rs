@@ -0,0 +1,376 @@
+// Copyright [2024] [Mark Benvenuto]
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::io::Write;
+use std::sync::Arc;
+use std::sync::Condvar;
+use std::sync::Mutex;
+use std::thread::JoinHandle;
+use std::time::Duration;
+use std::time::Instant;
+
+use anyhow::anyhow;
+use anyhow::Result;
+use bson::Document;
+use bson::RawDocument;
+use bson::RawDocumentBuf;
+use chrono::DateTime;
+use chrono::Utc;
+
+use crate::diagnostic_data::DiagnosticDataWriter;
+use crate::writer::BSONBlockWriter;
+
+/// Time between samples, mongod samples once a second
+pub const DEFAULT_PERIOD: Duration = Duration::from_secs(1);
+
+/// Time between collections of the metadata collectors
+pub const DEFAULT_METADATA_PERIOD: Duration = Duration::from_secs(60);
+
+/**
+ * A source of metrics, e.g. the equivalent of serverStatus
+ *
+ * `collect` is called on the controller's thread and its document becomes the `name` section of
+ * the sample.
+ */
+pub trait Collector: Send {
+    fn name(&self) -> &str;
+
+    fn collect(&mut self) -> Result<Document>;
+}
+
+/// Where the controller writes, implemented by `BSONBlockWriter` and `DiagnosticDataWriter`
+pub trait FtdcSink: Send {
+    fn add_metadata_doc(&mut self, doc: &Document, date: DateTime<Utc>) -> Result<()>;
+
+    fn add_periodic_metadata_doc(&mut self, delta: &Document, date: DateTime<Utc>) -> Result<()>;
+
+    fn add_raw_sample(&mut self, doc: &RawDocument, sample_date: DateTime<Utc>) -> Result<()>;
+
+    fn flush(&mut self) -> Result<()>;
+
+    fn close(self: Box<Self>) -> Result<()>;
+}
+
+impl<W: Write + Send> FtdcSink for BSONBlockWriter<W> {
+    fn add_metadata_doc(&mut self, doc: &Document, date: DateTime<Utc>) -> Result<()> {
+        self.add_metdata_doc(doc, date)
+    }
+
+    fn add_periodic_metadata_doc(&mut self, delta: &Document, date: DateTime<Utc>) -> Result<()> {
+        BSONBlockWriter::add_periodic_metadata_doc(self, delta, date)
+    }
+
+    fn add_raw_sample(&mut self, doc: &RawDocument, sample_date: DateTime<Utc>) -> Result<()> {
+        BSONBlockWriter::add_raw_sample(self, doc, sample_date)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        BSONBlockWriter::flush(self)
+    }
+
+    fn close(self: Box<Self>) -> Result<()> {
+        BSONBlockWriter::close(*self)
+    }
+}
+
+impl FtdcSink for DiagnosticDataWriter {
+    fn add_metadata_doc(&mut self, doc: &Document, date: DateTime<Utc>) -> Result<()> {
+        DiagnosticDataWriter::add_metadata_doc(self, doc, date)
+    }
+
+    fn add_periodic_metadata_doc(&mut self, delta: &Document, date: DateTime<Utc>) -> Result<()> {
+        DiagnosticDataWriter::add_periodic_metadata_doc(self, delta, date)
+    }
+
+    fn add_raw_sample(&mut self, doc: &RawDocument, sample_date: DateTime<Utc>) -> Result<()> {
+        DiagnosticDataWriter::add_raw_sample(self, doc, sample_date)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        DiagnosticDataWriter::flush(self)
+    }
+
+    fn close(self: Box<Self>) -> Result<()> {
+        DiagnosticDataWriter::close(*self)
+    }
+}
+
+/// Everything the sampling thread owns, handed back when it stops so it can be restarted
+struct Sampler {
+    sink: Box<dyn FtdcSink>,
+    collectors: Vec<Box<dyn Collector>>,
+    metadata_collectors: Vec<Box<dyn Collector>>,
+    period: Duration,
+    metadata_period: Duration,
+
+    /// Last metadata written, periodic metadata is only written for sections that changed
+    metadata: Option<Document>,
+}
+
+impl Sampler {
+    /**
+     * Collect one sample
+     *
+     * Each section is wrapped as `{start, <fields>, end}` and the sample as
+     * `{start, <name>: <section>..., end}`. A collector that fails is left out of the sample,
+     * which starts a new block the same way mongod does when a section disappears.
+     */
+    fn sample(&mut self) -> Result<()> {
+        let start = Utc::now();
+        let mut doc = Document::new();
+        doc.insert("start", start);
+
+        for collector in &mut self.collectors {
+            let section_start = Utc::now();
+            if let Ok(fields) = collector.collect() {
+                let mut section = Document::new();
+                section.insert("start", section_start);
+                section.extend(fields);
+                section.insert("end", Utc::now());
+
+                doc.insert(collector.name(), section);
+            }
+        }
+
+        doc.insert("end", Utc::now());
+
+        self.sink
+            .add_raw_sample(&RawDocumentBuf::from_document(&doc)?, start)
+    }
+
+    /// Write the full metadata the first time, after that only the sections that changed
+    fn collect_metadata(&mut self) -> Result<()> {
+        if self.metadata_collectors.is_empty() {
+            return Ok(());
+        }
+
+        let date = Utc::now();
+        let mut doc = Document::new();
+        for collector in &mut self.metadata_collectors {
+            if let Ok(section) = collector.collect() {
+                doc.insert(collector.name(), section);
+            }
+        }
+
+        match &self.metadata {
+            None => self.sink.add_metadata_doc(&doc, date)?,
+            Some(last) => {
+                let delta: Document = doc
+                    .iter()
+                    .filter(|(k, v)| last.get(k.as_str()) != Some(*v))
+                    .map(|(k, v)| (k.clone(), v.clone()))
+                    .collect::<Document>();
+
+                if !delta.is_empty() {
+                    self.sink.add_periodic_metadata_doc(&delta, date)?;
+                }
+            }
+        }
+
+        self.metadata = Some(doc);
+        Ok(())
+    }
+
+    /// Sample every period until `stop` is set, then flush the unfinished block
+    fn run(&mut self, stop: &(Mutex<bool>, Condvar)) -> Result<()> {
+        let (lock, cvar) = stop;
+        let mut next = Instant::now();
+        let mut next_metadata = next;
+
+        loop {
+            let timeout = next.saturating_duration_since(Instant::now());
+            let stopping = cvar
+                .wait_timeout_while(lock.lock().unwrap(), timeout, |stopping| !*stopping)
+                .unwrap()
+                .0;
+            if *stopping {
+                break;
+            }
+            drop(stopping);
+
+            let now = Instant::now();
+            if now >= next_metadata {
+                self.collect_metadata()?;
+                next_metadata = now + self.metadata_period;
+            }
+
+            self.sample()?;
+
+            // Periods missed while a collector was slow are skipped rather than caught up on
+            next += self.period;
+            if next < Instant::now() {
+                next = Instant::now() + self.period;
+            }
+        }
+
+        self.sink.flush()
+    }
+}
+
+/**
+ * Sample registered collectors on a background thread, like mongod's FTDC controller
+ *
+ * Collectors are called every period and their sections written as one sample to the sink.
+ * Metadata collectors are called every metadata period, the first result is written as a metadata
+ * document and later changes as periodic metadata.
+ *
+ * `stop` flushes the unfinished block and `start` resumes sampling. `shutdown` also closes the
+ * sink, dropping the controller does the same but ignores errors.
+ */
+pub struct FtdcController {
+    /// None while the thread owns it
+    sampler: Option<Sampler>,
+    thread: Option<JoinHandle<(Sampler, Result<()>)>>,
+    stop: Arc<(Mutex<bool>, Condvar)>,
+    closed: bool,
+}
+
+impl FtdcController {
+    pub fn new<S: FtdcSink + 'static>(sink: S) -> FtdcController {
+        FtdcController {
+            sampler: Some(Sampler {
+                sink: Box::new(sink),
+                collectors: Vec::new(),
+                metadata_collectors: Vec::new(),
+                period: DEFAULT_PERIOD,
+                metadata_period: DEFAULT_METADATA_PERIOD,
+                metadata: None,
+            }),
+            thread: None,
+            stop: Arc::new((Mutex::new(false), Condvar::new())),
+            closed: false,
+        }
+    }
+
+    /// Panics if `period` is zero, the thread would sample without ever waiting
+    pub fn with_period(mut self, period: Duration) -> FtdcController {
+        assert!(!period.is_zero(), "the sample period must not be zero");
+        self.sampler_mut().period = period;
+        self
+    }
+
+    pub fn with_metadata_period(mut self, period: Duration) -> FtdcController {
+        self.sampler_mut().metadata_period = period;
+        self
+    }
+
+    pub fn with_collector(mut self, collector: Box<dyn Collector>) -> FtdcController {
+        self.sampler_mut().collectors.push(collector);
+        self
+    }
+
+    pub fn with_metadata_collector(mut self, collector: Box<dyn Collector>) -> FtdcController {
+        self.sampler_mut().metadata_collectors.push(collector);
+        self
+    }
+
+    fn sampler_mut(&mut self) -> &mut Sampler {
+        self.sampler
+            .as_mut()
+            .expect("collectors can only be changed while stopped")
+    }
+
+    /// False once stopped, or once an error writing to the sink ended the thread
+    pub fn is_running(&self) -> bool {
+        self.thread.as_ref().is_some_and(|t| !t.is_finished())
+    }
+
+    /// Start sampling, the first sample is taken right away
+    pub fn start(&mut self) -> Result<()> {
+        let Some(mut sampler) = self.sampler.take() else {
+            return Err(anyhow!("FTDC controller is already running"));
+        };
+
+        *self.stop.0.lock().unwrap() = false;
+
+        let stop = self.stop.clone();
+        let thread = std::thread::Builder::new()
+            .name("ftdc".to_owned())
+            .spawn(move || {
+                let result = sampler.run(&stop);
+                (sampler, result)
+            })?;
+
+        self.thread = Some(thread);
+        Ok(())
+    }
+
+    /// Stop sampling and flush the unfinished block, returns the error that stopped the thread
+    pub fn stop(&mut self) -> Result<()> {
+        let Some(thread) = self.thread.take() else {
+            return Ok(());
+        };
+
+        let (lock, cvar) = &*self.stop;
+        *lock.lock().unwrap() = true;
+        cvar.notify_all();
+
+        let (sampler, result) = thread
+            .join()
+            .map_err(|_| anyhow!("FTDC collection thread panicked"))?;
+        self.sampler = Some(sampler);
+        result
+    }
+
+    /// Stop sampling and close the sink
+    pub fn shutdown(mut self) -> Result<()> {
+        self.finish()
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.closed = true;
+        let stopped = self.stop();
+
+        let closed = match self.sampler.take() {
+            Some(sampler) => sampler.sink.close(),
+            None => Ok(()),
+        };
+
+        stopped.and(closed)
+    }
+}
+
+impl Drop for FtdcController {
+    fn drop(&mut self) {
+        if !self.closed {
+            // Errors can only be reported by shutdown
+            let _ = self.finish();
+        }
+    }
+}
+
+/// A collector from a closure, for sections that are a single call
+pub struct FnCollector<F> {
+    name: String,
+    f: F,
+}
+
+impl<F: FnMut() -> Result<Document> + Send> FnCollector<F> {
+    pub fn new(name: &str, f: F) -> FnCollector<F> {
+        FnCollector {
+            name: name.to_owned(),
+            f,
+        }
+    }
+}
+
+impl<F: FnMut() -> Result<Document> + Send> Collector for FnCollector<F> {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn collect(&mut self) -> Result<Document> {
+        (self.f)()
+    }
+}
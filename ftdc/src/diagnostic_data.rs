@@ -0,0 +1,635 @@
+// Copyright [2024] [Mark Benvenuto]
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fs;
+use std::fs::File;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::Result;
+use bson::Document;
+use bson::RawDocument;
+use bson::RawDocumentBuf;
+use chrono::DateTime;
+use chrono::NaiveDateTime;
+use chrono::Utc;
+
+use crate::error::FtdcError;
+use crate::index::BlockIndex;
+use crate::index::IndexedReader;
+use crate::limits::DecodeLimits;
+use crate::metadata::apply_metadata_delta;
+use crate::reader::AsRawBlock;
+use crate::reader::BSONBlockReader;
+use crate::reader::RawBSONBlock;
+use crate::time_range::TimeFilter;
+use crate::time_range::TimeRange;
+use crate::util::gen_metadata_document;
+use crate::util::gen_metrics_document;
+use crate::util::gen_periodic_metadata_document;
+use crate::writer::interim_file_path;
+use crate::writer::AddResult;
+use crate::writer::BSONMetricsCompressor;
+use crate::writer::InterimFile;
+use crate::writer::WriterOptions;
+
+pub const METRICS_FILE_PREFIX: &str = "metrics.";
+pub const INTERIM_FILE_NAME: &str = "metrics.interim";
+
+/// Format of the timestamp mongod embeds in `metrics.<timestamp>-<n>` file names
+pub const METRICS_FILE_TIMESTAMP_FORMAT: &str = "%Y-%m-%dT%H-%M-%SZ";
+
+/// mongod defaults, `diagnosticDataCollectionFileSizeMB` and `diagnosticDataCollectionDirectorySizeMB`
+pub const DEFAULT_MAX_FILE_SIZE: u64 = 10 * 1024 * 1024;
+pub const DEFAULT_MAX_DIRECTORY_SIZE: u64 = 200 * 1024 * 1024;
+
+/// Samples between rewrites of `metrics.interim`
+pub const DEFAULT_INTERIM_SAMPLES: usize = 10;
+
+/// A block along with the file it was read from
+pub struct SourcedBlock {
+    pub source: PathBuf,
+    pub offset: u64,
+    pub block: RawBSONBlock,
+}
+
+impl AsRawBlock for SourcedBlock {
+    fn raw_block(&self) -> &RawBSONBlock {
+        &self.block
+    }
+
+    fn block_offset(&self) -> u64 {
+        self.offset
+    }
+}
+
+/**
+ * Parse a `metrics.<timestamp>-<n>` file name into its timestamp and sequence number
+ */
+pub fn parse_metrics_file_name(name: &str) -> Option<(NaiveDateTime, u32)> {
+    let rest = name.strip_prefix(METRICS_FILE_PREFIX)?;
+    let (ts, seq) = rest.rsplit_once('-')?;
+
+    let ts = NaiveDateTime::parse_from_str(ts, METRICS_FILE_TIMESTAMP_FORMAT).ok()?;
+    let seq = seq.parse::<u32>().ok()?;
+
+    Some((ts, seq))
+}
+
+/**
+ * List the FTDC files in a diagnostic.data directory in the order mongod wrote them
+ *
+ * `metrics.<timestamp>-<n>` files are ordered by their embedded timestamp and sequence number,
+ * `metrics.interim` holds the samples mongod had not flushed yet so it always comes last.
+ * Anything else in the directory is ignored.
+ */
+pub fn list_metrics_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files: Vec<((NaiveDateTime, u32), PathBuf)> = Vec::new();
+    let mut interim: Option<PathBuf> = None;
+
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_file() {
+            continue;
+        }
+
+        let file_name = entry.file_name();
+        let Some(name) = file_name.to_str() else {
+            continue;
+        };
+
+        if name == INTERIM_FILE_NAME {
+            interim = Some(entry.path());
+        } else if let Some(key) = parse_metrics_file_name(name) {
+            files.push((key, entry.path()));
+        }
+    }
+
+    files.sort_by_key(|(key, _)| *key);
+
+    let mut paths: Vec<PathBuf> = files.into_iter().map(|(_, p)| p).collect();
+    paths.extend(interim);
+
+    Ok(paths)
+}
+
+/// Blocks of the file being read, optionally only those an index selected
+enum FileBlocks {
+    Sequential(BSONBlockReader<File>),
+    Indexed(IndexedReader<File>),
+}
+
+impl FileBlocks {
+    fn offset(&self) -> u64 {
+        match self {
+            FileBlocks::Sequential(rdr) => rdr.offset(),
+            FileBlocks::Indexed(rdr) => rdr.offset(),
+        }
+    }
+}
+
+impl Iterator for FileBlocks {
+    type Item = Result<RawBSONBlock, FtdcError>;
+
+    fn next(&mut self) -> Option<Result<RawBSONBlock, FtdcError>> {
+        match self {
+            FileBlocks::Sequential(rdr) => rdr.next(),
+            FileBlocks::Indexed(rdr) => rdr.next(),
+        }
+    }
+}
+
+/**
+ * Read a whole diagnostic.data directory as one continuous stream of blocks
+ *
+ * A path to a single FTDC file is also accepted so callers do not need to care which they have,
+ * it is followed by the `<file>.interim` of a `BSONBlockWriter` if there is one. Errors are
+ * `FtdcError::Open` or `FtdcError::InFile` so they name the file they came from.
+ */
+pub struct DiagnosticDataReader {
+    files: Vec<PathBuf>,
+    next_file: usize,
+    current: Option<(PathBuf, FileBlocks)>,
+    recover: bool,
+    limits: DecodeLimits,
+    use_index: bool,
+    range: TimeRange,
+}
+
+impl DiagnosticDataReader {
+    pub fn new(path: &Path) -> Result<DiagnosticDataReader> {
+        let files = if path.is_dir() {
+            list_metrics_files(path)?
+        } else {
+            // Blocks a BSONBlockWriter had not finished when it stopped
+            let interim = interim_file_path(path);
+            let mut files = vec![path.to_path_buf()];
+            files.extend(interim.is_file().then_some(interim));
+            files
+        };
+
+        Ok(DiagnosticDataReader::from_files(files))
+    }
+
+    /// Read an explicit list of files in the order given
+    pub fn from_files(files: Vec<PathBuf>) -> DiagnosticDataReader {
+        DiagnosticDataReader {
+            files,
+            next_file: 0,
+            current: None,
+            recover: false,
+            limits: DecodeLimits::default(),
+            use_index: false,
+            range: TimeRange::default(),
+        }
+    }
+
+    /// See `BSONBlockReader::with_recovery`, applies to every file
+    pub fn with_recovery(mut self, recover: bool) -> DiagnosticDataReader {
+        self.recover = recover;
+        self
+    }
+
+    /// See `BSONBlockReader::with_limits`, applies to every file
+    pub fn with_limits(mut self, limits: DecodeLimits) -> DiagnosticDataReader {
+        self.limits = limits;
+        self
+    }
+
+    /**
+     * Use the sidecar `BlockIndex` of each file to seek to the blocks in the time range, the
+     * index is built or rebuilt as needed. Only takes effect with `with_time_range`.
+     */
+    pub fn with_index(mut self, use_index: bool) -> DiagnosticDataReader {
+        self.use_index = use_index;
+        self
+    }
+
+    /// Skip blocks that have no samples in the range, see `TimeFilter`
+    pub fn with_time_range(mut self, range: TimeRange) -> TimeFilter<DiagnosticDataReader> {
+        self.range = range;
+        TimeFilter::new(self, range)
+    }
+
+    fn open_file(&self, path: &Path) -> std::io::Result<FileBlocks> {
+        let file = File::open(path)?;
+
+        if self.use_index && !self.range.is_unbounded() {
+            // Fall back to reading the whole file, it reports whatever stopped the index build
+            if let Ok(index) = BlockIndex::open(path, self.recover, self.limits) {
+                let rdr = BSONBlockReader::from_buf_reader(BufReader::new(file))
+                    .with_recovery(self.recover)
+                    .with_limits(self.limits)
+                    .with_index(&index, self.range);
+                return Ok(FileBlocks::Indexed(rdr));
+            }
+        }
+
+        Ok(FileBlocks::Sequential(
+            BSONBlockReader::from_buf_reader(BufReader::new(file))
+                .with_recovery(self.recover)
+                .with_limits(self.limits),
+        ))
+    }
+
+    pub fn files(&self) -> &[PathBuf] {
+        &self.files
+    }
+
+    /// File the last block or error came from
+    pub fn current_file(&self) -> Option<&Path> {
+        self.current.as_ref().map(|(p, _)| p.as_path())
+    }
+}
+
+impl Iterator for DiagnosticDataReader {
+    type Item = Result<SourcedBlock, FtdcError>;
+
+    fn next(&mut self) -> Option<Result<SourcedBlock, FtdcError>> {
+        loop {
+            if let Some((source, rdr)) = &mut self.current {
+                let offset = rdr.offset();
+
+                if let Some(item) = rdr.next() {
+                    return Some(match item {
+                        Ok(block) => Ok(SourcedBlock {
+                            source: source.clone(),
+                            offset,
+                            block,
+                        }),
+                        Err(cause) => Err(FtdcError::InFile {
+                            path: source.clone(),
+                            cause: Box::new(cause),
+                        }),
+                    });
+                }
+            }
+
+            let path = self.files.get(self.next_file)?.clone();
+            self.next_file += 1;
+
+            match self.open_file(&path) {
+                Ok(rdr) => {
+                    self.current = Some((path, rdr));
+                }
+                Err(source) => {
+                    self.current = None;
+                    return Some(Err(FtdcError::Open { path, source }));
+                }
+            }
+        }
+    }
+}
+
+/// The archive file blocks are currently appended to
+struct ArchiveFile {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    size: u64,
+}
+
+impl ArchiveFile {
+    fn write_doc(&mut self, doc: &[u8]) -> Result<()> {
+        self.writer.write_all(doc)?;
+        self.size += doc.len() as u64;
+        Ok(())
+    }
+
+    /// Flush and sync, done before `metrics.interim` drops the blocks written here
+    fn sync_data(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()?;
+        Ok(())
+    }
+}
+
+/**
+ * Write a diagnostic.data directory the way mongod does
+ *
+ * Blocks are appended to `metrics.<timestamp>-<n>` files named after the first sample written to
+ * them. A file is closed once it grows past the maximum file size, and then the oldest files are
+ * deleted until the directory fits in the maximum directory size. The metadata document is
+ * repeated at the start of every file so each can be read on its own.
+ *
+ * The block being built is rewritten to `metrics.interim` every few samples. When a writer is
+ * opened on a directory with an interim file left behind by a crash, its blocks are copied to the
+ * start of the first new file. `close` writes the last block and removes the interim file,
+ * dropping the writer does the same but ignores errors.
+ */
+pub struct DiagnosticDataWriter {
+    dir: PathBuf,
+    max_file_size: u64,
+    max_directory_size: u64,
+
+    compressor: BSONMetricsCompressor,
+    file: Option<ArchiveFile>,
+    metadata: Option<Document>,
+
+    /// Blocks read back from a previous `metrics.interim`, written to the first new file
+    recovered: Vec<Vec<u8>>,
+    interim: InterimFile,
+    closed: bool,
+}
+
+impl DiagnosticDataWriter {
+    /// Open `dir`, creating it if needed, and pick up any interim file a previous writer left
+    pub fn new(dir: &Path) -> Result<DiagnosticDataWriter> {
+        fs::create_dir_all(dir)?;
+
+        let mut recovered = Vec::new();
+        let interim = dir.join(INTERIM_FILE_NAME);
+        if interim.exists() {
+            // Whatever was written before the crash is kept, a torn last block is dropped
+            let rdr = BSONBlockReader::new_reader(File::open(&interim)?)?.with_recovery(true);
+            for block in rdr.flatten() {
+                let doc = match &block {
+                    RawBSONBlock::Metadata(doc)
+                    | RawBSONBlock::Metrics(doc)
+                    | RawBSONBlock::PeriodicMetadata(doc) => doc,
+                };
+                recovered.push(doc.as_bytes().to_vec());
+            }
+        }
+
+        Ok(DiagnosticDataWriter {
+            dir: dir.to_path_buf(),
+            max_file_size: DEFAULT_MAX_FILE_SIZE,
+            max_directory_size: DEFAULT_MAX_DIRECTORY_SIZE,
+            compressor: BSONMetricsCompressor::with_options(WriterOptions::default()),
+            file: None,
+            metadata: None,
+            recovered,
+            interim: InterimFile::new(interim, DEFAULT_INTERIM_SAMPLES),
+            closed: false,
+        })
+    }
+
+    /// Start a new file once the current one is larger than this
+    pub fn with_max_file_size(mut self, size: u64) -> DiagnosticDataWriter {
+        self.max_file_size = size;
+        self
+    }
+
+    /// Delete the oldest files when the directory is larger than this
+    pub fn with_max_directory_size(mut self, size: u64) -> DiagnosticDataWriter {
+        self.max_directory_size = size;
+        self
+    }
+
+    /// Samples per metrics block, must be set before the first sample
+    pub fn with_max_samples(self, max_samples: usize) -> DiagnosticDataWriter {
+        let options = WriterOptions {
+            max_samples,
+            ..*self.compressor.options()
+        };
+        self.with_options(options)
+    }
+
+    /// See `WriterOptions`, must be set before the first sample
+    pub fn with_options(mut self, options: WriterOptions) -> DiagnosticDataWriter {
+        self.compressor = BSONMetricsCompressor::with_options(options);
+        self
+    }
+
+    /// Rewrite `metrics.interim` every `samples` samples
+    pub fn with_interim_samples(mut self, samples: usize) -> DiagnosticDataWriter {
+        self.interim = InterimFile::new(self.dir.join(INTERIM_FILE_NAME), samples);
+        self
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// File blocks are currently written to, None until the first document is added
+    pub fn current_file(&self) -> Option<&Path> {
+        self.file.as_ref().map(|f| f.path.as_path())
+    }
+
+    /// Write a metadata document now and at the start of every later file
+    pub fn add_metadata_doc(&mut self, doc: &Document, date: DateTime<Utc>) -> Result<()> {
+        self.metadata = Some(doc.clone());
+
+        if self.file.is_none() {
+            // Opening the file writes the metadata document
+            return self.open_file(date);
+        }
+
+        let md_doc = bson::to_vec(&gen_metadata_document(doc, date))?;
+        self.archive(date)?.write_doc(&md_doc)
+    }
+
+    /**
+     * Write the metadata fields that changed since the last metadata document
+     *
+     * The sections of the delta replace those of the metadata so later files start with the full
+     * document.
+     */
+    pub fn add_periodic_metadata_doc(
+        &mut self,
+        delta: &Document,
+        date: DateTime<Utc>,
+    ) -> Result<()> {
+        let merged = match &self.metadata {
+            Some(md) => apply_metadata_delta(
+                &RawDocumentBuf::from_document(md)?,
+                &RawDocumentBuf::from_document(delta)?,
+            )?
+            .to_document()?,
+            None => delta.clone(),
+        };
+        self.metadata = Some(merged);
+
+        if self.file.is_none() {
+            // Opening the file writes the merged metadata document
+            return self.open_file(date);
+        }
+
+        let md_doc = bson::to_vec(&gen_periodic_metadata_document(delta, date))?;
+        self.archive(date)?.write_doc(&md_doc)
+    }
+
+    pub fn add_sample(&mut self, doc: &Document, sample_date: DateTime<Utc>) -> Result<()> {
+        self.add_raw_sample(&RawDocumentBuf::from_document(doc)?, sample_date)
+    }
+
+    /// Add a sample that is already serialized, see `BSONMetricsCompressor::add_raw_doc`
+    pub fn add_raw_sample(&mut self, doc: &RawDocument, sample_date: DateTime<Utc>) -> Result<()> {
+        if self.file.is_none() {
+            self.open_file(sample_date)?;
+        }
+
+        match self.compressor.add_raw_doc(doc, sample_date)? {
+            AddResult::ExistingBlock => {}
+            AddResult::NewBlock(None) => {}
+            AddResult::NewBlock(Some((block, date))) => {
+                let metric_doc = bson::to_vec(&gen_metrics_document(&block, date))?;
+                self.archive(sample_date)?.write_doc(&metric_doc)?;
+
+                if self
+                    .file
+                    .as_ref()
+                    .is_some_and(|f| f.size > self.max_file_size)
+                {
+                    self.rotate(sample_date)?;
+                } else if let Some(file) = &mut self.file {
+                    file.sync_data()?;
+                }
+
+                // The interim file still has samples that are now in the archive
+                return self.interim.write(&self.compressor);
+            }
+        }
+
+        if self.interim.tick() {
+            self.interim.write(&self.compressor)?;
+        }
+
+        Ok(())
+    }
+
+    /**
+     * Write the block being built to the current file and flush it to disk
+     *
+     * Blocks recovered from `metrics.interim` are written to a new file even if nothing was added.
+     */
+    pub fn flush(&mut self) -> Result<()> {
+        if self.file.is_none() && !self.recovered.is_empty() {
+            self.open_file(self.recovered_date())?;
+        }
+
+        if let Some((block, date)) = self.compressor.flush()? {
+            let metric_doc = bson::to_vec(&gen_metrics_document(&block, date))?;
+            self.archive(date)?.write_doc(&metric_doc)?;
+        }
+
+        if let Some(file) = &mut self.file {
+            file.sync_data()?;
+        }
+
+        // Everything is in the archive now
+        self.interim.write(&self.compressor)
+    }
+
+    /// Write the block being built, flush the current file and remove `metrics.interim`
+    pub fn close(mut self) -> Result<()> {
+        self.finish()
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.closed = true;
+        self.flush()?;
+        self.interim.remove()
+    }
+
+    /// `_id` of the first recovered block, names the file they are written to
+    fn recovered_date(&self) -> DateTime<Utc> {
+        self.recovered
+            .first()
+            .and_then(|doc| RawDocument::from_bytes(doc).ok()?.get_datetime("_id").ok())
+            .map(|d| d.to_chrono())
+            .unwrap_or_else(Utc::now)
+    }
+
+    fn archive(&mut self, date: DateTime<Utc>) -> Result<&mut ArchiveFile> {
+        if self.file.is_none() {
+            self.open_file(date)?;
+        }
+
+        Ok(self.file.as_mut().expect("archive file is open"))
+    }
+
+    /// `metrics.<timestamp>-<n>` for the first name not already taken
+    fn archive_file_name(&self, date: DateTime<Utc>) -> PathBuf {
+        let ts = date.format(METRICS_FILE_TIMESTAMP_FORMAT);
+
+        let mut seq = 0;
+        loop {
+            let path = self
+                .dir
+                .join(format!("{}{}-{:05}", METRICS_FILE_PREFIX, ts, seq));
+            if !path.exists() {
+                return path;
+            }
+            seq += 1;
+        }
+    }
+
+    fn open_file(&mut self, date: DateTime<Utc>) -> Result<()> {
+        let path = self.archive_file_name(date);
+        let mut file = ArchiveFile {
+            writer: BufWriter::new(File::create(&path)?),
+            path,
+            size: 0,
+        };
+
+        if !self.recovered.is_empty() {
+            for doc in &self.recovered {
+                file.write_doc(doc)?;
+            }
+            file.sync_data()?;
+
+            self.recovered.clear();
+            fs::remove_file(self.dir.join(INTERIM_FILE_NAME))?;
+        }
+
+        if let Some(md) = &self.metadata {
+            file.write_doc(&bson::to_vec(&gen_metadata_document(md, date))?)?;
+        }
+
+        self.file = Some(file);
+        Ok(())
+    }
+
+    fn rotate(&mut self, date: DateTime<Utc>) -> Result<()> {
+        if let Some(mut file) = self.file.take() {
+            file.sync_data()?;
+        }
+
+        self.trim_directory()?;
+        self.open_file(date)
+    }
+
+    /// Delete the oldest files until the directory fits, the newest file is always kept
+    fn trim_directory(&self) -> Result<()> {
+        let files = list_metrics_files(&self.dir)?;
+
+        let mut total = 0;
+        for (i, path) in files
+            .iter()
+            .rev()
+            .filter(|p| !p.ends_with(INTERIM_FILE_NAME))
+            .enumerate()
+        {
+            total += fs::metadata(path)?.len();
+            if i > 0 && total > self.max_directory_size {
+                fs::remove_file(path)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for DiagnosticDataWriter {
+    fn drop(&mut self) {
+        if !self.closed {
+            // Errors can only be reported by close
+            let _ = self.finish();
+        }
+    }
+}
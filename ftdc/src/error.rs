@@ -0,0 +1,198 @@
+// Copyright [2024] [Mark Benvenuto]
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+
+/// Errors produced while reading and decoding FTDC blocks
+///
+/// Every variant carries the byte offset in the source stream of the block that failed to decode.
+#[derive(Debug)]
+pub enum FtdcError {
+    /// Underlying reader failed for a reason other than running out of bytes
+    Io { offset: u64, source: io::Error },
+
+    /// The stream ended in the middle of a block
+    TruncatedBlock {
+        offset: u64,
+        expected: usize,
+        actual: usize,
+    },
+
+    /// The block is not a valid BSON document or is missing a required field
+    BadBson { offset: u64, message: String },
+
+    /// The block `type` field is not one of the known FTDC block types
+    UnknownBlockType { offset: u64, block_type: i32 },
+
+    /// The zlib payload of a metrics block could not be inflated
+    InflateFailure { offset: u64, source: io::Error },
+
+    /// The metric count in the block header does not match the reference document
+    MetricCountMismatch {
+        offset: u64,
+        expected: usize,
+        actual: usize,
+    },
+
+    /// The delta/RLE encoded metric stream is malformed
+    CorruptMetricData { offset: u64, message: String },
+
+    /// A size or count in the block is over a `DecodeLimits` bound
+    LimitExceeded {
+        offset: u64,
+        what: &'static str,
+        limit: usize,
+        actual: usize,
+    },
+
+    /// Bytes skipped while resynchronizing after a corrupt block in recovery mode
+    Skipped {
+        offset: u64,
+        length: u64,
+        cause: Box<FtdcError>,
+    },
+
+    /// A file of a diagnostic.data directory could not be opened, the offset is always 0
+    Open { path: PathBuf, source: io::Error },
+
+    /// An error reading a block of a file in a diagnostic.data directory
+    InFile {
+        path: PathBuf,
+        cause: Box<FtdcError>,
+    },
+}
+
+impl FtdcError {
+    /// Byte offset of the block that produced this error
+    pub fn offset(&self) -> u64 {
+        match self {
+            FtdcError::Io { offset, .. }
+            | FtdcError::TruncatedBlock { offset, .. }
+            | FtdcError::BadBson { offset, .. }
+            | FtdcError::UnknownBlockType { offset, .. }
+            | FtdcError::InflateFailure { offset, .. }
+            | FtdcError::MetricCountMismatch { offset, .. }
+            | FtdcError::CorruptMetricData { offset, .. }
+            | FtdcError::LimitExceeded { offset, .. }
+            | FtdcError::Skipped { offset, .. } => *offset,
+            FtdcError::Open { .. } => 0,
+            FtdcError::InFile { cause, .. } => cause.offset(),
+        }
+    }
+
+    /// File the error came from, if it was read through a `DiagnosticDataReader`
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            FtdcError::Open { path, .. } | FtdcError::InFile { path, .. } => Some(path),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn bad_bson(offset: u64, err: impl fmt::Display) -> FtdcError {
+        FtdcError::BadBson {
+            offset,
+            message: err.to_string(),
+        }
+    }
+
+    pub(crate) fn corrupt(offset: u64, message: impl Into<String>) -> FtdcError {
+        FtdcError::CorruptMetricData {
+            offset,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for FtdcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FtdcError::Io { offset, source } => {
+                write!(f, "I/O error at offset {}: {}", offset, source)
+            }
+            FtdcError::TruncatedBlock {
+                offset,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "truncated block at offset {}: expected {} bytes, found {}",
+                offset, expected, actual
+            ),
+            FtdcError::BadBson { offset, message } => {
+                write!(f, "bad BSON block at offset {}: {}", offset, message)
+            }
+            FtdcError::UnknownBlockType { offset, block_type } => {
+                write!(f, "unknown block type {} at offset {}", block_type, offset)
+            }
+            FtdcError::InflateFailure { offset, source } => write!(
+                f,
+                "failed to inflate metrics block at offset {}: {}",
+                offset, source
+            ),
+            FtdcError::MetricCountMismatch {
+                offset,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "metric count mismatch at offset {}: header says {}, reference document has {}",
+                offset, expected, actual
+            ),
+            FtdcError::CorruptMetricData { offset, message } => {
+                write!(f, "corrupt metric data at offset {}: {}", offset, message)
+            }
+            FtdcError::LimitExceeded {
+                offset,
+                what,
+                limit,
+                actual,
+            } => write!(
+                f,
+                "{} of {} at offset {} is over the limit of {}",
+                what, actual, offset, limit
+            ),
+            FtdcError::Skipped {
+                offset,
+                length,
+                cause,
+            } => write!(
+                f,
+                "skipped {} bytes at offset {} after error: {}",
+                length, offset, cause
+            ),
+            FtdcError::Open { path, source } => {
+                write!(f, "failed to open {}: {}", path.display(), source)
+            }
+            FtdcError::InFile { path, cause } => write!(f, "{}: {}", path.display(), cause),
+        }
+    }
+}
+
+impl Error for FtdcError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            FtdcError::Io { source, .. }
+            | FtdcError::InflateFailure { source, .. }
+            | FtdcError::Open { source, .. } => Some(source),
+            FtdcError::Skipped { cause, .. } | FtdcError::InFile { cause, .. } => {
+                Some(cause.as_ref())
+            }
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,430 @@
+// Copyright [2024] [Mark Benvenuto]
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::VecDeque;
+use std::fs;
+use std::fs::File;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Read;
+use std::io::Seek;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::anyhow;
+use anyhow::Result;
+use bson::doc;
+use bson::Document;
+use bson::RawDocument;
+use chrono::DateTime;
+use chrono::Utc;
+
+use crate::error::FtdcError;
+use crate::limits::DecodeLimits;
+use crate::reader::decode_metric_block_with_limits;
+use crate::reader::BSONBlockReader;
+use crate::reader::RawBSONBlock;
+use crate::reader::MIN_BSON_SIZE;
+use crate::time_range::block_date;
+use crate::time_range::TimeRange;
+use crate::util::schema_fingerprint;
+
+/// Bumped when the sidecar layout changes, older sidecars are rebuilt
+const INDEX_VERSION: i32 = 1;
+
+/// Suffix of the sidecar file written next to an FTDC file
+pub const INDEX_FILE_SUFFIX: &str = ".index";
+
+/// Where a block lives in an FTDC file and what it covers
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BlockIndexEntry {
+    pub offset: u64,
+    pub length: u32,
+    pub block_type: i32,
+
+    /// The block's `_id`
+    pub date: Option<DateTime<Utc>>,
+
+    /// `start` of the reference document and of the last sample, None if the samples have no
+    /// `start` field or the block could not be decoded
+    pub first_sample: Option<DateTime<Utc>>,
+    pub last_sample: Option<DateTime<Utc>>,
+
+    pub metrics_count: i32,
+    pub sample_count: i32,
+
+    /// See `util::schema_fingerprint`, 0 for metadata blocks
+    pub schema: u64,
+}
+
+impl BlockIndexEntry {
+    fn from_block(offset: u64, block: &RawBSONBlock, limits: &DecodeLimits) -> BlockIndexEntry {
+        let (block_type, doc) = match block {
+            RawBSONBlock::Metadata(doc) => (0, doc),
+            RawBSONBlock::Metrics(doc) => (1, doc),
+            RawBSONBlock::PeriodicMetadata(doc) => (2, doc),
+        };
+
+        let mut entry = BlockIndexEntry {
+            offset,
+            length: doc.as_bytes().len() as u32,
+            block_type,
+            date: block_date(block),
+            first_sample: None,
+            last_sample: None,
+            metrics_count: 0,
+            sample_count: 0,
+            schema: 0,
+        };
+
+        // A block that does not decode is still indexed so readers report the error themselves
+        if let RawBSONBlock::Metrics(doc) = block {
+            if let Ok(db) = decode_metric_block_with_limits(doc, offset, limits) {
+                entry.metrics_count = db.metrics_count;
+                entry.sample_count = db.sample_count;
+                entry.schema = schema_fingerprint(&db.ref_doc);
+
+                if let Some(idx) = db.start_metric_index() {
+                    entry.first_sample =
+                        db.ref_doc.get_datetime("start").ok().map(|d| d.to_chrono());
+                    entry.last_sample = match db.sample_count {
+                        0 => entry.first_sample,
+                        n => DateTime::from_timestamp_millis(
+                            db.sample_value(n - 1, idx as i32) as i64
+                        ),
+                    };
+                }
+            }
+        }
+
+        entry
+    }
+
+    fn to_document(&self) -> Document {
+        let mut d = doc! {
+            "offset": self.offset as i64,
+            "length": self.length as i64,
+            "type": self.block_type,
+            "metrics": self.metrics_count,
+            "samples": self.sample_count,
+            "schema": self.schema as i64,
+        };
+
+        for (key, value) in [
+            ("_id", self.date),
+            ("first", self.first_sample),
+            ("last", self.last_sample),
+        ] {
+            if let Some(t) = value {
+                d.insert(key, t);
+            }
+        }
+
+        d
+    }
+
+    fn from_document(doc: &RawDocument) -> Result<BlockIndexEntry> {
+        let date = |key: &str| doc.get_datetime(key).ok().map(|d| d.to_chrono());
+
+        Ok(BlockIndexEntry {
+            offset: doc.get_i64("offset")? as u64,
+            length: doc.get_i64("length")? as u32,
+            block_type: doc.get_i32("type")?,
+            date: date("_id"),
+            first_sample: date("first"),
+            last_sample: date("last"),
+            metrics_count: doc.get_i32("metrics")?,
+            sample_count: doc.get_i32("samples")?,
+            schema: doc.get_i64("schema")? as u64,
+        })
+    }
+}
+
+/**
+ * Offsets and time spans of every block in an FTDC file
+ *
+ * Building an index inflates every metrics block once, afterwards readers can seek straight to the
+ * blocks that cover a window of time. Use `BlockIndex::open` to keep the index in a sidecar file
+ * next to the FTDC file, or `BlockIndex::build` to keep it in memory.
+ */
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BlockIndex {
+    file_length: u64,
+    entries: Vec<BlockIndexEntry>,
+}
+
+impl BlockIndex {
+    /**
+     * Index every block in a stream
+     *
+     * In recovery mode the skipped regions are left out of the index, otherwise the first error
+     * is returned.
+     */
+    pub fn build<R: Read>(mut reader: BSONBlockReader<R>) -> Result<BlockIndex, FtdcError> {
+        let mut entries = Vec::new();
+        let limits = reader.limits();
+
+        loop {
+            let offset = reader.offset();
+
+            match reader.next() {
+                None => break,
+                Some(Ok(block)) => {
+                    entries.push(BlockIndexEntry::from_block(offset, &block, &limits))
+                }
+                Some(Err(FtdcError::Skipped { .. })) => {}
+                Some(Err(e)) => return Err(e),
+            }
+        }
+
+        Ok(BlockIndex {
+            file_length: reader.offset(),
+            entries,
+        })
+    }
+
+    /// Index a file without writing a sidecar, blocks over `limits` are not decoded
+    pub fn build_file(path: &Path, recover: bool, limits: DecodeLimits) -> Result<BlockIndex> {
+        let rdr = BSONBlockReader::new_reader(File::open(path)?)?
+            .with_recovery(recover)
+            .with_limits(limits);
+
+        Ok(BlockIndex::build(rdr)?)
+    }
+
+    /**
+     * Sidecar path for an FTDC file
+     *
+     * The name is hidden and does not start with `metrics.` so mongod and `list_metrics_files`
+     * do not mistake it for an FTDC file when it sits in diagnostic.data.
+     */
+    pub fn sidecar_path(path: &Path) -> PathBuf {
+        let mut name = std::ffi::OsString::from(".");
+        name.push(path.file_name().unwrap_or_default());
+        name.push(INDEX_FILE_SUFFIX);
+
+        path.with_file_name(name)
+    }
+
+    /**
+     * Load the sidecar index for an FTDC file, rebuilding it if it is missing or stale
+     *
+     * The sidecar is stale if the FTDC file was modified after it or has a different length,
+     * mongod rewrites `metrics.interim` in place. Failing to write the sidecar is not an error
+     * since diagnostic.data is often read only, the index is then only kept in memory. A rebuild
+     * reads the file with `limits`, see `build_file`.
+     */
+    pub fn open(path: &Path, recover: bool, limits: DecodeLimits) -> Result<BlockIndex> {
+        let sidecar = BlockIndex::sidecar_path(path);
+        let file_meta = fs::metadata(path)?;
+
+        if let Ok(sidecar_meta) = fs::metadata(&sidecar) {
+            if sidecar_meta.modified()? >= file_meta.modified()? {
+                if let Ok(index) = BlockIndex::load(&sidecar) {
+                    if index.file_length == file_meta.len() {
+                        return Ok(index);
+                    }
+                }
+            }
+        }
+
+        let index = BlockIndex::build_file(path, recover, limits)?;
+        let _ = index.save(&sidecar);
+
+        Ok(index)
+    }
+
+    /// Read an index written by `save`
+    pub fn load(path: &Path) -> Result<BlockIndex> {
+        let mut buf = Vec::new();
+        BufReader::new(File::open(path)?).read_to_end(&mut buf)?;
+
+        let mut docs = Vec::new();
+        let mut pos = 0;
+        while pos < buf.len() {
+            let len = buf
+                .get(pos..pos + 4)
+                .map(|b| i32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                .ok_or_else(|| anyhow!("truncated index {}", path.display()))?;
+            let len = usize::try_from(len)
+                .ok()
+                .filter(|len| *len >= MIN_BSON_SIZE as usize)
+                .ok_or_else(|| {
+                    anyhow!(
+                        "invalid document length {} in index {}",
+                        len,
+                        path.display()
+                    )
+                })?;
+            let bytes = pos
+                .checked_add(len)
+                .and_then(|end| buf.get(pos..end))
+                .ok_or_else(|| anyhow!("truncated index {}", path.display()))?;
+
+            docs.push(RawDocument::from_bytes(bytes)?);
+            pos += len;
+        }
+
+        let (header, entries) = docs
+            .split_first()
+            .ok_or_else(|| anyhow!("empty index {}", path.display()))?;
+
+        let version = header.get_i32("version")?;
+        if version != INDEX_VERSION {
+            return Err(anyhow!("unsupported index version {}", version));
+        }
+
+        Ok(BlockIndex {
+            file_length: header.get_i64("fileLength")? as u64,
+            entries: entries
+                .iter()
+                .map(|d| BlockIndexEntry::from_document(d))
+                .collect::<Result<Vec<_>>>()?,
+        })
+    }
+
+    /// Write the index as a header document followed by one BSON document per block
+    pub fn save(&self, path: &Path) -> Result<()> {
+        // Write then rename so a concurrent reader never sees half an index
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+
+        {
+            let mut writer = BufWriter::new(File::create(&tmp)?);
+
+            doc! {
+                "version": INDEX_VERSION,
+                "fileLength": self.file_length as i64,
+            }
+            .to_writer(&mut writer)?;
+
+            for entry in &self.entries {
+                entry.to_document().to_writer(&mut writer)?;
+            }
+
+            writer.flush()?;
+        }
+
+        fs::rename(&tmp, path)?;
+
+        Ok(())
+    }
+
+    /// Length of the indexed stream in bytes
+    pub fn file_length(&self) -> u64 {
+        self.file_length
+    }
+
+    pub fn entries(&self) -> &[BlockIndexEntry] {
+        &self.entries
+    }
+
+    /// Time of the last sample in the file
+    pub fn last_sample(&self) -> Option<DateTime<Utc>> {
+        self.entries
+            .iter()
+            .rev()
+            .filter(|e| e.block_type == 1)
+            .find_map(|e| e.last_sample.or(e.date))
+    }
+
+    /**
+     * Blocks that can have samples in the range, in file order
+     *
+     * Metadata blocks up to the end of the range are always included, the same as `TimeFilter`.
+     * Metrics blocks without sample times are assumed to run up to the next metrics block.
+     */
+    pub fn select(&self, range: &TimeRange) -> Vec<&BlockIndexEntry> {
+        let mut selected = Vec::new();
+
+        for (i, entry) in self.entries.iter().enumerate() {
+            let keep = match (entry.block_type, entry.first_sample, entry.last_sample) {
+                (1, Some(first), Some(last)) => {
+                    range.start.is_none_or(|s| last >= s) && range.end.is_none_or(|e| first <= e)
+                }
+                (1, _, _) => match entry.date {
+                    Some(first) => {
+                        let next = self.entries[i + 1..]
+                            .iter()
+                            .find(|e| e.block_type == 1)
+                            .and_then(|e| e.date);
+                        range.overlaps(first, next)
+                    }
+                    None => true,
+                },
+                _ => entry.date.is_none_or(|d| range.end.is_none_or(|e| d <= e)),
+            };
+
+            if keep {
+                selected.push(entry);
+            }
+        }
+
+        selected
+    }
+}
+
+/**
+ * Read only the blocks of a seekable stream that a `BlockIndex` selected for a time range
+ *
+ * Consecutive selected blocks are read without seeking.
+ */
+pub struct IndexedReader<R: Read + Seek> {
+    reader: BSONBlockReader<R>,
+    offsets: VecDeque<u64>,
+}
+
+impl<R: Read + Seek> IndexedReader<R> {
+    pub fn new(
+        reader: BSONBlockReader<R>,
+        index: &BlockIndex,
+        range: TimeRange,
+    ) -> IndexedReader<R> {
+        IndexedReader {
+            reader,
+            offsets: index.select(&range).iter().map(|e| e.offset).collect(),
+        }
+    }
+
+    /// Byte offset of the next block that will be read
+    pub fn offset(&self) -> u64 {
+        self.offsets
+            .front()
+            .copied()
+            .unwrap_or_else(|| self.reader.offset())
+    }
+}
+
+impl<R: Read + Seek> Iterator for IndexedReader<R> {
+    type Item = Result<RawBSONBlock, FtdcError>;
+
+    fn next(&mut self) -> Option<Result<RawBSONBlock, FtdcError>> {
+        loop {
+            let offset = self.offsets.pop_front()?;
+
+            if self.reader.offset() != offset {
+                if let Err(e) = self.reader.seek_to(offset) {
+                    return Some(Err(e));
+                }
+            }
+
+            // The file may have been truncated since it was indexed
+            if let Some(item) = self.reader.next() {
+                return Some(item);
+            }
+        }
+    }
+}
@@ -12,18 +12,53 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+pub mod controller;
+pub mod diagnostic_data;
+pub mod error;
+pub mod index;
+pub mod limits;
+pub mod metadata;
+pub mod metric_path;
+pub mod parallel;
 pub mod reader;
+pub mod system_metrics;
+pub mod time_range;
 pub mod util;
+pub mod validate;
 pub mod writer;
 
+pub use controller::Collector;
+pub use controller::FtdcController;
+pub use controller::FtdcSink;
+pub use diagnostic_data::DiagnosticDataReader;
+pub use diagnostic_data::DiagnosticDataWriter;
+pub use diagnostic_data::SourcedBlock;
+pub use error::FtdcError;
+pub use index::BlockIndex;
+pub use index::IndexedReader;
+pub use limits::DecodeLimits;
+pub use metadata::MetadataTracker;
+pub use metric_path::MetricPath;
+pub use metric_path::PathSegment;
+pub use parallel::DecodedBlock;
+pub use parallel::ParallelDecoder;
 pub use reader::BSONBlockReader;
 pub use reader::MetricsDocument;
 pub use reader::MetricsReader;
 pub use reader::RawBSONBlock;
 pub use reader::VectorMetricsDocument;
 pub use reader::VectorMetricsReader;
+pub use system_metrics::SystemMetricsCollector;
+pub use time_range::TimeFilter;
+pub use time_range::TimeRange;
+pub use time_range::TimeSpec;
 pub use util::extract_metrics;
 pub use util::extract_metrics_paths;
+pub use util::MetricValue;
+pub use validate::validate_block;
+pub use validate::BlockReport;
+pub use writer::FtdcBuffer;
+pub use writer::WriterOptions;
 
 // pub enum MetricsDocument<'a> {
 //     Reference(&'a Document),
@@ -60,13 +95,37 @@ pub use util::extract_metrics_paths;
 // extern crate assert_ok;
 #[cfg(test)]
 mod test {
-    use super::reader::decode_metric_block;
-    use super::writer::{AddResult, BSONBlockWriter, BSONMetricsCompressor};
+    use super::controller::{Collector, FnCollector, FtdcController};
+    use super::diagnostic_data::{list_metrics_files, INTERIM_FILE_NAME};
+    use super::error::FtdcError;
+    use super::reader::{
+        decode_metric_block, decode_metric_block_at, decode_metric_block_with_limits,
+        DecodedMetricBlock,
+    };
+    use super::system_metrics::{parse_loadavg, HostInfoCollector, SystemMetricsCollector};
+    use super::time_range::block_date;
+    use super::util::{
+        decimal128_to_i64, extract_metrics_paths_raw, extract_metrics_raw, fill_document_raw,
+        i64_to_decimal128, MetricType,
+    };
+    use super::util::{same_schema, same_schema_raw};
+    use super::validate::{validate_block, Check};
+    use super::writer::{interim_file_path, AddResult, BSONBlockWriter, BSONMetricsCompressor};
+    use super::{
+        BSONBlockReader, BlockIndex, DecodeLimits, DecodedBlock, DiagnosticDataReader,
+        DiagnosticDataWriter, FtdcBuffer, MetadataTracker, MetricPath, MetricValue,
+        MetricsDocument, MetricsReader, ParallelDecoder, PathSegment, RawBSONBlock, SourcedBlock,
+        TimeRange, TimeSpec, VectorMetricsDocument, VectorMetricsReader, WriterOptions,
+    };
     use assert_ok::assert_ok;
     use bson::spec::BinarySubtype;
-    use bson::{doc, RawDocumentBuf};
+    use bson::{doc, RawArrayBuf, RawBson, RawDocument, RawDocumentBuf};
     use bytes::BufMut;
     use chrono::{TimeZone, Utc};
+    use std::io::Cursor;
+    use std::io::Read;
+    use std::io::Write;
+    use std::path::PathBuf;
 
     #[test]
     fn test_roundtrip_compressor() {
@@ -101,7 +160,7 @@ mod test {
 
         match addresult {
             AddResult::ExistingBlock => {
-                assert!(false);
+                unreachable!();
             }
             AddResult::NewBlock(met_opt) => {
                 let (met, _date) = met_opt.unwrap();
@@ -116,18 +175,293 @@ mod test {
                 let d1 =
                     doc! { "data" : bson::Binary{subtype: BinarySubtype::Generic, bytes: met} };
                 let raw_doc = RawDocumentBuf::from_document(&d1).unwrap();
-                let dmbr = decode_metric_block(&raw_doc.as_ref());
+                let dmbr = decode_metric_block(raw_doc.as_ref());
                 assert!(dmbr.is_ok());
                 let dmb = dmbr.unwrap();
                 assert_eq!(dmb.sample_count, 2);
                 assert_eq!(dmb.metrics_count, 2);
                 eprintln!("{:?}", dmb.ref_doc);
-                assert_eq!(dmb.raw_metrics, vec![2, 3, 2, 2]);
+                assert_eq!(dmb.raw_metrics, vec![1, 2, 3, 2, 2, 2]);
             }
         }
     }
 
     #[test]
+    fn test_schema_change() {
+        let date = Utc.timestamp_nanos(42);
+        let reference = doc! {"a": 1, "n": {"x": 2i64}, "arr": [1, 2], "s": "t"};
+
+        // Same metric count, but a reader would rebuild the sample with the wrong shape
+        let changed = [
+            doc! {"b": 1, "n": {"x": 2i64}, "arr": [1, 2], "s": "t"},
+            doc! {"a": 1.5, "n": {"x": 2i64}, "arr": [1, 2], "s": "t"},
+            doc! {"a": 1, "n": {"y": 2i64}, "arr": [1, 2], "s": "t"},
+            doc! {"a": 1, "n": {"x": 2i64}, "arr": [1, 2], "s": "u"},
+            doc! {"a": 1, "n": {"x": 2i64}, "arr": [1, 2], "s": "t", "z": null},
+            doc! {"n": {"x": 2i64}, "a": 1, "arr": [1, 2], "s": "t"},
+        ];
+
+        assert!(same_schema(&reference, &reference));
+        assert!(same_schema(
+            &reference,
+            &doc! {"a": 5, "n": {"x": 7i64}, "arr": [3, 4], "s": "t"}
+        ));
+
+        let raw_reference = RawDocumentBuf::from_document(&reference).unwrap();
+        for sample in &changed {
+            assert!(!same_schema(&reference, sample), "{}", sample);
+            assert!(!same_schema_raw(
+                &raw_reference,
+                &RawDocumentBuf::from_document(sample).unwrap()
+            ));
+
+            let mut writer = BSONMetricsCompressor::new(10);
+            assert_eq!(
+                writer.add_doc(&reference, date).unwrap(),
+                AddResult::NewBlock(None)
+            );
+            let AddResult::NewBlock(Some((block, _))) = writer.add_doc(sample, date).unwrap()
+            else {
+                panic!("expected a new block for {}", sample);
+            };
+
+            let d1 = doc! { "data" : bson::Binary{subtype: BinarySubtype::Generic, bytes: block} };
+            let raw_doc = RawDocumentBuf::from_document(&d1).unwrap();
+            let dmb = decode_metric_block(raw_doc.as_ref()).unwrap();
+            assert_eq!(dmb.sample_count, 0);
+            assert_eq!(dmb.ref_doc.to_document().unwrap(), reference);
+        }
+    }
+
+    #[test]
+    fn test_raw_samples() {
+        let samples: Vec<_> = (0..12i64)
+            .map(|i| {
+                let date = Utc.timestamp_opt(i, 0).unwrap();
+                let mut sample = doc! {
+                    "start": date,
+                    "d": i as f64 * 2.0,
+                    "n": {"x": i, "b": i % 2 == 0, "ts": bson::Timestamp{time: i as u32, increment: 1}},
+                    "arr": [i, -i],
+                    "dec": i64_to_decimal128(i * 7),
+                    "s": "t",
+                };
+                // Schema change in the middle of a block
+                if i == 5 {
+                    sample.insert("s", "u");
+                }
+                (sample, date)
+            })
+            .collect();
+
+        let write = |raw: bool| {
+            let mut buf = Vec::with_capacity(1024);
+            {
+                let mut writer = BSONBlockWriter::new(&mut buf, max_samples(4));
+                for (sample, date) in &samples {
+                    if raw {
+                        let raw_sample = RawDocumentBuf::from_document(sample).unwrap();
+                        assert_ok!(writer.add_raw_sample(&raw_sample, *date));
+                    } else {
+                        assert_ok!(writer.add_sample(sample, *date));
+                    }
+                }
+                assert_ok!(writer.close());
+            }
+            buf
+        };
+
+        let bytes = write(true);
+        assert_eq!(bytes, write(false));
+
+        let mut rows = Vec::new();
+        for block in BSONBlockReader::new_reader(bytes.as_slice()).unwrap() {
+            if let RawBSONBlock::Metrics(doc) = block.unwrap() {
+                rows.extend(MetricsReader::new(&doc).unwrap().map(|(_, d)| match d {
+                    MetricsDocument::Reference(d) => d.to_document().unwrap(),
+                    MetricsDocument::Metrics(d) => d.to_document().unwrap(),
+                }));
+            }
+        }
+        let expected: Vec<_> = samples.into_iter().map(|(s, _)| s).collect();
+        assert_eq!(rows, expected);
+    }
+
+    /// The delta stream of a block encoded one metric at a time, without the column buffer
+    fn reference_delta_stream(samples: &[Vec<u64>]) -> Vec<u8> {
+        fn varint(out: &mut Vec<u8>, mut v: u64) {
+            while v >= 0x80 {
+                out.push((v as u8) | 0x80);
+                v >>= 7;
+            }
+            out.push(v as u8);
+        }
+
+        let mut out = Vec::new();
+        let mut zeros = 0u64;
+        for m in 0..samples[0].len() {
+            for s in 1..samples.len() {
+                let delta = samples[s][m].wrapping_sub(samples[s - 1][m]);
+                if delta == 0 {
+                    zeros += 1;
+                    continue;
+                }
+                if zeros > 0 {
+                    varint(&mut out, 0);
+                    varint(&mut out, zeros - 1);
+                    zeros = 0;
+                }
+                varint(&mut out, delta);
+            }
+        }
+        if zeros > 0 {
+            varint(&mut out, 0);
+            varint(&mut out, zeros - 1);
+        }
+        out
+    }
+
+    #[test]
+    fn test_compressor_delta_stream() {
+        // Metric "a" never changes, "b" stops changing half way and "c" only starts changing half
+        // way, so zero runs cross from one column into the next. 40 samples grow the columns
+        // past their initial capacity, and the block is closed well before max_samples.
+        let samples: Vec<Vec<u64>> = (0..40u64)
+            .map(|i| vec![7, i.min(20) * 1000, i.saturating_sub(20) * 3, u64::MAX - i])
+            .collect();
+        let sample_doc = |v: &[u64]| doc! {"a": v[0] as i64, "b": v[1] as i64, "c": v[2] as i64, "d": v[3] as i64};
+
+        let mut compressor = BSONMetricsCompressor::new(1000);
+        let date = Utc.timestamp_nanos(42);
+        for v in &samples {
+            assert_ok!(compressor.add_doc(&sample_doc(v), date));
+        }
+        let (block, _) = compressor.flush().unwrap().unwrap();
+
+        let mut inflated = Vec::new();
+        libflate::zlib::Decoder::new(&block[4..])
+            .unwrap()
+            .read_to_end(&mut inflated)
+            .unwrap();
+        let ref_len = bson::to_vec(&sample_doc(&samples[0])).unwrap().len();
+        assert_eq!(inflated[ref_len..ref_len + 4], 4i32.to_le_bytes());
+        assert_eq!(inflated[ref_len + 4..ref_len + 8], 39i32.to_le_bytes());
+        assert_eq!(inflated[ref_len + 8..], reference_delta_stream(&samples));
+
+        // And the reader gets the samples back
+        let doc = RawDocumentBuf::from_document(&doc! {
+            "_id": date,
+            "type": 1,
+            "data": bson::Binary { subtype: BinarySubtype::Generic, bytes: block },
+        })
+        .unwrap();
+        let rows: Vec<Vec<u64>> = VectorMetricsReader::new(&doc)
+            .unwrap()
+            .map(|(_, d)| match d {
+                VectorMetricsDocument::Reference(d) => extract_metrics_raw(&d).unwrap(),
+                VectorMetricsDocument::Metrics(m) => m,
+            })
+            .collect();
+        assert_eq!(rows, samples);
+
+        // Blocks closed by time rather than count do not allocate for max_samples up front
+        let mut compressor = BSONMetricsCompressor::new(usize::MAX);
+        for v in &samples {
+            assert_ok!(compressor.add_doc(&sample_doc(v), date));
+        }
+        assert_eq!(compressor.sample_count(), 40);
+    }
+
+    #[test]
+    fn test_malformed_raw_sample() {
+        // Valid framing, but the element type of "b" is not a BSON type
+        let mut bytes = bson::to_vec(&doc! {"a": 1i64, "b": 2i64}).unwrap();
+        bytes[4 + 1 + 2 + 8] = 0x55;
+        let bad = bson::RawDocument::from_bytes(&bytes).unwrap();
+
+        let date = Utc.timestamp_nanos(42);
+        let mut compressor = BSONMetricsCompressor::new(3);
+        assert!(compressor.add_raw_doc(bad, date).is_err());
+
+        assert_ok!(compressor.add_doc(&doc! {"a": 1i64, "b": 2i64}, date));
+        assert!(compressor.add_raw_doc(bad, date).is_err());
+
+        // The block being built is kept
+        assert_eq!(
+            compressor
+                .add_doc(&doc! {"a": 3i64, "b": 4i64}, date)
+                .unwrap(),
+            AddResult::ExistingBlock
+        );
+        assert_eq!(compressor.sample_count(), 2);
+
+        let mut writer = BSONBlockWriter::new(Vec::new(), max_samples(3));
+        assert!(writer.add_raw_sample(bad, date).is_err());
+    }
+
+    #[test]
+    fn test_writer_options() {
+        // Sample seconds of each metrics block written with `options`
+        let blocks = |options: WriterOptions, seconds: &[i64]| {
+            let mut bytes = Vec::with_capacity(1024);
+            {
+                let mut writer = BSONBlockWriter::new(&mut bytes, options);
+                for &i in seconds {
+                    let date = Utc.timestamp_opt(i, 0).unwrap();
+                    let sample = doc! {"start": date, "a": i * 1000, "b": 7};
+                    assert_ok!(writer.add_sample(&sample, date));
+                }
+                assert_ok!(writer.close());
+            }
+
+            let mut blocks = Vec::new();
+            for block in BSONBlockReader::new_reader(bytes.as_slice()).unwrap() {
+                if let RawBSONBlock::Metrics(doc) = block.unwrap() {
+                    let rdr = VectorMetricsReader::new(&doc).unwrap();
+                    blocks.push(rdr.map(|(t, _)| t.timestamp()).collect::<Vec<_>>());
+                }
+            }
+            (blocks, bytes.len())
+        };
+
+        let seconds: Vec<i64> = (0..10).map(|i| i * 3).collect();
+        let duration = WriterOptions {
+            max_block_duration: Some(chrono::Duration::seconds(10)),
+            ..WriterOptions::default()
+        };
+        assert_eq!(
+            blocks(duration, &seconds).0,
+            [vec![0, 3, 6, 9], vec![12, 15, 18, 21], vec![24, 27]]
+        );
+
+        let gap = WriterOptions {
+            max_sample_gap: Some(chrono::Duration::seconds(30)),
+            ..WriterOptions::default()
+        };
+        assert_eq!(
+            blocks(gap, &[0, 1, 2, 100, 101, 200]).0,
+            [vec![0, 1, 2], vec![100, 101], vec![200]]
+        );
+
+        // Every level decodes to the same samples, storing them is the largest
+        let seconds: Vec<i64> = (0..200).collect();
+        let (default_blocks, default_size) = blocks(WriterOptions::default(), &seconds);
+        assert_eq!(default_blocks.concat(), seconds);
+        for level in [0, 1, 3, 9, 42] {
+            let options = WriterOptions {
+                compression_level: level,
+                ..WriterOptions::default()
+            };
+            let (level_blocks, size) = blocks(options, &seconds);
+            assert_eq!(level_blocks, default_blocks);
+            if level == 0 {
+                assert!(size > default_size);
+            }
+        }
+    }
+
+    #[test]
+    #[allow(deprecated)]
     fn test_roundtrip_bson() {
         let mut buf = Vec::with_capacity(1024).writer();
 
@@ -141,10 +475,1526 @@ mod test {
         //et addresult = writer.add_doc(&doc! {"a": 7, "x" : 9, "s" : "t"}).unwrap();
     }
 
-    // TODO - test duplicate fields - will need RAW BSON API
-    /*
-    > .systemMetrics.mounts./boot/efi.capacity
-    > .systemMetrics.mounts./boot/efi.available
-    > .systemMetrics.mounts./boot/efi.free
-     */
+    #[test]
+    fn test_roundtrip_bson_writer() {
+        let samples: Vec<_> = (1..=5).map(|i| doc! {"a": i, "x" : 2, "s" : "t"}).collect();
+
+        let mut buf = Vec::with_capacity(1024);
+        {
+            let mut writer = BSONBlockWriter::new(&mut buf, max_samples(3));
+            let date = Utc.timestamp_nanos(42);
+            for sample in &samples {
+                assert_ok!(writer.add_sample(sample, date));
+            }
+            assert_ok!(writer.close());
+        }
+
+        let mut rows = Vec::new();
+        for block in BSONBlockReader::new_reader(buf.as_slice()).unwrap() {
+            let RawBSONBlock::Metrics(doc) = block.unwrap() else {
+                panic!("expected metrics");
+            };
+            rows.extend(MetricsReader::new(&doc).unwrap().map(|(_, d)| match d {
+                MetricsDocument::Reference(d) => d.to_document().unwrap(),
+                MetricsDocument::Metrics(d) => d.to_document().unwrap(),
+            }));
+        }
+        assert_eq!(rows, samples);
+    }
+
+    fn max_samples(max_samples: usize) -> WriterOptions {
+        WriterOptions {
+            max_samples,
+            ..WriterOptions::default()
+        }
+    }
+
+    #[test]
+    fn test_ftdc_buffer() {
+        let mut buf = FtdcBuffer::new(max_samples(3));
+        let mut writer = BSONBlockWriter::new(Vec::new(), max_samples(3));
+
+        let date = Utc.timestamp_nanos(42);
+        assert_ok!(buf.add_metadata_doc(&doc! {"host": "h"}, date));
+        assert_ok!(writer.add_metdata_doc(&doc! {"host": "h"}, date));
+        for i in 0..5 {
+            let sample = doc! {"a": i, "x": 2};
+            assert_ok!(buf.add_sample(&sample, date));
+            assert_ok!(writer.add_sample(&sample, date));
+        }
+
+        // into_inner writes the unfinished block like close
+        let bytes = writer.into_inner().unwrap();
+
+        let blocks: Vec<_> = buf.into_reader().unwrap().map(|b| b.unwrap()).collect();
+        let mut buffered = Vec::new();
+        let mut rows = Vec::new();
+        for block in &blocks {
+            match block {
+                RawBSONBlock::Metadata(doc) => {
+                    assert_eq!(doc.get_document("doc").unwrap().get_str("host"), Ok("h"));
+                    buffered.extend_from_slice(doc.as_bytes());
+                }
+                RawBSONBlock::Metrics(doc) => {
+                    rows.extend(MetricsReader::new(doc).unwrap().map(|(_, d)| match d {
+                        MetricsDocument::Reference(d) => d.to_document().unwrap(),
+                        MetricsDocument::Metrics(d) => d.to_document().unwrap(),
+                    }));
+                    buffered.extend_from_slice(doc.as_bytes());
+                }
+                RawBSONBlock::PeriodicMetadata(_) => panic!("no periodic metadata was written"),
+            }
+        }
+        assert_eq!(blocks.len(), 3);
+        let expected: Vec<_> = (0..5).map(|i| doc! {"a": i, "x": 2}).collect();
+        assert_eq!(rows, expected);
+        assert_eq!(buffered, bytes);
+    }
+
+    #[test]
+    fn test_block_id_is_reference_date() {
+        let secs = |i: i64| Utc.timestamp_opt(i, 0).unwrap();
+
+        // Blocks end when full and on a schema change at 4, in both the document and raw paths
+        for raw in [false, true] {
+            let mut buf = FtdcBuffer::new(max_samples(3));
+            for i in 0..8 {
+                let sample = match i {
+                    0..=3 => doc! {"a": i},
+                    _ => doc! {"a": i, "b": 1},
+                };
+                if raw {
+                    let raw_sample = RawDocumentBuf::from_document(&sample).unwrap();
+                    assert_ok!(buf.add_raw_sample(&raw_sample, secs(i)));
+                } else {
+                    assert_ok!(buf.add_sample(&sample, secs(i)));
+                }
+            }
+
+            let dates: Vec<_> = buf
+                .into_reader()
+                .unwrap()
+                .map(|b| block_date(&b.unwrap()))
+                .collect();
+            assert_eq!(dates, [secs(0), secs(3), secs(4), secs(7)].map(Some));
+        }
+    }
+
+    fn write_test_stream() -> Vec<u8> {
+        let mut buf = FtdcBuffer::new(max_samples(3));
+
+        let date = Utc.timestamp_nanos(42);
+        assert_ok!(buf.add_metadata_doc(&doc! {"host": "h"}, date));
+        for i in 0..5 {
+            assert_ok!(buf.add_sample(&doc! {"a": i, "x" : 2, "s" : "t"}, date));
+        }
+
+        buf.into_bytes().unwrap()
+    }
+
+    #[test]
+    fn test_reader_clean_eof() {
+        let bytes = write_test_stream();
+
+        let rdr = BSONBlockReader::new_reader(bytes.as_slice()).unwrap();
+        let blocks: Vec<_> = rdr.collect::<Result<_, _>>().unwrap();
+
+        assert_eq!(blocks.len(), 3);
+        assert!(matches!(blocks[0], RawBSONBlock::Metadata(_)));
+        assert!(matches!(blocks[1], RawBSONBlock::Metrics(_)));
+    }
+
+    #[test]
+    fn test_reader_truncated_block() {
+        let bytes = write_test_stream();
+        let truncated = &bytes[..bytes.len() - 3];
+
+        let mut rdr = BSONBlockReader::new_reader(truncated).unwrap();
+        assert!(matches!(rdr.next(), Some(Ok(RawBSONBlock::Metadata(_)))));
+        assert!(matches!(rdr.next(), Some(Ok(RawBSONBlock::Metrics(_)))));
+
+        let last_offset = rdr.offset();
+        match rdr.next() {
+            Some(Err(FtdcError::TruncatedBlock { offset, .. })) => {
+                assert_eq!(offset, last_offset)
+            }
+            _ => panic!("expected truncated block"),
+        }
+        assert!(rdr.next().is_none());
+    }
+
+    #[test]
+    fn test_reader_unknown_block_type() {
+        let mut bytes = bson::to_vec(&doc! {"_id": 1, "type": 7}).unwrap();
+        bytes.extend(write_test_stream());
+
+        let mut rdr = BSONBlockReader::new_reader(bytes.as_slice()).unwrap();
+        assert!(matches!(
+            rdr.next(),
+            Some(Err(FtdcError::UnknownBlockType {
+                offset: 0,
+                block_type: 7
+            }))
+        ));
+
+        // The reader steps past the unknown block
+        assert!(matches!(rdr.next(), Some(Ok(RawBSONBlock::Metadata(_)))));
+    }
+
+    #[test]
+    fn test_reader_recovery_resync() {
+        let stream = write_test_stream();
+
+        let metadata_len = {
+            let mut rdr = BSONBlockReader::new_reader(stream.as_slice()).unwrap();
+            rdr.next().unwrap().unwrap();
+            rdr.offset() as usize
+        };
+
+        // Clobber the length prefix of the first metrics block and insert garbage before it
+        let mut bytes = stream[..metadata_len].to_vec();
+        bytes.extend_from_slice(&[0xff, 0xff, 0xff, 0x7f, 1, 2, 3]);
+        bytes.extend_from_slice(&stream[metadata_len..]);
+
+        let mut rdr = BSONBlockReader::new_reader(bytes.as_slice())
+            .unwrap()
+            .with_recovery(true);
+
+        assert!(matches!(rdr.next(), Some(Ok(RawBSONBlock::Metadata(_)))));
+        match rdr.next() {
+            Some(Err(FtdcError::Skipped { offset, length, .. })) => {
+                assert_eq!(offset, metadata_len as u64);
+                assert_eq!(length, 7);
+            }
+            _ => panic!("expected skipped range"),
+        }
+        assert!(matches!(rdr.next(), Some(Ok(RawBSONBlock::Metrics(_)))));
+        assert!(matches!(rdr.next(), Some(Ok(RawBSONBlock::Metrics(_)))));
+        assert!(rdr.next().is_none());
+    }
+
+    #[test]
+    fn test_reader_recovery_truncated_tail() {
+        let bytes = write_test_stream();
+        let truncated = &bytes[..bytes.len() - 3];
+
+        let rdr = BSONBlockReader::new_reader(truncated)
+            .unwrap()
+            .with_recovery(true);
+        let results: Vec<_> = rdr.collect();
+
+        assert_eq!(results.len(), 3);
+        assert!(matches!(results[2], Err(FtdcError::Skipped { .. })));
+    }
+
+    #[test]
+    fn test_reader_recovery_io_error() {
+        struct FailingRead;
+
+        impl std::io::Read for FailingRead {
+            fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+                Err(std::io::Error::other("disk error"))
+            }
+        }
+
+        // A bad length prefix starts a scan that runs into the read error
+        let mut bytes = vec![1, 0, 0, 0];
+        bytes.extend_from_slice(&[7; 100]);
+
+        let mut rdr =
+            BSONBlockReader::new_reader(std::io::Read::chain(bytes.as_slice(), FailingRead))
+                .unwrap()
+                .with_recovery(true);
+
+        assert!(matches!(
+            rdr.next(),
+            Some(Err(FtdcError::Io { offset: 1, .. }))
+        ));
+        assert!(rdr.next().is_none());
+    }
+
+    #[test]
+    fn test_periodic_metadata_deltas() {
+        let mut bytes = Vec::new();
+        for block in [
+            doc! {"_id": 1, "type": 0, "doc": {"host": "a", "params": {"x": 1, "y": 2}}},
+            doc! {"_id": 2, "type": 2, "counter": 0_i64, "doc": {"params": {"x": 3}}},
+            doc! {"_id": 3, "type": 2, "counter": 1_i64, "doc": {"version": "7.0"}},
+        ] {
+            bytes.extend(bson::to_vec(&block).unwrap());
+        }
+
+        let mut tracker = MetadataTracker::new();
+        let mut rdr = BSONBlockReader::new_reader(bytes.as_slice()).unwrap();
+
+        let b1 = rdr.next().unwrap().unwrap();
+        assert!(matches!(b1, RawBSONBlock::Metadata(_)));
+        tracker.update(&b1).unwrap();
+
+        let b2 = rdr.next().unwrap().unwrap();
+        assert!(matches!(b2, RawBSONBlock::PeriodicMetadata(_)));
+        let full = bson::Document::try_from(tracker.update(&b2).unwrap().unwrap()).unwrap();
+        // The section is replaced as a whole, y is gone from it
+        assert_eq!(full, doc! {"host": "a", "params": {"x": 3}});
+
+        let b3 = rdr.next().unwrap().unwrap();
+        let full = bson::Document::try_from(tracker.update(&b3).unwrap().unwrap()).unwrap();
+        assert_eq!(
+            full,
+            doc! {"host": "a", "params": {"x": 3}, "version": "7.0"}
+        );
+    }
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ftdc_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Sample times in seconds of every metrics block in a diagnostic.data directory
+    fn read_sample_seconds(dir: &std::path::Path) -> Vec<i64> {
+        let mut times = Vec::new();
+        for block in DiagnosticDataReader::new(dir).unwrap() {
+            if let RawBSONBlock::Metrics(doc) = block.unwrap().block {
+                let rdr = VectorMetricsReader::new(&doc).unwrap();
+                times.extend(rdr.map(|(t, _)| t.timestamp()));
+            }
+        }
+        times
+    }
+
+    #[test]
+    fn test_diagnostic_data_writer() {
+        let dir = test_dir("diagnostic_data_writer");
+        let sample = |i: i64| {
+            let date = Utc.timestamp_opt(i, 0).unwrap();
+            (doc! {"start": date, "a": i, "s": "x"}, date)
+        };
+
+        let mut writer = DiagnosticDataWriter::new(&dir)
+            .unwrap()
+            .with_max_samples(5)
+            .with_max_file_size(200);
+        assert_ok!(writer.add_metadata_doc(&doc! {"host": "h"}, Utc.timestamp_opt(0, 0).unwrap()));
+        for i in 0..40 {
+            let (doc, date) = sample(i);
+            assert_ok!(writer.add_sample(&doc, date));
+        }
+        assert_ok!(writer.close());
+
+        let files = list_metrics_files(&dir).unwrap();
+        assert!(files.len() > 2);
+        assert_eq!(
+            files[0].file_name().unwrap(),
+            "metrics.1970-01-01T00-00-00Z-00000"
+        );
+        assert!(!dir.join(INTERIM_FILE_NAME).exists());
+        for file in &files {
+            // Every archive file starts with the metadata document
+            let first = BSONBlockReader::new(file.to_str().unwrap())
+                .unwrap()
+                .next()
+                .unwrap()
+                .unwrap();
+            assert!(matches!(first, RawBSONBlock::Metadata(_)));
+        }
+        assert_eq!(read_sample_seconds(&dir), (0..40).collect::<Vec<_>>());
+
+        // Trimming keeps the newest samples
+        let dir = test_dir("diagnostic_data_writer_trim");
+        let mut writer = DiagnosticDataWriter::new(&dir)
+            .unwrap()
+            .with_max_samples(5)
+            .with_max_file_size(200)
+            .with_max_directory_size(300);
+        for i in 0..40 {
+            let (doc, date) = sample(i);
+            assert_ok!(writer.add_sample(&doc, date));
+        }
+        assert_ok!(writer.close());
+
+        let times = read_sample_seconds(&dir);
+        assert!(times.len() < 40);
+        assert_eq!(times, (40 - times.len() as i64..40).collect::<Vec<_>>());
+
+        // A writer that was never closed or dropped leaves its samples in the interim file
+        let dir = test_dir("diagnostic_data_writer_interim");
+        let mut writer = DiagnosticDataWriter::new(&dir)
+            .unwrap()
+            .with_interim_samples(2);
+        for i in 0..5 {
+            let (doc, date) = sample(i);
+            assert_ok!(writer.add_sample(&doc, date));
+        }
+        std::mem::forget(writer);
+        assert_eq!(read_sample_seconds(&dir), [0, 1, 2, 3]);
+
+        // The next writer copies them to its first file
+        let mut writer = DiagnosticDataWriter::new(&dir).unwrap();
+        for i in 10..12 {
+            let (doc, date) = sample(i);
+            assert_ok!(writer.add_sample(&doc, date));
+        }
+        assert_ok!(writer.close());
+        assert_eq!(read_sample_seconds(&dir), [0, 1, 2, 3, 10, 11]);
+
+        // Recovered samples are kept by a writer that is closed or dropped before adding any
+        for i in 0..2 {
+            let dir = test_dir(&format!("diagnostic_data_writer_recover_empty_{}", i));
+            let mut writer = DiagnosticDataWriter::new(&dir)
+                .unwrap()
+                .with_interim_samples(1);
+            for i in 0..3 {
+                let (doc, date) = sample(i);
+                assert_ok!(writer.add_sample(&doc, date));
+            }
+            std::mem::forget(writer);
+
+            let writer = DiagnosticDataWriter::new(&dir).unwrap();
+            if i == 0 {
+                assert_ok!(writer.close());
+            } else {
+                drop(writer);
+            }
+            assert!(!dir.join(INTERIM_FILE_NAME).exists());
+            assert_eq!(read_sample_seconds(&dir), [0, 1, 2]);
+        }
+    }
+
+    #[test]
+    fn test_controller() {
+        use std::sync::atomic::{AtomicI64, Ordering};
+        use std::sync::Arc;
+        use std::time::Duration;
+
+        let dir = test_dir("controller");
+        let calls = Arc::new(AtomicI64::new(0));
+        let version = Arc::new(AtomicI64::new(1));
+
+        let counter = calls.clone();
+        let metadata_version = version.clone();
+        let mut controller = FtdcController::new(DiagnosticDataWriter::new(&dir).unwrap())
+            .with_period(Duration::from_millis(5))
+            .with_metadata_period(Duration::ZERO)
+            .with_collector(Box::new(FnCollector::new("counter", move || {
+                Ok(doc! {"calls": counter.fetch_add(1, Ordering::SeqCst)})
+            })))
+            .with_collector(Box::new(FnCollector::new("broken", || {
+                Err(anyhow::anyhow!("not available"))
+            })))
+            .with_metadata_collector(Box::new(FnCollector::new("build", move || {
+                Ok(doc! {"version": metadata_version.load(Ordering::SeqCst)})
+            })));
+
+        assert_ok!(controller.start());
+        assert!(controller.start().is_err());
+        std::thread::sleep(Duration::from_millis(50));
+        assert_ok!(controller.stop());
+        assert!(!controller.is_running());
+
+        // Only a change of the metadata is written as periodic metadata
+        version.store(2, Ordering::SeqCst);
+        assert_ok!(controller.start());
+        std::thread::sleep(Duration::from_millis(50));
+        assert_ok!(controller.shutdown());
+
+        let mut tracker = MetadataTracker::new();
+        let mut periodic = 0;
+        let mut counts = Vec::new();
+        for block in DiagnosticDataReader::new(&dir).unwrap() {
+            let block = block.unwrap().block;
+            match &block {
+                RawBSONBlock::Metrics(doc) => {
+                    for (_, d) in MetricsReader::new(doc).unwrap() {
+                        let d = match d {
+                            MetricsDocument::Reference(d) => d.to_document().unwrap(),
+                            MetricsDocument::Metrics(d) => d.to_document().unwrap(),
+                        };
+                        assert_eq!(d.keys().collect::<Vec<_>>(), ["start", "counter", "end"]);
+                        let section = d.get_document("counter").unwrap();
+                        assert_eq!(
+                            section.keys().collect::<Vec<_>>(),
+                            ["start", "calls", "end"]
+                        );
+                        counts.push(section.get_i64("calls").unwrap());
+                    }
+                }
+                RawBSONBlock::PeriodicMetadata(_) => periodic += 1,
+                RawBSONBlock::Metadata(_) => {}
+            }
+            tracker.update(&block).unwrap();
+        }
+
+        assert_eq!(periodic, 1);
+        let metadata = bson::Document::try_from(tracker.current().unwrap()).unwrap();
+        assert_eq!(metadata, doc! {"build": {"version": 2i64}});
+
+        // Every sample taken was written, in order
+        let taken = calls.load(Ordering::SeqCst);
+        assert!(taken > 2);
+        assert_eq!(counts, (0..taken).collect::<Vec<_>>());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    #[should_panic(expected = "sample period")]
+    fn test_controller_zero_period() {
+        let _ = FtdcController::new(BSONBlockWriter::new(Vec::new(), WriterOptions::default()))
+            .with_period(std::time::Duration::ZERO);
+    }
+
+    #[test]
+    fn test_system_metrics() {
+        let proc_dir = test_dir("system_metrics");
+        let files = [
+            (
+                "stat",
+                "cpu  100 2 30 4000 5 0 6 7 0 0\n\
+                 cpu0 50 1 15 2000 2 0 3 3 0 0\n\
+                 cpu1 50 1 15 2000 3 0 3 4 0 0\n\
+                 intr 12345 0 0\n\
+                 ctxt 987\n\
+                 btime 1700000000\n\
+                 processes 42\n\
+                 procs_running 2\n\
+                 procs_blocked 0\n",
+            ),
+            (
+                "meminfo",
+                "MemTotal:       16000 kB\n\
+                 MemFree:         8000 kB\n\
+                 Active(anon):     100 kB\n\
+                 HugePages_Total:    0\n",
+            ),
+            (
+                "diskstats",
+                "   7       0 loop0 1 0 2 0 0 0 0 0 0 0 0 0 0 0 0\n\
+                    8       0 sda 10 1 200 30 20 2 400 50 0 60 80 0 0 0 0\n",
+            ),
+            (
+                "net/dev",
+                "Inter-|   Receive                                                |  Transmit\n\
+                  face |bytes    packets errs drop fifo frame compressed multicast|bytes    packets errs drop fifo colls carrier compressed\n\
+                     lo:  1000      10    0    0    0     0          0         0     1000      10    0    0    0     0       0          0\n\
+                   eth0:  5000      50    1    2    0     0          0         3     7000      70    0    0    0     0       0          0\n",
+            ),
+            ("loadavg", "0.52 1.05 2.00 3/456 7890\n"),
+            ("vmstat", "nr_free_pages 123\npgfault 456\n"),
+            ("sys/kernel/hostname", "box\n"),
+        ];
+        for (name, contents) in files {
+            let path = proc_dir.join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, contents).unwrap();
+        }
+
+        let mut collector = SystemMetricsCollector::with_proc_dir(&proc_dir);
+        assert_eq!(collector.name(), "systemMetrics");
+        let metrics = collector.collect().unwrap();
+
+        assert_eq!(
+            metrics.get_document("cpu").unwrap(),
+            &doc! {
+                "user_ms": 1000i64, "nice_ms": 20i64, "system_ms": 300i64, "idle_ms": 40000i64,
+                "iowait_ms": 50i64, "irq_ms": 0i64, "softirq_ms": 60i64, "steal_ms": 70i64,
+                "guest_ms": 0i64, "guest_nice_ms": 0i64, "ctxt": 987i64, "btime": 1700000000i64,
+                "processes": 42i64, "procs_running": 2i64, "procs_blocked": 0i64, "num_cpus": 2i64,
+            }
+        );
+        assert_eq!(
+            metrics.get_document("memory").unwrap(),
+            &doc! {"MemTotal_kb": 16000i64, "MemFree_kb": 8000i64, "Active(anon)_kb": 100i64, "HugePages_Total": 0i64}
+        );
+
+        let disks = metrics.get_document("disks").unwrap();
+        assert_eq!(disks.keys().collect::<Vec<_>>(), ["sda"]);
+        let sda = disks.get_document("sda").unwrap();
+        assert_eq!(sda.get_i64("read_sectors"), Ok(200));
+        assert_eq!(sda.get_i64("io_queued_ms"), Ok(80));
+
+        let network = metrics.get_document("network").unwrap();
+        assert_eq!(network.keys().collect::<Vec<_>>(), ["lo", "eth0"]);
+        let eth0 = network.get_document("eth0").unwrap();
+        assert_eq!(eth0.get_i64("rx_bytes"), Ok(5000));
+        assert_eq!(eth0.get_i64("rx_multicast"), Ok(3));
+        assert_eq!(eth0.get_i64("tx_packets"), Ok(70));
+
+        assert_eq!(
+            metrics.get_document("loadavg").unwrap(),
+            &doc! {"load1_x100": 52i64, "load5_x100": 105i64, "load15_x100": 200i64, "runnable": 3i64, "tasks": 456i64}
+        );
+        assert_eq!(
+            metrics.get_document("vmstat").unwrap(),
+            &doc! {"nr_free_pages": 123i64, "pgfault": 456i64}
+        );
+        assert!(parse_loadavg("0.5 0.5").is_err());
+
+        // Files a kernel or container does not have are left out
+        std::fs::remove_file(proc_dir.join("vmstat")).unwrap();
+        let metrics = collector.collect().unwrap();
+        assert!(!metrics.contains_key("vmstat"));
+
+        let host = HostInfoCollector::with_proc_dir(&proc_dir)
+            .collect()
+            .unwrap();
+        assert_eq!(
+            host,
+            doc! {"hostname": "box", "num_cpus": 2i64, "mem_total_kb": 16000i64}
+        );
+
+        let _ = std::fs::remove_dir_all(&proc_dir);
+    }
+
+    #[test]
+    fn test_block_writer_close() {
+        let dir = test_dir("block_writer_close");
+        let file = dir.join("metrics.ftdc");
+        let interim = interim_file_path(&file);
+        let sample = |i: i64| {
+            let date = Utc.timestamp_opt(i, 0).unwrap();
+            (doc! {"start": date, "a": i}, date)
+        };
+
+        // A block of just the reference document is still written
+        let mut writer = BSONBlockWriter::new_file(&file, 3).unwrap();
+        let (doc, date) = sample(0);
+        assert_ok!(writer.add_sample(&doc, date));
+        assert_ok!(writer.close());
+        assert_eq!(read_sample_seconds(&file), [0]);
+
+        // Dropping the writer writes the unfinished block
+        let mut buf = Vec::with_capacity(1024);
+        {
+            let mut writer = BSONBlockWriter::new(&mut buf, max_samples(3));
+            for i in 0..5 {
+                let (doc, date) = sample(i);
+                assert_ok!(writer.add_sample(&doc, date));
+            }
+        }
+        let metrics = BSONBlockReader::new_reader(buf.as_slice())
+            .unwrap()
+            .map(|b| b.unwrap())
+            .filter(|b| matches!(b, RawBSONBlock::Metrics(_)))
+            .count();
+        assert_eq!(metrics, 2);
+
+        // A writer that is never dropped leaves its unfinished block in the interim file
+        let mut writer = BSONBlockWriter::new_file(&file, 3)
+            .unwrap()
+            .with_interim_file(interim.clone(), 1)
+            .unwrap();
+        for i in 0..8 {
+            let (doc, date) = sample(i);
+            assert_ok!(writer.add_sample(&doc, date));
+        }
+        std::mem::forget(writer);
+        assert_eq!(read_sample_seconds(&interim), [6, 7]);
+        assert_eq!(read_sample_seconds(&file), (0..8).collect::<Vec<_>>());
+
+        // The interim file of the earlier writer is not read after the new file
+        let stale = std::fs::read(&interim).unwrap();
+        let writer = BSONBlockWriter::new_file(&file, 3).unwrap();
+        assert!(!interim.exists());
+        std::fs::write(&interim, stale).unwrap();
+        let mut writer = writer.with_interim_file(interim.clone(), 1).unwrap();
+        assert!(read_sample_seconds(&file).is_empty());
+
+        let (doc, date) = sample(10);
+        assert_ok!(writer.add_sample(&doc, date));
+        assert_ok!(writer.close());
+        assert!(!interim.exists());
+        assert_eq!(read_sample_seconds(&file), [10]);
+    }
+
+    #[test]
+    fn test_diagnostic_data_reader_order() {
+        let dir = test_dir("diagnostic_data_order");
+
+        let names = [
+            "metrics.2024-01-02T00-00-00Z-00000",
+            "metrics.interim",
+            "metrics.2024-01-01T00-00-00Z-00001",
+            "metrics.2024-01-01T00-00-00Z-00000",
+        ];
+        for name in names {
+            let mut writer = BSONBlockWriter::new_file(&dir.join(name), 3).unwrap();
+            let date = Utc.timestamp_nanos(42);
+            assert_ok!(writer.add_metdata_doc(&doc! {"file": name}, date));
+        }
+        std::fs::write(dir.join("junk.txt"), "not ftdc").unwrap();
+
+        let rdr = DiagnosticDataReader::new(&dir).unwrap();
+        assert_eq!(rdr.files().len(), 4);
+
+        let files: Vec<String> = rdr
+            .map(|b| {
+                let b = b.unwrap();
+                match b.block {
+                    RawBSONBlock::Metadata(doc) => {
+                        let file = doc.get_document("doc").unwrap().get_str("file").unwrap();
+                        assert!(b.source.ends_with(file));
+                        file.to_string()
+                    }
+                    _ => panic!("expected metadata"),
+                }
+            })
+            .collect();
+
+        assert_eq!(
+            files,
+            vec![
+                "metrics.2024-01-01T00-00-00Z-00000",
+                "metrics.2024-01-01T00-00-00Z-00001",
+                "metrics.2024-01-02T00-00-00Z-00000",
+                "metrics.interim",
+            ]
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_diagnostic_data_reader_errors() {
+        let dir = test_dir("diagnostic_data_errors");
+
+        let bytes = write_test_stream();
+        let truncated = dir.join("metrics.2024-01-01T00-00-00Z-00000");
+        std::fs::write(&truncated, &bytes[..bytes.len() - 3]).unwrap();
+        let missing = dir.join("metrics.2024-01-02T00-00-00Z-00000");
+
+        let errors: Vec<FtdcError> =
+            DiagnosticDataReader::from_files(vec![truncated.clone(), missing.clone()])
+                .filter_map(|b| b.err())
+                .collect();
+        assert_eq!(errors.len(), 2);
+
+        match &errors[0] {
+            FtdcError::InFile { path, cause } => {
+                assert_eq!(path, &truncated);
+                assert!(matches!(**cause, FtdcError::TruncatedBlock { .. }));
+            }
+            e => panic!("expected an error in the file, got {}", e),
+        }
+        assert!(errors[0].offset() > 0);
+        assert!(errors[0]
+            .to_string()
+            .starts_with(&truncated.display().to_string()));
+
+        assert!(matches!(&errors[1], FtdcError::Open { path, .. } if path == &missing));
+        assert_eq!(errors[1].path(), Some(missing.as_path()));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_time_spec_parse() {
+        assert_eq!(
+            "-15m".parse::<TimeSpec>().unwrap(),
+            TimeSpec::FromEnd(chrono::Duration::minutes(15))
+        );
+        assert_eq!(
+            "2024-01-01T00:00:10Z".parse::<TimeSpec>().unwrap(),
+            TimeSpec::Absolute(Utc.timestamp_opt(1704067210, 0).unwrap())
+        );
+        assert!("-15".parse::<TimeSpec>().is_err());
+        assert!("-15w".parse::<TimeSpec>().is_err());
+    }
+
+    /// Metadata then samples one second apart with a `start` date, 3 samples per block
+    fn write_timed_stream(count: i64) -> Vec<u8> {
+        let mut buf = FtdcBuffer::new(max_samples(3));
+
+        assert_ok!(buf.add_metadata_doc(&doc! {"host": "h"}, Utc.timestamp_nanos(0)));
+        for i in 0..count {
+            let date = Utc.timestamp_opt(i, 0).unwrap();
+            assert_ok!(buf.add_sample(&doc! {"start": date, "a": i}, date));
+        }
+
+        buf.into_bytes().unwrap()
+    }
+
+    #[test]
+    fn test_time_range_filter() {
+        let bytes = write_timed_stream(10);
+        let range = TimeRange::new(
+            Some(Utc.timestamp_opt(4, 0).unwrap()),
+            Some(Utc.timestamp_opt(6, 0).unwrap()),
+        );
+
+        let rdr = BSONBlockReader::new_reader(bytes.as_slice())
+            .unwrap()
+            .with_time_range(range);
+
+        let mut blocks = 0;
+        let mut samples = Vec::new();
+        for block in rdr {
+            if let RawBSONBlock::Metrics(doc) = block.unwrap() {
+                blocks += 1;
+
+                for (t, m) in MetricsReader::new(&doc).unwrap().with_time_range(range) {
+                    let d = match m {
+                        MetricsDocument::Reference(d) if range.contains_doc(&d) => (*d).clone(),
+                        MetricsDocument::Reference(_) => continue,
+                        MetricsDocument::Metrics(d) => d,
+                    };
+                    assert_eq!(d.get_datetime("start").unwrap().to_chrono(), t);
+                    samples.push(d.get_i64("a").unwrap());
+                }
+            }
+        }
+
+        // Blocks hold samples [0, 2], [3, 5], [6, 8] and [9], only the middle two are decoded
+        assert_eq!(blocks, 2);
+        assert_eq!(samples, vec![4, 5, 6]);
+    }
+
+    #[test]
+    fn test_block_index() {
+        let secs = |s| Some(Utc.timestamp_opt(s, 0).unwrap());
+        let bytes = write_timed_stream(9);
+
+        let index =
+            BlockIndex::build(BSONBlockReader::new_reader(bytes.as_slice()).unwrap()).unwrap();
+        assert_eq!(index.file_length(), bytes.len() as u64);
+
+        let entries = index.entries();
+        assert_eq!(entries.len(), 4);
+        assert_eq!(entries[0].block_type, 0);
+        assert_eq!(entries[2].block_type, 1);
+        assert_eq!(entries[2].first_sample, secs(3));
+        assert_eq!(entries[2].last_sample, secs(5));
+        assert_eq!(entries[2].metrics_count, 2);
+        assert_eq!(entries[2].sample_count, 2);
+        assert_eq!(entries[1].schema, entries[2].schema);
+        assert_eq!(entries[3].last_sample, secs(8));
+        assert_eq!(index.last_sample(), secs(8));
+
+        let range = TimeRange::new(secs(4), secs(6));
+        let selected: Vec<u64> = index.select(&range).iter().map(|e| e.offset).collect();
+        assert_eq!(
+            selected,
+            vec![entries[0].offset, entries[2].offset, entries[3].offset]
+        );
+
+        // Seek past the first metrics block straight to the selected ones
+        let rdr = BSONBlockReader::new_reader(Cursor::new(bytes.clone()))
+            .unwrap()
+            .with_index(&index, range);
+        let mut firsts = Vec::new();
+        for block in rdr {
+            if let RawBSONBlock::Metrics(doc) = block.unwrap() {
+                let db = decode_metric_block(&doc).unwrap();
+                firsts.push(db.ref_doc.get_i64("a").unwrap());
+            }
+        }
+        assert_eq!(firsts, vec![3, 6]);
+
+        // The sidecar round trips and is rebuilt once the file grows
+        let dir = test_dir("block_index");
+        let path = dir.join("metrics.2024-01-01T00-00-00Z-00000");
+        std::fs::write(&path, &bytes).unwrap();
+
+        // A rebuild reads the file with the caller's limits
+        let tight = DecodeLimits {
+            max_block_size: 64,
+            ..DecodeLimits::default()
+        };
+        assert!(BlockIndex::open(&path, false, tight).is_err());
+        assert!(BlockIndex::build_file(&path, false, tight).is_err());
+
+        assert_eq!(
+            BlockIndex::open(&path, false, DecodeLimits::default()).unwrap(),
+            index
+        );
+        let sidecar = BlockIndex::sidecar_path(&path);
+        assert_eq!(BlockIndex::load(&sidecar).unwrap(), index);
+
+        // A corrupt sidecar is an error, not a panic
+        let saved = std::fs::read(&sidecar).unwrap();
+        for len in [-1_i32, 0, 4, i32::MAX] {
+            let mut corrupt = saved.clone();
+            corrupt[..4].copy_from_slice(&len.to_le_bytes());
+            std::fs::write(&sidecar, corrupt).unwrap();
+            assert!(BlockIndex::load(&sidecar).is_err());
+        }
+        std::fs::write(&sidecar, saved).unwrap();
+
+        std::fs::write(&path, write_timed_stream(12)).unwrap();
+        let rebuilt = BlockIndex::open(&path, false, DecodeLimits::default()).unwrap();
+        assert_eq!(rebuilt.last_sample(), secs(11));
+        assert_eq!(BlockIndex::load(&sidecar).unwrap(), rebuilt);
+
+        // The sidecar is not mistaken for an FTDC file
+        assert_eq!(DiagnosticDataReader::new(&dir).unwrap().files().len(), 1);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_parallel_decoder_order() {
+        let bytes = write_timed_stream(30);
+
+        let firsts = |threads| {
+            let rdr = BSONBlockReader::new_reader(bytes.as_slice()).unwrap();
+            ParallelDecoder::new(rdr, threads)
+                .map(|item| {
+                    let item = item.unwrap();
+                    match (item.block, item.metrics) {
+                        (RawBSONBlock::Metrics(_), Some(db)) => {
+                            db.unwrap().ref_doc.get_i64("a").unwrap()
+                        }
+                        (RawBSONBlock::Metadata(_), None) => -1,
+                        _ => panic!("metrics blocks are decoded, metadata is not"),
+                    }
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let expected: Vec<i64> = std::iter::once(-1).chain((0..30).step_by(3)).collect();
+        assert_eq!(firsts(1), expected);
+        assert_eq!(firsts(4), expected);
+    }
+
+    #[test]
+    fn test_columns() {
+        let mut bytes = Vec::with_capacity(1024);
+
+        {
+            let mut writer = BSONBlockWriter::new(&mut bytes, max_samples(10));
+            for i in 0..4 {
+                let date = Utc.timestamp_opt(i, 0).unwrap();
+                let sample = doc! {"start": date, "s": {"c": {"cur": i * 10, "s": "str"}, "t": 7}};
+                assert_ok!(writer.add_sample(&sample, date));
+            }
+            assert_ok!(writer.flush());
+        }
+
+        let block = BSONBlockReader::new_reader(bytes.as_slice())
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap();
+        let RawBSONBlock::Metrics(doc) = block else {
+            panic!("expected metrics");
+        };
+
+        let db = decode_metric_block(&doc).unwrap();
+        assert_eq!(db.paths(), ["start", "s.c.cur", "s.t"]);
+        assert_eq!(db.column_len(), 4);
+        assert_eq!(db.column(0), [0, 1000, 2000, 3000]);
+        assert_eq!(db.column_by_path("s.c.cur"), Some(&[0u64, 10, 20, 30][..]));
+        assert_eq!(db.column_by_path("s.t"), Some(&[7u64, 7, 7, 7][..]));
+        assert_eq!(db.column_by_path("s.c.s"), None);
+        assert_eq!(db.sample_value(2, 1), 30);
+    }
+
+    #[test]
+    fn test_decimal128_conversion() {
+        let cases = [
+            ("0", 0),
+            ("-0", 0),
+            ("42", 42),
+            ("-42", -42),
+            ("1.9", 1),
+            ("-1.9", -1),
+            ("1E+3", 1000),
+            ("1E-40", 0),
+            ("123456789012345678901234567890E-20", 1234567890),
+            ("9223372036854775807", i64::MAX),
+            ("-9223372036854775808", i64::MIN),
+            ("12345678901234567890", i64::MAX),
+            ("-1E+30", i64::MIN),
+            ("Infinity", i64::MAX),
+            ("-Infinity", i64::MIN),
+            ("NaN", 0),
+        ];
+
+        for (s, expected) in cases {
+            let d: bson::Decimal128 = s.parse().unwrap();
+            assert_eq!(decimal128_to_i64(d), expected, "{}", s);
+        }
+
+        for v in [0, 7, -7, i64::MAX, i64::MIN] {
+            assert_eq!(decimal128_to_i64(i64_to_decimal128(v)), v);
+            assert_eq!(i64_to_decimal128(v).to_string(), v.to_string());
+        }
+    }
+
+    #[test]
+    fn test_decimal128_roundtrip() {
+        let dec = |s: &str| s.parse::<bson::Decimal128>().unwrap();
+        let mut bytes = Vec::with_capacity(1024);
+
+        {
+            let mut writer = BSONBlockWriter::new(&mut bytes, max_samples(10));
+            for d in ["1.5", "2", "-100.9"] {
+                let sample = doc! {"a": 1, "d": dec(d), "s": {"n": dec(d)}};
+                assert_ok!(writer.add_sample(&sample, Utc.timestamp_nanos(42)));
+            }
+            assert_ok!(writer.flush());
+        }
+
+        let mut docs = Vec::new();
+        for block in BSONBlockReader::new_reader(bytes.as_slice()).unwrap() {
+            if let RawBSONBlock::Metrics(doc) = block.unwrap() {
+                let rdr = MetricsReader::new(&doc).unwrap();
+                assert_eq!(rdr.decoded_block.paths(), ["a", "d", "s.n"]);
+                assert_eq!(rdr.decoded_block.column(1), [1, 2, -100i64 as u64]);
+
+                for (_, m) in rdr {
+                    let d = match m {
+                        MetricsDocument::Reference(d) => (*d).clone(),
+                        MetricsDocument::Metrics(d) => d,
+                    };
+                    docs.push(bson::Document::try_from(d.as_ref()).unwrap());
+                }
+            }
+        }
+
+        // The reference document is stored as is, samples hold the integer part
+        assert_eq!(
+            docs,
+            vec![
+                doc! {"a": 1, "d": dec("1.5"), "s": {"n": dec("1.5")}},
+                doc! {"a": 1, "d": dec("2"), "s": {"n": dec("2")}},
+                doc! {"a": 1, "d": dec("-100"), "s": {"n": dec("-100")}},
+            ]
+        );
+    }
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn test_decoded_types_send_sync() {
+        assert_send_sync::<RawBSONBlock>();
+        assert_send_sync::<SourcedBlock>();
+        assert_send_sync::<DecodedMetricBlock>();
+        assert_send_sync::<MetricsDocument>();
+        assert_send_sync::<VectorMetricsDocument>();
+        assert_send_sync::<MetricsReader<'static>>();
+        assert_send_sync::<VectorMetricsReader<'static>>();
+        assert_send_sync::<DecodedBlock<SourcedBlock>>();
+        assert_send_sync::<FtdcError>();
+    }
+
+    #[test]
+    fn test_decode_corrupt_metrics() {
+        let d1 = doc! { "data" : bson::Binary{subtype: BinarySubtype::Generic, bytes: vec![10, 0, 0, 0, 1, 2, 3]} };
+        let raw_doc = RawDocumentBuf::from_document(&d1).unwrap();
+
+        assert!(matches!(
+            decode_metric_block(raw_doc.as_ref()),
+            Err(FtdcError::InflateFailure { .. })
+        ));
+    }
+
+    /// A metrics block for the reference document `{"a": 1}` with the given header and delta stream
+    fn hostile_metrics_block(
+        un_size: Option<i32>,
+        metrics: i32,
+        samples: i32,
+        stream: &[u8],
+    ) -> RawDocumentBuf {
+        let ref_doc = bson::to_vec(&doc! {"a": 1i64}).unwrap();
+        metrics_block_with_ref(un_size, &ref_doc, metrics, samples, stream)
+    }
+
+    /// `hostile_metrics_block` with the reference document bytes given as is
+    fn metrics_block_with_ref(
+        un_size: Option<i32>,
+        ref_doc: &[u8],
+        metrics: i32,
+        samples: i32,
+        stream: &[u8],
+    ) -> RawDocumentBuf {
+        let mut payload = ref_doc.to_vec();
+        payload.extend(metrics.to_le_bytes());
+        payload.extend(samples.to_le_bytes());
+        payload.extend(stream);
+
+        let mut encoder = libflate::zlib::Encoder::new(Vec::new()).unwrap();
+        encoder.write_all(&payload).unwrap();
+        let compressed = encoder.finish().into_result().unwrap();
+
+        let mut chunk = un_size
+            .unwrap_or(payload.len() as i32)
+            .to_le_bytes()
+            .to_vec();
+        chunk.extend(compressed);
+
+        RawDocumentBuf::from_document(
+            &doc! { "data" : bson::Binary{subtype: BinarySubtype::Generic, bytes: chunk} },
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_bad_reference_element() {
+        // The framing of the reference document is fine, the element type of "b" is not
+        let mut ref_doc = bson::to_vec(&doc! {"a": 1i64, "b": 2i64}).unwrap();
+        let b = 4 + 1 + 2 + 8;
+        assert_eq!(ref_doc[b], 0x12);
+        ref_doc[b] = 0x55;
+
+        let raw = RawDocument::from_bytes(&ref_doc).unwrap();
+        assert!(extract_metrics_paths_raw(raw).is_err());
+        assert!(fill_document_raw(raw, &[1, 2]).is_err());
+
+        let doc = metrics_block_with_ref(None, &ref_doc, 2, 0, &[]);
+        assert!(matches!(
+            decode_metric_block_at(&doc, 77),
+            Err(FtdcError::BadBson { offset: 77, .. })
+        ));
+        let report = validate_block(&RawBSONBlock::Metrics(doc.clone()), 0, None);
+        let issues: Vec<_> = report.issues.iter().map(|i| i.check).collect();
+        assert!(issues.contains(&Check::Header), "{:?}", issues);
+
+        // The parallel decoder reports the block instead of unwinding
+        let blocks = vec![Ok(RawBSONBlock::Metrics(doc))];
+        let decoded: Vec<_> = ParallelDecoder::new(blocks.into_iter(), 2).collect();
+        assert!(matches!(
+            decoded.as_slice(),
+            [Ok(DecodedBlock {
+                metrics: Some(Err(FtdcError::BadBson { .. })),
+                ..
+            })]
+        ));
+
+        // Strings are checked as well even though they carry no metric
+        let mut ref_doc = bson::to_vec(&doc! {"s": "x", "a": 1i64}).unwrap();
+        let x = ref_doc.iter().position(|&c| c == b'x').unwrap();
+        ref_doc[x] = 0xff;
+        let doc = metrics_block_with_ref(None, &ref_doc, 1, 0, &[]);
+        assert!(matches!(
+            decode_metric_block_at(&doc, 77),
+            Err(FtdcError::BadBson { offset: 77, .. })
+        ));
+    }
+
+    #[test]
+    fn test_decode_limits() {
+        let limit_of = |r: Result<DecodedMetricBlock, FtdcError>| match r {
+            Err(FtdcError::LimitExceeded { what, .. }) => what,
+            Err(e) => panic!("unexpected error {}", e),
+            Ok(_) => panic!("expected a limit error"),
+        };
+
+        let doc = hostile_metrics_block(None, 1, i32::MAX, &[]);
+        assert_eq!(limit_of(decode_metric_block(&doc)), "sample count");
+
+        let doc = hostile_metrics_block(None, i32::MAX, 1, &[]);
+        assert_eq!(limit_of(decode_metric_block(&doc)), "metric count");
+
+        let doc = hostile_metrics_block(Some(i32::MAX), 1, 0, &[]);
+        assert_eq!(limit_of(decode_metric_block(&doc)), "decompressed size");
+
+        // Counts within their own limits can still multiply past the value limit
+        let limits = DecodeLimits {
+            max_values: 100,
+            ..DecodeLimits::default()
+        };
+        let doc = hostile_metrics_block(None, 1, 1000, &[]);
+        assert_eq!(
+            limit_of(decode_metric_block_with_limits(&doc, 0, &limits)),
+            "decoded value count"
+        );
+
+        // Inflating stops just past the size in the header
+        let doc = hostile_metrics_block(Some(8), 1, 0, &[]);
+        assert!(matches!(
+            decode_metric_block(&doc),
+            Err(FtdcError::CorruptMetricData { .. })
+        ));
+
+        // Huge length prefixes are refused before anything is allocated
+        let mut bytes = i32::MAX.to_le_bytes().to_vec();
+        bytes.extend(write_test_stream());
+        let mut rdr = BSONBlockReader::new_reader(bytes.as_slice()).unwrap();
+        assert!(matches!(
+            rdr.next(),
+            Some(Err(FtdcError::LimitExceeded {
+                what: "block size",
+                ..
+            }))
+        ));
+
+        let limits = DecodeLimits {
+            max_block_size: 64,
+            ..DecodeLimits::default()
+        };
+        let bytes = write_test_stream();
+        let mut rdr = BSONBlockReader::new_reader(bytes.as_slice())
+            .unwrap()
+            .with_limits(limits);
+        assert!(matches!(rdr.next(), Some(Ok(RawBSONBlock::Metadata(_)))));
+        assert!(matches!(
+            rdr.next(),
+            Some(Err(FtdcError::LimitExceeded { offset, limit: 64, .. })) if offset > 0
+        ));
+    }
+
+    #[test]
+    fn test_validate_block() {
+        let checks = |doc: RawDocumentBuf, previous| {
+            let report = validate_block(&RawBSONBlock::Metrics(doc), 0, previous);
+            report.issues.iter().map(|i| i.check).collect::<Vec<_>>()
+        };
+
+        let bytes = write_timed_stream(5);
+        let blocks: Vec<_> = BSONBlockReader::new_reader(bytes.as_slice())
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let mut previous = None;
+        for block in &blocks {
+            let report = validate_block(block, 0, previous);
+            assert!(report.is_valid(), "{:?}", report.issues);
+            if let RawBSONBlock::Metrics(_) = block {
+                assert_eq!(report.metrics_count, Some(2));
+                previous = report.date;
+            }
+        }
+
+        // The second metrics block again, as if it was written after the clock went back
+        let RawBSONBlock::Metrics(doc) = &blocks[1] else {
+            panic!("expected metrics");
+        };
+        assert_eq!(checks(doc.clone(), previous), [Check::DateOrder]);
+
+        // Blocks built by hand have no _id
+        let stream = [0u8, 2];
+        assert_eq!(
+            checks(hostile_metrics_block(None, 1, 3, &stream), None),
+            [Check::DateOrder]
+        );
+        assert_eq!(
+            checks(hostile_metrics_block(Some(8), 1, 3, &stream), None)[1..],
+            [Check::UncompressedSize]
+        );
+        assert_eq!(
+            checks(hostile_metrics_block(None, 2, 0, &[]), None)[1..],
+            [Check::MetricCount]
+        );
+        assert_eq!(
+            checks(hostile_metrics_block(None, 1, 3, &[5]), None)[1..],
+            [Check::StreamLength]
+        );
+        assert_eq!(
+            checks(hostile_metrics_block(None, 1, 3, &[0, 2, 7]), None)[1..],
+            [Check::StreamLength]
+        );
+
+        let overrun = hostile_metrics_block(None, 1, 3, &[0, 5]);
+        assert_eq!(checks(overrun.clone(), None)[1..], [Check::ZeroRun]);
+        assert!(matches!(
+            decode_metric_block(&overrun),
+            Err(FtdcError::CorruptMetricData { .. })
+        ));
+        assert!(decode_metric_block(&hostile_metrics_block(None, 1, 3, &stream)).is_ok());
+
+        let d1 = doc! { "data" : bson::Binary{subtype: BinarySubtype::UserDefined(0x80), bytes: vec![]} };
+        assert_eq!(
+            checks(RawDocumentBuf::from_document(&d1).unwrap(), None)[1..],
+            [Check::Subtype, Check::UncompressedSize]
+        );
+    }
+
+    #[test]
+    fn test_duplicate_fields() {
+        // mongod can repeat a mount point, bson::Document would merge them so build it raw
+        let mut efi1 = RawDocumentBuf::new();
+        efi1.append("capacity", 1i64);
+        let mut efi2 = RawDocumentBuf::new();
+        efi2.append("capacity", 2i64);
+        let mut mounts = RawDocumentBuf::new();
+        mounts.append("/boot/efi", efi1);
+        mounts.append("/boot/efi", efi2);
+
+        let mut ref_doc = RawDocumentBuf::new();
+        ref_doc.append("mounts", mounts);
+        ref_doc.append("a.b", 3i32);
+        ref_doc.append(
+            "a",
+            RawDocumentBuf::from_document(&doc! {"b": 4i32}).unwrap(),
+        );
+        ref_doc.append(
+            "arr",
+            RawBson::Array(RawArrayBuf::from_iter([
+                RawBson::Int32(5),
+                RawBson::Int32(6),
+            ])),
+        );
+
+        let paths = extract_metrics_paths_raw(&ref_doc).unwrap();
+        let names: Vec<String> = paths.iter().map(|p| p.unique_name()).collect();
+        assert_eq!(
+            names,
+            [
+                "mounts./boot/efi.capacity",
+                "mounts./boot/efi.capacity#1",
+                "a\\.b",
+                "a.b",
+                "arr[0]",
+                "arr[1]"
+            ]
+        );
+        assert_eq!(
+            paths[1].path,
+            MetricPath::from_iter(["mounts", "/boot/efi", "capacity"])
+        );
+        assert_eq!(paths[1].occurrence, 1);
+        assert_eq!(paths[1].index, 1);
+        assert_eq!(paths[2].path, MetricPath::from_iter(["a.b"]));
+        assert_eq!(paths[3].path, MetricPath::from_iter(["a", "b"]));
+        assert_eq!(paths[4].path.last(), Some(&PathSegment::Index(0)));
+
+        // Values stay attached to their own position
+        let metrics = extract_metrics_raw(&ref_doc).unwrap();
+        assert_eq!(metrics, [1, 2, 3, 4, 5, 6]);
+
+        let filled = fill_document_raw(&ref_doc, &[10, 20, 30, 40, 50, 60]).unwrap();
+        assert_eq!(
+            extract_metrics_raw(&filled).unwrap(),
+            [10, 20, 30, 40, 50, 60]
+        );
+        assert_eq!(extract_metrics_paths_raw(&filled).unwrap(), paths);
+    }
+
+    #[test]
+    fn test_metric_path_text_form() {
+        let cases = [
+            ("serverStatus.opcounters.insert", 3),
+            ("systemMetrics.mounts./boot/efi\\.old.capacity", 4),
+            ("members[0].health", 3),
+            ("[1][2].x", 3),
+            ("a\\\\b\\[c\\]\\#d", 1),
+            ("a..b", 3),
+            ("", 0),
+        ];
+        for (text, len) in cases {
+            let path = MetricPath::parse(text).unwrap();
+            assert_eq!(path.len(), len, "{}", text);
+            assert_eq!(path.to_string(), text);
+        }
+
+        let path: MetricPath = "mounts./boot/efi\\.old".parse().unwrap();
+        assert_eq!(
+            path.segments(),
+            [
+                PathSegment::Key("mounts".into()),
+                PathSegment::Key("/boot/efi.old".into())
+            ]
+        );
+
+        assert_eq!(
+            MetricPath::parse_with_occurrence("a[3].b#2").unwrap(),
+            (
+                MetricPath::from(vec![
+                    PathSegment::Key("a".into()),
+                    PathSegment::Index(3),
+                    PathSegment::Key("b".into())
+                ]),
+                Some(2)
+            )
+        );
+        assert!(MetricPath::parse("a#1").is_err());
+
+        for bad in [
+            "a[x]", "a[1", "a]", "a[0]b", "a\\", "a\\q", "a#", "a#1b", "[]",
+        ] {
+            assert!(MetricPath::parse_with_occurrence(bad).is_err(), "{}", bad);
+        }
+
+        let parent = MetricPath::parse("serverStatus.opcounters").unwrap();
+        assert!(MetricPath::parse("serverStatus.opcounters.insert")
+            .unwrap()
+            .starts_with(&parent));
+        assert!(!MetricPath::parse("serverStatus.opcountersRepl.insert")
+            .unwrap()
+            .starts_with(&parent));
+    }
+
+    #[test]
+    fn test_column_by_path_dotted_keys() {
+        let mut bytes = Vec::with_capacity(1024);
+        {
+            let mut writer = BSONBlockWriter::new(&mut bytes, max_samples(3));
+            let date = Utc.timestamp_nanos(42);
+            for i in 0..3i64 {
+                assert_ok!(writer.add_sample(&doc! {"a.b": i, "a": {"b": i * 10}}, date));
+            }
+            assert_ok!(writer.flush());
+        }
+
+        let block = BSONBlockReader::new_reader(bytes.as_slice())
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap();
+        let RawBSONBlock::Metrics(doc) = block else {
+            panic!("expected metrics");
+        };
+
+        // A dotted key no longer collides with the nested path of the same text
+        let db = decode_metric_block(&doc).unwrap();
+        assert_eq!(db.paths(), ["a\\.b", "a.b"]);
+        assert_eq!(db.column_by_path("a\\.b"), Some(&[0u64, 1, 2][..]));
+        assert_eq!(db.column_by_path("a.b"), Some(&[0u64, 10, 20][..]));
+        assert_eq!(
+            db.column_by_metric_path(&MetricPath::from_iter(["a.b"]), 0),
+            Some(&[0u64, 1, 2][..])
+        );
+        assert_eq!(db.column_by_path("a.b#1"), None);
+    }
+
+    #[test]
+    fn test_typed_values() {
+        let mut bytes = Vec::with_capacity(1024);
+        {
+            let mut writer = BSONBlockWriter::new(&mut bytes, max_samples(3));
+            for i in 0..3i64 {
+                let date = Utc
+                    .timestamp_millis_opt(1_700_000_000_000 + i * 1000)
+                    .unwrap();
+                assert_ok!(writer.add_sample(
+                    &doc! {
+                        "start": date,
+                        "n": -5 - i,
+                        "i": -3i32,
+                        "d": -2.5 - i as f64,
+                        "b": i % 2 == 0,
+                        "ts": bson::Timestamp { time: 100, increment: i as u32 },
+                    },
+                    date
+                ));
+            }
+            assert_ok!(writer.flush());
+        }
+
+        let block = BSONBlockReader::new_reader(bytes.as_slice())
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap();
+        let RawBSONBlock::Metrics(doc) = block else {
+            panic!("expected metrics");
+        };
+        let db = decode_metric_block(&doc).unwrap();
+
+        assert_eq!(db.metric_type(3), MetricType::Double);
+        assert_eq!(
+            db.typed_row(2),
+            [
+                MetricValue::DateTime(Utc.timestamp_millis_opt(1_700_000_002_000).unwrap()),
+                MetricValue::I64(-7),
+                MetricValue::I64(-3),
+                MetricValue::F64(-4.0),
+                MetricValue::Bool(true),
+                MetricValue::Timestamp(100),
+                MetricValue::Timestamp(2),
+            ]
+        );
+        assert_eq!(
+            db.typed_column(4),
+            [
+                MetricValue::Bool(true),
+                MetricValue::Bool(false),
+                MetricValue::Bool(true)
+            ]
+        );
+
+        let text: Vec<String> = db.typed_row(1).iter().map(|v| v.to_string()).collect();
+        assert_eq!(
+            text,
+            [
+                "2023-11-14T22:13:21.000Z",
+                "-6",
+                "-3",
+                "-3",
+                "false",
+                "100",
+                "1"
+            ]
+        );
+        assert_eq!(MetricValue::Bool(true).as_i64(), 1);
+        assert_eq!(MetricValue::I64(-1).as_f64(), -1.0);
+    }
+
+    #[test]
+    fn test_negative_double_roundtrip() {
+        // Doubles are truncated to an i64 and stored as its two's complement, like mongod
+        let sample = |i: i64| doc! {"d": -2.5 - i as f64, "n": 1.5 * i as f64};
+        let raw = RawDocumentBuf::from_document(&sample(0)).unwrap();
+        assert_eq!(extract_metrics_raw(&raw).unwrap(), [-2_i64 as u64, 0]);
+
+        let mut buf = FtdcBuffer::new(max_samples(4));
+        let date = Utc.timestamp_nanos(42);
+        for i in 0..4 {
+            let raw = RawDocumentBuf::from_document(&sample(i)).unwrap();
+            assert_ok!(buf.add_raw_sample(&raw, date));
+        }
+
+        let mut rows = Vec::new();
+        for block in buf.into_reader().unwrap() {
+            if let RawBSONBlock::Metrics(doc) = block.unwrap() {
+                rows.extend(MetricsReader::new(&doc).unwrap().map(|(_, d)| match d {
+                    MetricsDocument::Reference(d) => d.to_document().unwrap(),
+                    MetricsDocument::Metrics(d) => d.to_document().unwrap(),
+                }));
+            }
+        }
+
+        // The reference document is stored as is, samples are truncated
+        let expected: Vec<_> = [(-2.5, 0.0), (-3.0, 1.0), (-4.0, 3.0), (-5.0, 4.0)]
+            .iter()
+            .map(|&(d, n)| doc! {"d": d, "n": n})
+            .collect();
+        assert_eq!(rows, expected);
+    }
+
+    #[test]
+    fn test_sample_times() {
+        let bytes = write_timed_stream(5);
+        let blocks: Vec<_> = BSONBlockReader::new_reader(bytes.as_slice())
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let RawBSONBlock::Metrics(doc) = &blocks[2] else {
+            panic!("expected metrics");
+        };
+
+        let rdr = VectorMetricsReader::new(doc).unwrap();
+        assert_eq!(
+            rdr.decoded_block.block_date(),
+            Some(Utc.timestamp_opt(3, 0).unwrap())
+        );
+        assert_eq!(
+            rdr.decoded_block.time_range(),
+            TimeRange::new(
+                Some(Utc.timestamp_opt(3, 0).unwrap()),
+                Some(Utc.timestamp_opt(4, 0).unwrap())
+            )
+        );
+        let times: Vec<_> = rdr.map(|(t, _)| t.timestamp()).collect();
+        assert_eq!(times, [3, 4]);
+
+        // Without a start field every sample gets the block _id
+        let bytes = write_test_stream();
+        let block = BSONBlockReader::new_reader(bytes.as_slice())
+            .unwrap()
+            .nth(1)
+            .unwrap()
+            .unwrap();
+        let RawBSONBlock::Metrics(doc) = block else {
+            panic!("expected metrics");
+        };
+        let mut rdr = VectorMetricsReader::new(&doc).unwrap();
+        assert_eq!(
+            rdr.decoded_block.row_time(2),
+            Utc.timestamp_millis_opt(0).unwrap()
+        );
+        assert!(rdr.all(|(t, _)| t == Utc.timestamp_millis_opt(0).unwrap()));
+    }
 }
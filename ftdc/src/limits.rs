@@ -0,0 +1,86 @@
+// Copyright [2024] [Mark Benvenuto]
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::error::FtdcError;
+
+/// Largest document mongod will write
+pub const DEFAULT_MAX_BLOCK_SIZE: usize = 16 * 1024 * 1024;
+
+/// Room for a maximum size reference document plus its samples
+pub const DEFAULT_MAX_DECOMPRESSED_SIZE: usize = 64 * 1024 * 1024;
+
+/// mongod records a few thousand metrics per sample
+pub const DEFAULT_MAX_METRICS: usize = 1024 * 1024;
+
+/// mongod writes 300 samples per block by default, this leaves room for writers configured with
+/// far larger blocks while keeping a hostile sample count from sizing allocations
+pub const DEFAULT_MAX_SAMPLES: usize = 1024 * 1024;
+
+/// 512MB of decoded metrics
+pub const DEFAULT_MAX_VALUES: usize = 64 * 1024 * 1024;
+
+/**
+ * Bounds on the sizes a block claims for itself
+ *
+ * Length prefixes and counts in an FTDC file are trusted to size allocations, a corrupt or
+ * hostile file could otherwise make the reader allocate gigabytes. A block over a limit fails
+ * with `FtdcError::LimitExceeded`. The defaults are well above anything mongod writes.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DecodeLimits {
+    /// Largest BSON block read from the stream
+    pub max_block_size: usize,
+
+    /// Largest inflated metrics chunk, the reference document plus the delta stream
+    pub max_decompressed_size: usize,
+
+    /// Most metrics in one reference document
+    pub max_metrics: usize,
+
+    /// Most samples in one metrics block
+    pub max_samples: usize,
+
+    /// Most decoded values in one block, metrics times samples plus the reference document
+    pub max_values: usize,
+}
+
+impl Default for DecodeLimits {
+    fn default() -> DecodeLimits {
+        DecodeLimits {
+            max_block_size: DEFAULT_MAX_BLOCK_SIZE,
+            max_decompressed_size: DEFAULT_MAX_DECOMPRESSED_SIZE,
+            max_metrics: DEFAULT_MAX_METRICS,
+            max_samples: DEFAULT_MAX_SAMPLES,
+            max_values: DEFAULT_MAX_VALUES,
+        }
+    }
+}
+
+pub(crate) fn check_limit(
+    offset: u64,
+    what: &'static str,
+    limit: usize,
+    actual: usize,
+) -> Result<(), FtdcError> {
+    if actual > limit {
+        return Err(FtdcError::LimitExceeded {
+            offset,
+            what,
+            limit,
+            actual,
+        });
+    }
+
+    Ok(())
+}
@@ -0,0 +1,95 @@
+// Copyright [2024] [Mark Benvenuto]
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use anyhow::Result;
+use bson::RawDocument;
+use bson::RawDocumentBuf;
+
+use crate::reader::RawBSONBlock;
+
+/**
+ * Apply a periodic metadata delta on top of a full metadata document
+ *
+ * Like mongod, the delta holds whole top-level sections. A section present in the delta replaces
+ * the section in base as a whole, so fields it no longer has are dropped, and sections only
+ * present in the delta are appended.
+ */
+pub fn apply_metadata_delta(base: &RawDocument, delta: &RawDocument) -> Result<RawDocumentBuf> {
+    let mut out = RawDocumentBuf::new();
+
+    for item in base {
+        let (key, base_value) = item?;
+
+        match delta.get(key)? {
+            Some(delta_value) => out.append(key, delta_value.to_raw_bson()),
+            None => out.append(key, base_value.to_raw_bson()),
+        }
+    }
+
+    for item in delta {
+        let (key, delta_value) = item?;
+
+        if base.get(key)?.is_none() {
+            out.append(key, delta_value.to_raw_bson());
+        }
+    }
+
+    Ok(out)
+}
+
+/**
+ * Track the full metadata document as type 0 and type 2 blocks are read
+ *
+ * A type 0 block replaces the current metadata, a type 2 block is a delta of the fields that
+ * changed and is applied on top of the current metadata.
+ */
+#[derive(Default)]
+pub struct MetadataTracker {
+    current: Option<RawDocumentBuf>,
+}
+
+impl MetadataTracker {
+    pub fn new() -> MetadataTracker {
+        MetadataTracker { current: None }
+    }
+
+    /// The reconstructed metadata document as of the last block passed to update
+    pub fn current(&self) -> Option<&RawDocument> {
+        self.current.as_deref()
+    }
+
+    /**
+     * Update the tracked metadata from a block, metrics blocks are ignored
+     *
+     * Returns the full metadata document if the block changed it.
+     */
+    pub fn update(&mut self, block: &RawBSONBlock) -> Result<Option<&RawDocument>> {
+        match block {
+            RawBSONBlock::Metadata(doc) => {
+                self.current = Some(doc.get_document("doc")?.to_raw_document_buf());
+            }
+            RawBSONBlock::PeriodicMetadata(doc) => {
+                let delta = doc.get_document("doc")?;
+
+                self.current = Some(match &self.current {
+                    Some(base) => apply_metadata_delta(base, delta)?,
+                    None => delta.to_raw_document_buf(),
+                });
+            }
+            RawBSONBlock::Metrics(_) => return Ok(None),
+        }
+
+        Ok(self.current())
+    }
+}
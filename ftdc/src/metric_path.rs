@@ -0,0 +1,253 @@
+// Copyright [2024] [Mark Benvenuto]
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+
+/// One step from a document down to a metric
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum PathSegment {
+    /// Field name in a document, may contain any character including dots
+    Key(String),
+
+    /// Element of an array
+    Index(usize),
+}
+
+/**
+ * Location of a metric in a reference document
+ *
+ * The text form joins keys with `.` and writes array elements as `[n]`, for example
+ * `serverStatus.opLatencies.reads.ops` or `replSetGetStatus.members[0].health`. A `\` escapes
+ * `.`, `[`, `]`, `#` and `\` inside a key, so the mount point key `/boot/efi.old` is written
+ * `systemMetrics.mounts./boot/efi\.old.capacity`. `#` is reserved for the occurrence suffix of
+ * repeated keys, see `parse_with_occurrence`.
+ *
+ * The empty string is the empty path, a path made of a single empty key has no text form.
+ */
+#[derive(Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct MetricPath {
+    segments: Vec<PathSegment>,
+}
+
+/// A string that is not a valid `MetricPath`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MetricPathError {
+    /// Byte position in the input of the problem
+    pub position: usize,
+    pub message: &'static str,
+}
+
+impl fmt::Display for MetricPathError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "invalid metric path at position {}: {}",
+            self.position, self.message
+        )
+    }
+}
+
+impl Error for MetricPathError {}
+
+fn is_special(c: char) -> bool {
+    matches!(c, '.' | '[' | ']' | '#' | '\\')
+}
+
+impl MetricPath {
+    pub fn new() -> MetricPath {
+        MetricPath::default()
+    }
+
+    pub fn segments(&self) -> &[PathSegment] {
+        &self.segments
+    }
+
+    pub fn len(&self) -> usize {
+        self.segments.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    pub fn push_key(&mut self, key: impl Into<String>) {
+        self.segments.push(PathSegment::Key(key.into()));
+    }
+
+    pub fn push_index(&mut self, index: usize) {
+        self.segments.push(PathSegment::Index(index));
+    }
+
+    pub fn pop(&mut self) -> Option<PathSegment> {
+        self.segments.pop()
+    }
+
+    /// Last segment, the field name of most metrics
+    pub fn last(&self) -> Option<&PathSegment> {
+        self.segments.last()
+    }
+
+    /// True if `prefix` is this path or one of its parents
+    pub fn starts_with(&self, prefix: &MetricPath) -> bool {
+        self.segments.starts_with(&prefix.segments)
+    }
+
+    /// Parse the text form, see the type documentation for the syntax
+    pub fn parse(s: &str) -> Result<MetricPath, MetricPathError> {
+        match MetricPath::parse_with_occurrence(s)? {
+            (path, None) => Ok(path),
+            (_, Some(_)) => Err(MetricPathError {
+                position: s.rfind('#').unwrap_or(0),
+                message: "unexpected occurrence suffix",
+            }),
+        }
+    }
+
+    /**
+     * Parse the text form followed by an optional `#<n>` occurrence suffix
+     *
+     * This is the form of `MetricTypeInfo::unique_name`, `n` counts earlier metrics with the same
+     * path in a document that repeats a key.
+     */
+    pub fn parse_with_occurrence(s: &str) -> Result<(MetricPath, Option<usize>), MetricPathError> {
+        let mut path = MetricPath::new();
+        if s.is_empty() {
+            return Ok((path, None));
+        }
+
+        let err = |position, message| Err(MetricPathError { position, message });
+
+        let mut chars = s.char_indices().peekable();
+        let mut key = String::new();
+        // False right after an array index, otherwise a key is pending even when it is empty
+        let mut want_key = true;
+
+        while let Some((pos, c)) = chars.next() {
+            match c {
+                '\\' => match chars.next() {
+                    Some((_, e)) if is_special(e) => key.push(e),
+                    Some((epos, _)) => return err(epos, "unknown escape"),
+                    None => return err(pos, "trailing escape"),
+                },
+                '.' => {
+                    if want_key {
+                        path.push_key(std::mem::take(&mut key));
+                    }
+                    want_key = true;
+                }
+                '[' => {
+                    if want_key && (!key.is_empty() || pos > 0) {
+                        path.push_key(std::mem::take(&mut key));
+                    }
+                    want_key = false;
+
+                    let start = pos + 1;
+                    let mut end = start;
+                    loop {
+                        match chars.next() {
+                            Some((_, d)) if d.is_ascii_digit() => end += 1,
+                            Some((_, ']')) => break,
+                            Some((p, _)) => return err(p, "expected a digit or ']'"),
+                            None => return err(s.len(), "unterminated array index"),
+                        }
+                    }
+
+                    match s[start..end].parse::<usize>() {
+                        Ok(idx) => path.push_index(idx),
+                        Err(_) => return err(start, "invalid array index"),
+                    }
+
+                    if let Some(&(p, n)) = chars.peek() {
+                        if !matches!(n, '.' | '[' | '#') {
+                            return err(p, "expected '.' or '[' after an array index");
+                        }
+                    }
+                }
+                ']' => return err(pos, "unexpected ']'"),
+                '#' => {
+                    if want_key && (!key.is_empty() || pos > 0) {
+                        path.push_key(std::mem::take(&mut key));
+                    }
+
+                    let rest = &s[pos + 1..];
+                    if rest.is_empty() || !rest.bytes().all(|b| b.is_ascii_digit()) {
+                        return err(pos + 1, "occurrence must be a number");
+                    }
+                    return match rest.parse::<usize>() {
+                        Ok(n) => Ok((path, Some(n))),
+                        Err(_) => err(pos + 1, "occurrence must be a number"),
+                    };
+                }
+                _ => key.push(c),
+            }
+        }
+
+        if want_key {
+            path.push_key(key);
+        }
+
+        Ok((path, None))
+    }
+}
+
+impl fmt::Display for MetricPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, segment) in self.segments.iter().enumerate() {
+            match segment {
+                PathSegment::Key(key) => {
+                    if i > 0 {
+                        f.write_str(".")?;
+                    }
+                    for c in key.chars() {
+                        if is_special(c) {
+                            f.write_str("\\")?;
+                        }
+                        write!(f, "{}", c)?;
+                    }
+                }
+                PathSegment::Index(idx) => write!(f, "[{}]", idx)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl FromStr for MetricPath {
+    type Err = MetricPathError;
+
+    fn from_str(s: &str) -> Result<MetricPath, MetricPathError> {
+        MetricPath::parse(s)
+    }
+}
+
+impl From<Vec<PathSegment>> for MetricPath {
+    fn from(segments: Vec<PathSegment>) -> MetricPath {
+        MetricPath { segments }
+    }
+}
+
+impl<'a> FromIterator<&'a str> for MetricPath {
+    /// Build a path of keys
+    fn from_iter<I: IntoIterator<Item = &'a str>>(iter: I) -> MetricPath {
+        MetricPath {
+            segments: iter
+                .into_iter()
+                .map(|k| PathSegment::Key(k.to_string()))
+                .collect(),
+        }
+    }
+}
@@ -0,0 +1,238 @@
+// Copyright [2024] [Mark Benvenuto]
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::panic;
+use std::panic::AssertUnwindSafe;
+use std::sync::mpsc;
+use std::sync::mpsc::Receiver;
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread;
+use std::thread::JoinHandle;
+
+use crate::error::FtdcError;
+use crate::limits::DecodeLimits;
+use crate::reader::decode_metric_block_with_limits;
+use crate::reader::AsRawBlock;
+use crate::reader::DecodedMetricBlock;
+use crate::reader::RawBSONBlock;
+
+/// A block along with its decoded metrics
+pub struct DecodedBlock<T> {
+    pub block: T,
+
+    /// Some for metrics blocks, None for metadata blocks
+    pub metrics: Option<Result<DecodedMetricBlock, FtdcError>>,
+}
+
+type DecodeResult = Option<Result<DecodedMetricBlock, FtdcError>>;
+type Job<T> = (u64, T, DecodeLimits);
+type JobResult<T> = (u64, T, Result<DecodeResult, Box<dyn Any + Send>>);
+
+fn decode<T: AsRawBlock>(block: &T, limits: &DecodeLimits) -> DecodeResult {
+    match block.raw_block() {
+        RawBSONBlock::Metrics(doc) => Some(decode_metric_block_with_limits(
+            doc,
+            block.block_offset(),
+            limits,
+        )),
+        RawBSONBlock::Metadata(_) | RawBSONBlock::PeriodicMetadata(_) => None,
+    }
+}
+
+fn worker<T: AsRawBlock>(jobs: Arc<Mutex<Receiver<Job<T>>>>, results: Sender<JobResult<T>>) {
+    loop {
+        let job = jobs.lock().expect("job queue lock").recv();
+        let Ok((seq, block, limits)) = job else {
+            return;
+        };
+
+        // Hand panics back to the reading thread so they surface the same as a serial decode
+        let result = panic::catch_unwind(AssertUnwindSafe(|| decode(&block, &limits)));
+
+        if results.send((seq, block, result)).is_err() {
+            return;
+        }
+    }
+}
+
+/**
+ * Decode the metrics blocks of a block reader on a pool of worker threads
+ *
+ * Blocks are read on the calling thread and handed to the workers, results are returned in the
+ * same order the blocks were read. Only a few blocks per thread are in flight at a time so memory
+ * use does not depend on the size of the input. With one thread blocks are decoded inline.
+ */
+pub struct ParallelDecoder<I, T>
+where
+    I: Iterator<Item = Result<T, FtdcError>>,
+    T: AsRawBlock + Send + 'static,
+{
+    inner: I,
+    inner_done: bool,
+
+    jobs: Option<Sender<Job<T>>>,
+    results: Receiver<JobResult<T>>,
+    workers: Vec<JoinHandle<()>>,
+
+    limits: DecodeLimits,
+    max_in_flight: u64,
+    next_seq: u64,
+    issued: u64,
+    ready: BTreeMap<u64, Result<DecodedBlock<T>, FtdcError>>,
+}
+
+impl<I, T> ParallelDecoder<I, T>
+where
+    I: Iterator<Item = Result<T, FtdcError>>,
+    T: AsRawBlock + Send + 'static,
+{
+    pub fn new(inner: I, threads: usize) -> ParallelDecoder<I, T> {
+        let (job_tx, job_rx) = mpsc::channel::<Job<T>>();
+        let (result_tx, result_rx) = mpsc::channel::<JobResult<T>>();
+        let job_rx = Arc::new(Mutex::new(job_rx));
+
+        let workers = if threads > 1 {
+            (0..threads)
+                .map(|_| {
+                    let jobs = job_rx.clone();
+                    let results = result_tx.clone();
+                    thread::spawn(move || worker(jobs, results))
+                })
+                .collect()
+        } else {
+            Vec::new()
+        };
+
+        ParallelDecoder {
+            inner,
+            inner_done: false,
+            jobs: Some(job_tx),
+            results: result_rx,
+            workers,
+            limits: DecodeLimits::default(),
+            max_in_flight: threads.max(1) as u64 * 4,
+            next_seq: 0,
+            issued: 0,
+            ready: BTreeMap::new(),
+        }
+    }
+
+    /// Bound what each block may allocate while decoding, see `DecodeLimits`
+    pub fn with_limits(mut self, limits: DecodeLimits) -> ParallelDecoder<I, T> {
+        self.limits = limits;
+        self
+    }
+
+    /// Number of decoding threads, 0 when blocks are decoded on the calling thread
+    pub fn threads(&self) -> usize {
+        self.workers.len()
+    }
+
+    /// Read blocks from the inner reader until enough are in flight
+    fn fill(&mut self) {
+        while !self.inner_done && self.issued - self.next_seq < self.max_in_flight {
+            let Some(item) = self.inner.next() else {
+                self.inner_done = true;
+                return;
+            };
+
+            let seq = self.issued;
+            self.issued += 1;
+
+            match item {
+                Ok(block) if self.workers.is_empty() => {
+                    let metrics = decode(&block, &self.limits);
+                    self.ready.insert(seq, Ok(DecodedBlock { block, metrics }));
+                }
+                Ok(block) => {
+                    if let RawBSONBlock::Metrics(_) = block.raw_block() {
+                        self.jobs
+                            .as_ref()
+                            .expect("job queue open")
+                            .send((seq, block, self.limits))
+                            .expect("decoder threads running");
+                    } else {
+                        self.ready.insert(
+                            seq,
+                            Ok(DecodedBlock {
+                                block,
+                                metrics: None,
+                            }),
+                        );
+                    }
+                }
+                Err(e) => {
+                    self.ready.insert(seq, Err(e));
+                }
+            }
+        }
+    }
+}
+
+impl<I, T> Iterator for ParallelDecoder<I, T>
+where
+    I: Iterator<Item = Result<T, FtdcError>>,
+    T: AsRawBlock + Send + 'static,
+{
+    type Item = Result<DecodedBlock<T>, FtdcError>;
+
+    fn next(&mut self) -> Option<Result<DecodedBlock<T>, FtdcError>> {
+        self.fill();
+
+        loop {
+            if let Some(item) = self.ready.remove(&self.next_seq) {
+                self.next_seq += 1;
+                return Some(item);
+            }
+
+            if self.next_seq == self.issued {
+                return None;
+            }
+
+            let (seq, block, result) = self.results.recv().ok()?;
+            match result {
+                Ok(metrics) => {
+                    self.ready.insert(seq, Ok(DecodedBlock { block, metrics }));
+                }
+                Err(payload) => panic::resume_unwind(payload),
+            }
+        }
+    }
+}
+
+impl<I, T> Drop for ParallelDecoder<I, T>
+where
+    I: Iterator<Item = Result<T, FtdcError>>,
+    T: AsRawBlock + Send + 'static,
+{
+    fn drop(&mut self) {
+        // Closing the job queue stops the workers once they finish their current block
+        self.jobs = None;
+
+        for handle in self.workers.drain(..) {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Default thread count for `ParallelDecoder`, one per CPU
+pub fn default_threads() -> usize {
+    thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+}
@@ -1,23 +1,43 @@
+use std::collections::VecDeque;
 use std::fs::File;
 use std::io::BufReader;
+use std::io::ErrorKind;
 use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
 
 use anyhow::Result;
 use bson::spec::BinarySubtype;
 use bson::RawDocument;
 use bson::RawDocumentBuf;
-use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use byteorder::{LittleEndian, ReadBytesExt};
+use chrono::DateTime;
+use chrono::Utc;
 use libflate::zlib::Decoder;
+use std::collections::HashMap;
 use std::io::Cursor;
-use std::rc::Rc;
-
+use std::sync::Arc;
+use std::sync::OnceLock;
+
+use crate::error::FtdcError;
+use crate::index::BlockIndex;
+use crate::index::IndexedReader;
+use crate::limits::check_limit;
+use crate::limits::DecodeLimits;
+use crate::metric_path::MetricPath;
+use crate::time_range::TimeFilter;
+use crate::time_range::TimeRange;
 use crate::util::extract_metrics_paths_raw;
 use crate::util::extract_metrics_raw;
 use crate::util::fill_document_raw;
+use crate::util::find_top_level_date_index_raw;
+use crate::util::MetricType;
+use crate::util::MetricTypeInfo;
+use crate::util::MetricValue;
 
 #[derive(Debug)]
 pub enum MetricsDocument {
-    Reference(Rc<RawDocumentBuf>),
+    Reference(Arc<RawDocumentBuf>),
     Metrics(RawDocumentBuf),
 }
 
@@ -28,228 +48,797 @@ enum MetricState {
 
 pub struct BSONBlockReader<R: Read> {
     reader: BufReader<R>,
+    offset: u64,
+    done: bool,
+    recover: bool,
+    limits: DecodeLimits,
+
+    // Bytes read ahead while resynchronizing that have not been returned as a block yet
+    pending: VecDeque<u8>,
 }
 
 pub enum RawBSONBlock {
     Metadata(RawDocumentBuf),
     Metrics(RawDocumentBuf),
+    /// Delta of the metadata fields that changed since the last full metadata document
+    PeriodicMetadata(RawDocumentBuf),
+}
+
+/// Access the raw block of items produced by the block readers
+pub trait AsRawBlock {
+    fn raw_block(&self) -> &RawBSONBlock;
+
+    /// Byte offset of the block in its stream if known, used to report decoding errors
+    fn block_offset(&self) -> u64 {
+        0
+    }
+}
+
+impl AsRawBlock for RawBSONBlock {
+    fn raw_block(&self) -> &RawBSONBlock {
+        self
+    }
 }
 
 impl BSONBlockReader<File> {
     pub fn new(file_name: &str) -> Result<BSONBlockReader<File>> {
         let ff = File::open(file_name)?;
 
-        Ok(BSONBlockReader {
-            reader: BufReader::new(ff),
-        })
+        Ok(BSONBlockReader::from_buf_reader(BufReader::new(ff)))
     }
 }
 
 impl<R: Read> BSONBlockReader<R> {
     pub fn new_reader(reader: R) -> Result<BSONBlockReader<R>> {
-        Ok(BSONBlockReader {
-            reader: BufReader::<R>::new(reader),
-        })
+        Ok(BSONBlockReader::from_buf_reader(BufReader::<R>::new(
+            reader,
+        )))
     }
-}
 
-// #[derive(Serialize, Deserialize, Debug)]
-// pub struct MetadataDoc {
-//     #[serde(rename = "_id")]  // Use MongoDB's special primary key field name when serializing
-//     pub id: Date,
-//     pub type: i32,
-//     pub age: i32
-// }
+    pub(crate) fn from_buf_reader(reader: BufReader<R>) -> BSONBlockReader<R> {
+        BSONBlockReader {
+            reader,
+            offset: 0,
+            done: false,
+            recover: false,
+            limits: DecodeLimits::default(),
+            pending: VecDeque::new(),
+        }
+    }
 
-impl<R: Read> Iterator for BSONBlockReader<R> {
-    // add code here
-    type Item = RawBSONBlock;
+    /**
+     * In recovery mode a corrupt or truncated block does not end the stream. The reader scans
+     * forward for the next plausible block and reports the bytes it skipped as
+     * `FtdcError::Skipped`.
+     */
+    pub fn with_recovery(mut self, recover: bool) -> BSONBlockReader<R> {
+        self.recover = recover;
+        self
+    }
 
-    fn next(&mut self) -> Option<RawBSONBlock> {
-        let mut size_buf: [u8; 4] = [0, 0, 0, 0];
+    /// Bound the size of blocks read from the stream, see `DecodeLimits::max_block_size`
+    pub fn with_limits(mut self, limits: DecodeLimits) -> BSONBlockReader<R> {
+        self.limits = limits;
+        self
+    }
 
-        let result = self.reader.read_exact(&mut size_buf);
-        if result.is_err() {
-            return None;
+    pub fn limits(&self) -> DecodeLimits {
+        self.limits
+    }
+
+    /// Byte offset of the next block in the stream
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Skip blocks that have no samples in the range, see `TimeFilter`
+    pub fn with_time_range(self, range: TimeRange) -> TimeFilter<BSONBlockReader<R>> {
+        TimeFilter::new(self, range)
+    }
+
+    fn read_bytes(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let from_pending = self.pending.len().min(buf.len());
+        for (dst, src) in buf.iter_mut().zip(self.pending.drain(..from_pending)) {
+            *dst = src;
         }
 
-        // todo: size == 4
-        let mut size_rdr = Cursor::new(size_buf);
-        let size = size_rdr.read_i32::<LittleEndian>().unwrap();
-        // println!("size2 {}", size);
-        // // Look for the first 4 bytes
-        // let mut rdr = Cursor::new(self.buffer);
-        // // Note that we use type parameters to indicate which kind of byte order
-        // // we want!
-        // println!("size {}", size);
+        Ok(from_pending + read_fully(&mut self.reader, &mut buf[from_pending..])?)
+    }
 
-        let read_size = size as usize;
-        let mut v: Vec<u8> = Vec::with_capacity(read_size);
+    /**
+     * Read the next block, every byte consumed from the stream is left in v so a failed block
+     * can be rescanned in recovery mode
+     */
+    fn read_block(&mut self, v: &mut Vec<u8>) -> Option<Result<RawBSONBlock, FtdcError>> {
+        let offset = self.offset;
+
+        let mut size_buf: [u8; 4] = [0, 0, 0, 0];
+        match self.read_bytes(&mut size_buf) {
+            Ok(0) => return None,
+            Ok(4) => v.extend_from_slice(&size_buf),
+            Ok(n) => {
+                v.extend_from_slice(&size_buf[..n]);
+                return Some(Err(FtdcError::TruncatedBlock {
+                    offset,
+                    expected: 4,
+                    actual: n,
+                }));
+            }
+            Err(source) => return Some(Err(FtdcError::Io { offset, source })),
+        }
 
-        v.write_i32::<LittleEndian>(size).unwrap();
+        let size = i32::from_le_bytes(size_buf);
+        if size < MIN_BSON_SIZE {
+            return Some(Err(FtdcError::bad_bson(
+                offset,
+                format!("invalid document length {}", size),
+            )));
+        }
+        if let Err(e) = check_limit(
+            offset,
+            "block size",
+            self.limits.max_block_size,
+            size as usize,
+        ) {
+            return Some(Err(e));
+        }
 
+        let read_size = size as usize;
         v.resize(read_size, 0);
-        let result = self.reader.read_exact(&mut v[4..]);
-        if result.is_err() {
-            return None;
+
+        match self.read_bytes(&mut v[4..]) {
+            Ok(n) if n == read_size - 4 => {}
+            Ok(n) => {
+                v.truncate(n + 4);
+                return Some(Err(FtdcError::TruncatedBlock {
+                    offset,
+                    expected: read_size,
+                    actual: n + 4,
+                }));
+            }
+            Err(source) => return Some(Err(FtdcError::Io { offset, source })),
         }
 
-        // println!("size3 {}", v.len());
-        // println!("Pos: {}\n", self.reader.stream_position().unwrap());
+        self.offset += read_size as u64;
+
+        let ftdc_type = match block_type(v, offset) {
+            Ok(t) => t,
+            Err(e) => return Some(Err(e)),
+        };
+
+        let doc = match RawDocumentBuf::from_bytes(std::mem::take(v)) {
+            Ok(doc) => doc,
+            Err(e) => return Some(Err(FtdcError::bad_bson(offset, e))),
+        };
+
+        match ftdc_type {
+            0 => Some(Ok(RawBSONBlock::Metadata(doc))),
+            1 => Some(Ok(RawBSONBlock::Metrics(doc))),
+            2 => Some(Ok(RawBSONBlock::PeriodicMetadata(doc))),
+            _ => Some(Err(FtdcError::UnknownBlockType {
+                offset,
+                block_type: ftdc_type,
+            })),
+        }
+    }
 
-        let doc = RawDocumentBuf::from_bytes(v).unwrap();
+    /**
+     * Make sure at least `count` bytes are buffered in pending, returns false at end of stream
+     */
+    fn fill_pending(&mut self, count: usize) -> std::io::Result<bool> {
+        let mut chunk = [0u8; 8192];
 
-        let ftdc_type = doc.get_i32("type").unwrap();
+        while self.pending.len() < count {
+            let n = read_fully(&mut self.reader, &mut chunk)?;
+            if n == 0 {
+                return Ok(false);
+            }
+            self.pending.extend(&chunk[..n]);
+        }
+
+        Ok(true)
+    }
 
-        if ftdc_type == 0 {
-            return Some(RawBSONBlock::Metadata(doc));
-        } else if ftdc_type == 2 {
-            // TODO - fix
-            return Some(RawBSONBlock::Metadata(doc));
+    /**
+     * Scan forward one byte at a time from just past the start of a failed block until we find
+     * a length prefix that frames a BSON document with a known FTDC `type` field
+     */
+    fn resync(&mut self, start: u64, bytes: Vec<u8>, cause: FtdcError) -> FtdcError {
+        // Everything after the first byte of the bad block is a candidate for the next block
+        for b in bytes.into_iter().skip(1).rev() {
+            self.pending.push_front(b);
         }
 
-        Some(RawBSONBlock::Metrics(doc))
+        let mut candidate = start + 1;
+
+        loop {
+            match self.fill_pending(4) {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(source) => return self.scan_failed(candidate, source),
+            }
+
+            let prefix: Vec<u8> = self.pending.range(..4).copied().collect();
+            let size = i32::from_le_bytes([prefix[0], prefix[1], prefix[2], prefix[3]]);
+
+            let framed = size >= MIN_BSON_SIZE
+                && size as usize <= self.limits.max_block_size
+                && match self.fill_pending(size as usize) {
+                    Ok(framed) => framed,
+                    Err(source) => return self.scan_failed(candidate, source),
+                };
+
+            if framed {
+                let buf = self.pending.make_contiguous();
+                if matches!(block_type(&buf[..size as usize], candidate), Ok(0..=2)) {
+                    self.offset = candidate;
+
+                    return FtdcError::Skipped {
+                        offset: start,
+                        length: candidate - start,
+                        cause: Box::new(cause),
+                    };
+                }
+            }
+
+            self.pending.pop_front();
+            candidate += 1;
+        }
+
+        // No plausible block before the end of the stream, drop the tail
+        let end = candidate + self.pending.len() as u64;
+        self.pending.clear();
+        self.offset = end;
+        self.done = true;
+
+        FtdcError::Skipped {
+            offset: start,
+            length: end - start,
+            cause: Box::new(cause),
+        }
+    }
+
+    /// A read error while scanning is not damage, report it and stop rather than drop the tail
+    fn scan_failed(&mut self, offset: u64, source: std::io::Error) -> FtdcError {
+        self.pending.clear();
+        self.done = true;
+
+        FtdcError::Io { offset, source }
+    }
+}
+
+impl<R: Read + Seek> BSONBlockReader<R> {
+    /// Move to the block that starts at offset, such as one recorded in a `BlockIndex`
+    pub fn seek_to(&mut self, offset: u64) -> Result<(), FtdcError> {
+        self.reader
+            .seek(SeekFrom::Start(offset))
+            .map_err(|source| FtdcError::Io { offset, source })?;
+
+        self.pending.clear();
+        self.offset = offset;
+        self.done = false;
+
+        Ok(())
+    }
+
+    /// Only read the blocks the index says can have samples in the range
+    pub fn with_index(self, index: &BlockIndex, range: TimeRange) -> IndexedReader<R> {
+        IndexedReader::new(self, index, range)
+    }
+}
+
+/// Smallest possible BSON document, an i32 length and a trailing null
+pub(crate) const MIN_BSON_SIZE: i32 = 5;
+
+/**
+ * Read until buf is full or the reader hits EOF, returns the number of bytes read
+ */
+fn read_fully<R: Read>(reader: &mut R, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(read)
+}
+
+fn block_type(v: &[u8], offset: u64) -> Result<i32, FtdcError> {
+    let doc = RawDocument::from_bytes(v).map_err(|e| FtdcError::bad_bson(offset, e))?;
+
+    doc.get_i32("type")
+        .map_err(|e| FtdcError::bad_bson(offset, e))
+}
+
+// #[derive(Serialize, Deserialize, Debug)]
+// pub struct MetadataDoc {
+//     #[serde(rename = "_id")]  // Use MongoDB's special primary key field name when serializing
+//     pub id: Date,
+//     pub type: i32,
+//     pub age: i32
+// }
+
+impl<R: Read> Iterator for BSONBlockReader<R> {
+    type Item = Result<RawBSONBlock, FtdcError>;
+
+    fn next(&mut self) -> Option<Result<RawBSONBlock, FtdcError>> {
+        if self.done {
+            return None;
+        }
+
+        let offset = self.offset;
+        let mut bytes = Vec::new();
+
+        match self.read_block(&mut bytes) {
+            Some(Err(
+                e @ (FtdcError::TruncatedBlock { .. }
+                | FtdcError::BadBson { .. }
+                | FtdcError::LimitExceeded { .. }),
+            )) if self.recover => Some(Err(self.resync(offset, bytes, e))),
+            Some(Err(e)) => {
+                // Stop after an error we cannot step past so callers do not loop forever
+                if self.offset == offset {
+                    self.done = true;
+                }
+                Some(Err(e))
+            }
+            result => result,
+        }
     }
 }
 
 // TODO - use lifetime to avoid copy of vec
 #[derive(Debug)]
 pub enum VectorMetricsDocument {
-    Reference(Rc<RawDocumentBuf>),
+    Reference(Arc<RawDocumentBuf>),
     Metrics(Vec<u64>),
 }
 
 pub struct DecodedMetricBlock {
-    pub ref_doc: Rc<RawDocumentBuf>,
+    pub ref_doc: Arc<RawDocumentBuf>,
     pub ref_doc_size_bytes: usize,
 
     pub chunk_size_bytes: usize,
     pub sample_count: i32,
     pub metrics_count: i32,
 
+    // Column-major, each column is the reference document's value followed by every sample
     pub(crate) raw_metrics: Vec<u64>,
+
+    block_date: Option<DateTime<Utc>>,
+    paths: OnceLock<MetricPaths>,
+}
+
+/// Metric names of a reference document, built the first time they are asked for
+struct MetricPaths {
+    infos: Vec<MetricTypeInfo>,
+    names: Vec<String>,
+    index: HashMap<(MetricPath, usize), usize>,
 }
 
-pub fn decode_metric_block<'a>(doc: &'a RawDocument) -> Result<DecodedMetricBlock> {
-    let blob = doc.get_binary("data")?;
-    assert_eq!(blob.subtype, BinarySubtype::Generic);
+impl DecodedMetricBlock {
+    fn new(
+        ref_doc: Arc<RawDocumentBuf>,
+        ref_doc_size_bytes: usize,
+        chunk_size_bytes: usize,
+        sample_count: i32,
+        metrics_count: i32,
+        raw_metrics: Vec<u64>,
+        block_date: Option<DateTime<Utc>>,
+    ) -> DecodedMetricBlock {
+        DecodedMetricBlock {
+            ref_doc,
+            ref_doc_size_bytes,
+            chunk_size_bytes,
+            sample_count,
+            metrics_count,
+            raw_metrics,
+            block_date,
+            paths: OnceLock::new(),
+        }
+    }
+
+    /// Index of the top level `start` date that mongod records with each sample
+    pub fn start_metric_index(&self) -> Option<usize> {
+        find_top_level_date_index_raw(&self.ref_doc, "start")
+    }
+
+    /// The `_id` date of the block, None if the block did not have one
+    pub fn block_date(&self) -> Option<DateTime<Utc>> {
+        self.block_date
+    }
+
+    /**
+     * Time of a row, row 0 is the reference document and row `n` is sample `n - 1`
+     *
+     * This is the `start` date of the sample when the reference document has one, otherwise the
+     * block `_id`. Blocks with neither are at the Unix epoch.
+     */
+    pub fn row_time(&self, row: usize) -> DateTime<Utc> {
+        self.row_time_at(self.start_metric_index(), row)
+    }
+
+    fn row_time_at(&self, start_index: Option<usize>, row: usize) -> DateTime<Utc> {
+        let start = start_index
+            .and_then(|idx| DateTime::from_timestamp_millis(self.column(idx)[row] as i64));
+
+        start
+            .or(self.block_date)
+            .unwrap_or(DateTime::<Utc>::UNIX_EPOCH)
+    }
+
+    /// Times of the reference document and the last sample, see `row_time`
+    pub fn time_range(&self) -> TimeRange {
+        let start_index = self.start_metric_index();
+        TimeRange::new(
+            Some(self.row_time_at(start_index, 0)),
+            Some(self.row_time_at(start_index, self.column_len() - 1)),
+        )
+    }
+
+    /// Value of a metric in a sample, samples do not include the reference document
+    pub fn sample_value(&self, sample: i32, metric: i32) -> u64 {
+        self.raw_metrics[get_array_offset(self.column_len(), sample as usize + 1, metric as usize)]
+    }
+
+    /// Number of values in each column, the reference document plus every sample
+    pub fn column_len(&self) -> usize {
+        self.sample_count as usize + 1
+    }
+
+    /**
+     * All the values of one metric in time order, starting with the reference document
+     *
+     * Metrics are numbered in the order `util::extract_metrics_raw` visits the reference document.
+     * Panics if metric is not less than `metrics_count`.
+     */
+    pub fn column(&self, metric: usize) -> &[u64] {
+        let len = self.column_len();
+        &self.raw_metrics[metric * len..(metric + 1) * len]
+    }
+
+    /// Type of a metric, panics if metric is not less than `metrics_count`
+    pub fn metric_type(&self, metric: usize) -> MetricType {
+        self.metrics()[metric].metric_type
+    }
+
+    /// `column` converted according to the metric's type
+    pub fn typed_column(&self, metric: usize) -> Vec<MetricValue> {
+        let metric_type = self.metric_type(metric);
+        self.column(metric)
+            .iter()
+            .map(|&v| MetricValue::from_raw(metric_type, v))
+            .collect()
+    }
+
+    /**
+     * Every metric of one row converted according to its type, in column order
+     *
+     * Row 0 is the reference document and row `n` is sample `n - 1`, the same rows as `column`.
+     * Panics if row is not less than `column_len`.
+     */
+    pub fn typed_row(&self, row: usize) -> Vec<MetricValue> {
+        let len = self.column_len();
+        assert!(row < len, "row {} out of range for {} rows", row, len);
+        self.metrics()
+            .iter()
+            .map(|m| MetricValue::from_raw(m.metric_type, self.raw_metrics[m.index * len + row]))
+            .collect()
+    }
+
+    /**
+     * Column for a path such as `serverStatus.connections.current`, see `MetricPath` for the syntax
+     *
+     * A repeated key is addressed with a `#<n>` suffix, see `MetricTypeInfo::unique_name`.
+     */
+    pub fn column_by_path(&self, path: &str) -> Option<&[u64]> {
+        let (path, occurrence) = MetricPath::parse_with_occurrence(path).ok()?;
+        self.column_by_metric_path(&path, occurrence.unwrap_or(0))
+    }
+
+    /// Column for the `occurrence`th metric at `path`, 0 unless the document repeats a key
+    pub fn column_by_metric_path(&self, path: &MetricPath, occurrence: usize) -> Option<&[u64]> {
+        let idx = *self.metric_paths().index.get(&(path.clone(), occurrence))?;
+        Some(self.column(idx))
+    }
+
+    /// Unique name of each metric in column order, see `MetricTypeInfo::unique_name`
+    pub fn paths(&self) -> &[String] {
+        &self.metric_paths().names
+    }
+
+    /// Type and path of each metric, in column order
+    pub fn metrics(&self) -> &[MetricTypeInfo] {
+        &self.metric_paths().infos
+    }
+
+    fn metric_paths(&self) -> &MetricPaths {
+        self.paths.get_or_init(|| {
+            // Decoding already walked every element, a block built by hand has no paths if not
+            let infos = extract_metrics_paths_raw(&self.ref_doc).unwrap_or_default();
+            let names: Vec<String> = infos.iter().map(|m| m.unique_name()).collect();
+
+            let index = infos
+                .iter()
+                .map(|m| ((m.path.clone(), m.occurrence), m.index))
+                .collect();
+
+            MetricPaths {
+                infos,
+                names,
+                index,
+            }
+        })
+    }
+
+    fn sample_in_range(&self, range: &TimeRange, start_index: Option<usize>, sample: i32) -> bool {
+        match start_index {
+            Some(idx) if !range.is_unbounded() => {
+                range.contains_millis(self.sample_value(sample, idx as i32) as i64)
+            }
+            _ => true,
+        }
+    }
+}
+
+pub fn decode_metric_block(doc: &RawDocument) -> Result<DecodedMetricBlock, FtdcError> {
+    decode_metric_block_at(doc, 0)
+}
+
+/**
+ * Decode a metrics block, errors are reported against the block's byte offset in its stream
+ */
+pub fn decode_metric_block_at(
+    doc: &RawDocument,
+    offset: u64,
+) -> Result<DecodedMetricBlock, FtdcError> {
+    decode_metric_block_with_limits(doc, offset, &DecodeLimits::default())
+}
+
+/**
+ * Decode a metrics block, failing with `FtdcError::LimitExceeded` rather than allocating more
+ * than the limits allow
+ */
+pub fn decode_metric_block_with_limits(
+    doc: &RawDocument,
+    offset: u64,
+    limits: &DecodeLimits,
+) -> Result<DecodedMetricBlock, FtdcError> {
+    let blob = doc
+        .get_binary("data")
+        .map_err(|e| FtdcError::bad_bson(offset, e))?;
+    if blob.subtype != BinarySubtype::Generic {
+        return Err(FtdcError::bad_bson(
+            offset,
+            format!("unexpected binary subtype {:?}", blob.subtype),
+        ));
+    }
     let chunk_size_bytes = blob.bytes.len();
+    let block_date = doc.get_datetime("_id").ok().map(|d| d.to_chrono());
 
-    let mut size_rdr = Cursor::new(&blob.bytes);
-    let _un_size = size_rdr.read_i32::<LittleEndian>()?;
-    // println!("Uncompressed size {}", un_size);
+    if blob.bytes.len() < 4 {
+        return Err(FtdcError::corrupt(offset, "metrics chunk too small"));
+    }
 
-    // skip the length in the compressed blob
+    let mut size_rdr = Cursor::new(&blob.bytes);
+    let un_size = size_rdr
+        .read_i32::<LittleEndian>()
+        .map_err(|e| FtdcError::corrupt(offset, e.to_string()))?;
+    if un_size < 0 {
+        return Err(FtdcError::corrupt(
+            offset,
+            format!("invalid uncompressed size {}", un_size),
+        ));
+    }
+    let un_size = un_size as usize;
+    check_limit(
+        offset,
+        "decompressed size",
+        limits.max_decompressed_size,
+        un_size,
+    )?;
+
+    // skip the length in the compressed blob, and stop a zlib bomb one byte past the header size
     let mut decoded_data = Vec::<u8>::new();
-    let mut decoder = Decoder::new(&blob.bytes[4..])?;
-    decoder.read_to_end(&mut decoded_data)?;
+    let decoder = Decoder::new(&blob.bytes[4..])
+        .map_err(|source| FtdcError::InflateFailure { offset, source })?;
+    decoder
+        .take(un_size as u64 + 1)
+        .read_to_end(&mut decoded_data)
+        .map_err(|source| FtdcError::InflateFailure { offset, source })?;
+    if decoded_data.len() != un_size {
+        return Err(FtdcError::corrupt(
+            offset,
+            format!(
+                "inflated {} bytes, header says {}",
+                decoded_data.len(),
+                un_size
+            ),
+        ));
+    }
 
     let mut cur = Cursor::new(&decoded_data);
 
-    let ref_doc_size_bytes = cur.read_i32::<LittleEndian>()? as usize;
+    let ref_doc_size_bytes = cur
+        .read_i32::<LittleEndian>()
+        .map_err(|_| FtdcError::corrupt(offset, "missing reference document"))?;
+    if ref_doc_size_bytes < 0 || ref_doc_size_bytes as usize > decoded_data.len() {
+        return Err(FtdcError::corrupt(
+            offset,
+            format!("invalid reference document length {}", ref_doc_size_bytes),
+        ));
+    }
+    let ref_doc_size_bytes = ref_doc_size_bytes as usize;
 
-    // RawDocument::from_bytes expects the slice to be the length of the bson document
+    // RawDocument::from_bytes expects the slice to be the length of the bson document, it only
+    // checks the framing, extract_metrics_raw below walks every element
     let ref_doc_slice: &[u8] = &decoded_data[0..ref_doc_size_bytes];
-    let ref_doc = Rc::new(RawDocument::from_bytes(&ref_doc_slice)?.to_raw_document_buf());
+    let ref_doc = Arc::new(
+        RawDocument::from_bytes(ref_doc_slice)
+            .map_err(|e| FtdcError::bad_bson(offset, e))?
+            .to_raw_document_buf(),
+    );
 
     // Advance the cursor past the reference document
     cur.set_position(ref_doc_size_bytes as u64);
-    // let mut pos1: usize = cur.position() as usize;
-    // println!("pos:{:?}", pos1);
 
-    let metrics_count = cur.read_i32::<LittleEndian>()?;
-    // println!("metric_count {}", metrics_count);
-
-    let sample_count = cur.read_i32::<LittleEndian>()?;
-    // println!("sample_count {}", sample_count);
+    let metrics_count = cur
+        .read_i32::<LittleEndian>()
+        .map_err(|_| FtdcError::corrupt(offset, "missing metric count"))?;
+
+    let sample_count = cur
+        .read_i32::<LittleEndian>()
+        .map_err(|_| FtdcError::corrupt(offset, "missing sample count"))?;
+
+    if metrics_count < 0 || sample_count < 0 {
+        return Err(FtdcError::corrupt(
+            offset,
+            format!(
+                "negative counts, metrics {} samples {}",
+                metrics_count, sample_count
+            ),
+        ));
+    }
 
-    // Extract metrics from reference document
-    let ref_metrics = extract_metrics_raw(&ref_doc);
+    check_limit(
+        offset,
+        "metric count",
+        limits.max_metrics,
+        metrics_count as usize,
+    )?;
+    check_limit(
+        offset,
+        "sample count",
+        limits.max_samples,
+        sample_count as usize,
+    )?;
+    let values = (metrics_count as usize).saturating_mul(sample_count as usize + 1);
+    check_limit(offset, "decoded value count", limits.max_values, values)?;
+
+    // Extract metrics from reference document, a malformed element anywhere in it fails here
+    let ref_metrics = extract_metrics_raw(&ref_doc).map_err(|e| FtdcError::bad_bson(offset, e))?;
     if ref_metrics.len() != metrics_count as usize {
-        let paths = extract_metrics_paths_raw(&ref_doc);
-        for p in paths {
-            println!("{}", p.name);
-        }
+        return Err(FtdcError::MetricCountMismatch {
+            offset,
+            expected: metrics_count as usize,
+            actual: ref_metrics.len(),
+        });
     }
-    assert_eq!(ref_metrics.len(), metrics_count as usize);
-    // println!("{:?}", ref_metrics);
 
     // println!("Ref: Sample {} Metric {}", self.sample_count, self.metrics_count);
 
-    // Decode metrics
-    let mut raw_metrics = Vec::<u64>::with_capacity((metrics_count * sample_count) as usize);
-
     let mut zeros_count = 0;
 
     let mut pos: usize = cur.position() as usize;
-    // println!("pos:{:?}", pos);
-    let buf = decoded_data.as_ref();
+    let buf = decoded_data.as_slice();
 
     if sample_count == 0 || metrics_count == 0 {
-        return Ok(DecodedMetricBlock {
+        return Ok(DecodedMetricBlock::new(
             ref_doc,
             ref_doc_size_bytes,
             chunk_size_bytes,
             sample_count,
             metrics_count,
-            raw_metrics,
-        });
+            ref_metrics,
+            block_date,
+        ));
     }
 
-    raw_metrics.resize((sample_count * metrics_count) as usize, 0);
+    // Decode metrics, row 0 of each column is the reference document
+    let rows = sample_count as usize + 1;
+    let metrics = metrics_count as usize;
+    let mut raw_metrics = vec![0u64; values];
 
-    for i in 0..metrics_count {
-        for j in 0..sample_count {
-            // eprintln!("r{},{}", i, j);
+    for i in 0..metrics {
+        raw_metrics[get_array_offset(rows, 0, i)] = ref_metrics[i];
+    }
+
+    for i in 0..metrics {
+        for j in 1..rows {
             if zeros_count > 0 {
-                raw_metrics[get_array_offset(sample_count, j, i)] = 0;
+                raw_metrics[get_array_offset(rows, j, i)] = 0;
                 zeros_count -= 1;
                 continue;
             }
 
-            let mut val: u64 = 0;
-            let read_size = varinteger::decode_with_offset(buf, pos, &mut val);
-            pos += read_size;
+            let val = decode_varint(buf, &mut pos)
+                .ok_or_else(|| FtdcError::corrupt(offset, "metric stream ended early"))?;
 
             if val == 0 {
                 // Read zeros count
-                let read_size = varinteger::decode_with_offset(buf, pos, &mut zeros_count);
-                pos += read_size;
+                zeros_count = decode_varint(buf, &mut pos)
+                    .ok_or_else(|| FtdcError::corrupt(offset, "metric stream ended early"))?;
             }
 
-            raw_metrics[get_array_offset(sample_count, j, i)] = val;
+            raw_metrics[get_array_offset(rows, j, i)] = val;
         }
     }
 
-    assert_eq!(pos, buf.len());
-
-    // eprintln!("ddd: {:?}", raw_metrics);
+    if zeros_count > 0 {
+        return Err(FtdcError::corrupt(
+            offset,
+            format!(
+                "run of zeros is {} values longer than the block",
+                zeros_count
+            ),
+        ));
+    }
 
-    // Inflate the metrics
-    for i in 0..metrics_count {
-        let (v, _) = raw_metrics[get_array_offset(sample_count, 0, i)]
-            .overflowing_add(ref_metrics[i as usize] as u64);
-        raw_metrics[get_array_offset(sample_count, 0, i)] = v;
+    if pos != buf.len() {
+        return Err(FtdcError::corrupt(
+            offset,
+            format!("{} trailing bytes after metric stream", buf.len() - pos),
+        ));
     }
 
-    for i in 0..metrics_count {
-        for j in 1..sample_count {
-            let (v, _) = raw_metrics[get_array_offset(sample_count, j, i)]
-                .overflowing_add(raw_metrics[get_array_offset(sample_count, j - 1, i)]);
-            raw_metrics[get_array_offset(sample_count, j, i)] = v;
+    // Inflate the metrics, each sample is a delta from the one before it
+    for i in 0..metrics {
+        for j in 1..rows {
+            let (v, _) = raw_metrics[get_array_offset(rows, j, i)]
+                .overflowing_add(raw_metrics[get_array_offset(rows, j - 1, i)]);
+            raw_metrics[get_array_offset(rows, j, i)] = v;
         }
     }
 
-    Ok(DecodedMetricBlock {
+    Ok(DecodedMetricBlock::new(
         ref_doc,
         ref_doc_size_bytes,
         chunk_size_bytes,
         sample_count,
         metrics_count,
         raw_metrics,
-    })
+        block_date,
+    ))
 }
 
+/**
+ * Decode an unsigned LEB128 varint, returns None if the buffer ends or the value does not fit a u64
+ */
+pub(crate) fn decode_varint(buf: &[u8], pos: &mut usize) -> Option<u64> {
+    let mut val: u64 = 0;
+    let mut shift = 0;
+
+    loop {
+        let byte = *buf.get(*pos)?;
+        *pos += 1;
+
+        if shift > 63 {
+            return None;
+        }
+        val |= ((byte & 0x7f) as u64) << shift;
+
+        if byte & 0x80 == 0 {
+            return Some(val);
+        }
+        shift += 7;
+    }
+}
+
+/// Samples of a metrics block as documents along with their time, see `DecodedMetricBlock::row_time`
 // TODO - make this a wrapper around VectorMetricsReader
 pub struct MetricsReader<'a> {
     _doc: &'a RawDocument,
@@ -258,61 +847,97 @@ pub struct MetricsReader<'a> {
     it_state: MetricState,
     sample: i32,
     scratch: Vec<u64>,
+
+    range: TimeRange,
+    start_index: Option<usize>,
 }
 
 impl<'a> MetricsReader<'a> {
-    pub fn new<'b>(doc: &'b RawDocument) -> Result<MetricsReader<'b>> {
-        let db = decode_metric_block(doc)?;
+    pub fn new<'b>(doc: &'b RawDocument) -> Result<MetricsReader<'b>, FtdcError> {
+        Ok(MetricsReader::from_decoded(doc, decode_metric_block(doc)?))
+    }
+
+    /// Iterate a block that was already decoded, such as by a `ParallelDecoder`
+    pub fn from_decoded<'b>(doc: &'b RawDocument, db: DecodedMetricBlock) -> MetricsReader<'b> {
         let s = vec![0; db.metrics_count as usize];
+        let start_index = db.start_metric_index();
 
-        Ok(MetricsReader {
+        MetricsReader {
             _doc: doc,
             decoded_block: db,
             it_state: MetricState::Reference,
             sample: 0,
             scratch: s,
-        })
+            range: TimeRange::default(),
+            start_index,
+        }
+    }
+
+    /**
+     * Skip samples whose `start` date is outside the range
+     *
+     * The reference document is always returned first since it describes the schema, use
+     * `TimeRange::contains_doc` to check whether it is in the range.
+     */
+    pub fn with_time_range(mut self, range: TimeRange) -> MetricsReader<'a> {
+        self.range = range;
+        self
     }
 }
 
 /**
- * Compute the offset into an array for given (sample, metric) pair
+ * Compute the offset into an array for given (row, metric) pair, rows is the length of a column
  */
-fn get_array_offset(sample_count: i32, sample: i32, metric: i32) -> usize {
-    ((metric * sample_count) + sample) as usize
+fn get_array_offset(rows: usize, row: usize, metric: usize) -> usize {
+    (metric * rows) + row
 }
 
 impl<'a> Iterator for MetricsReader<'a> {
-    type Item = MetricsDocument;
+    type Item = (DateTime<Utc>, MetricsDocument);
 
-    fn next(&mut self) -> Option<MetricsDocument> {
+    fn next(&mut self) -> Option<(DateTime<Utc>, MetricsDocument)> {
         match self.it_state {
             MetricState::Reference => {
                 self.it_state = MetricState::Metrics;
 
-                Some(MetricsDocument::Reference(
-                    self.decoded_block.ref_doc.clone(),
+                Some((
+                    self.decoded_block.row_time_at(self.start_index, 0),
+                    MetricsDocument::Reference(self.decoded_block.ref_doc.clone()),
                 ))
             }
             MetricState::Metrics => {
-                if self.sample == self.decoded_block.sample_count {
-                    return None;
+                loop {
+                    if self.sample == self.decoded_block.sample_count {
+                        return None;
+                    }
+
+                    self.sample += 1;
+
+                    if self.decoded_block.sample_in_range(
+                        &self.range,
+                        self.start_index,
+                        self.sample - 1,
+                    ) {
+                        break;
+                    }
                 }
 
-                self.sample += 1;
-
                 for i in 0..self.decoded_block.metrics_count {
-                    self.scratch[i as usize] = self.decoded_block.raw_metrics
-                        [get_array_offset(self.decoded_block.sample_count, self.sample - 1, i)];
+                    self.scratch[i as usize] = self.decoded_block.sample_value(self.sample - 1, i);
                 }
 
-                let d = fill_document_raw(&self.decoded_block.ref_doc, &self.scratch);
-                Some(MetricsDocument::Metrics(d))
+                // Decoding already walked every element, stop at a block built by hand if not
+                let d = fill_document_raw(&self.decoded_block.ref_doc, &self.scratch).ok()?;
+                let t = self
+                    .decoded_block
+                    .row_time_at(self.start_index, self.sample as usize);
+                Some((t, MetricsDocument::Metrics(d)))
             }
         }
     }
 }
 
+/// Samples of a metrics block as metric arrays along with their time, see `DecodedMetricBlock::row_time`
 pub struct VectorMetricsReader<'a> {
     _doc: &'a RawDocument,
     pub decoded_block: DecodedMetricBlock,
@@ -320,20 +945,47 @@ pub struct VectorMetricsReader<'a> {
     it_state: MetricState,
     sample: i32,
     scratch: Vec<u64>,
+
+    range: TimeRange,
+    start_index: Option<usize>,
 }
 
 impl<'a> VectorMetricsReader<'a> {
-    pub fn new<'b>(doc: &'b RawDocument) -> Result<VectorMetricsReader<'b>> {
-        let db = decode_metric_block(doc)?;
+    pub fn new<'b>(doc: &'b RawDocument) -> Result<VectorMetricsReader<'b>, FtdcError> {
+        Ok(VectorMetricsReader::from_decoded(
+            doc,
+            decode_metric_block(doc)?,
+        ))
+    }
+
+    /// Iterate a block that was already decoded, such as by a `ParallelDecoder`
+    pub fn from_decoded<'b>(
+        doc: &'b RawDocument,
+        db: DecodedMetricBlock,
+    ) -> VectorMetricsReader<'b> {
         let s = vec![0; db.metrics_count as usize];
+        let start_index = db.start_metric_index();
 
-        Ok(VectorMetricsReader {
+        VectorMetricsReader {
             _doc: doc,
             decoded_block: db,
             it_state: MetricState::Reference,
             sample: 0,
             scratch: s,
-        })
+            range: TimeRange::default(),
+            start_index,
+        }
+    }
+
+    /**
+     * Skip samples whose `start` date is outside the range
+     *
+     * The reference document is always returned first since it describes the schema, use
+     * `TimeRange::contains_doc` to check whether it is in the range.
+     */
+    pub fn with_time_range(mut self, range: TimeRange) -> VectorMetricsReader<'a> {
+        self.range = range;
+        self
     }
 
     pub fn get_metrics_count(&self) -> usize {
@@ -342,30 +994,43 @@ impl<'a> VectorMetricsReader<'a> {
 }
 
 impl<'a> Iterator for VectorMetricsReader<'a> {
-    type Item = VectorMetricsDocument;
+    type Item = (DateTime<Utc>, VectorMetricsDocument);
 
-    fn next(&mut self) -> Option<VectorMetricsDocument> {
+    fn next(&mut self) -> Option<(DateTime<Utc>, VectorMetricsDocument)> {
         match self.it_state {
             MetricState::Reference => {
                 self.it_state = MetricState::Metrics;
 
-                Some(VectorMetricsDocument::Reference(
-                    self.decoded_block.ref_doc.clone(),
+                Some((
+                    self.decoded_block.row_time_at(self.start_index, 0),
+                    VectorMetricsDocument::Reference(self.decoded_block.ref_doc.clone()),
                 ))
             }
             MetricState::Metrics => {
-                if self.sample == self.decoded_block.sample_count {
-                    return None;
+                loop {
+                    if self.sample == self.decoded_block.sample_count {
+                        return None;
+                    }
+
+                    self.sample += 1;
+
+                    if self.decoded_block.sample_in_range(
+                        &self.range,
+                        self.start_index,
+                        self.sample - 1,
+                    ) {
+                        break;
+                    }
                 }
 
-                self.sample += 1;
-
                 for i in 0..self.decoded_block.metrics_count {
-                    self.scratch[i as usize] = self.decoded_block.raw_metrics
-                        [get_array_offset(self.decoded_block.sample_count, self.sample - 1, i)];
+                    self.scratch[i as usize] = self.decoded_block.sample_value(self.sample - 1, i);
                 }
 
-                Some(VectorMetricsDocument::Metrics(self.scratch.clone()))
+                let t = self
+                    .decoded_block
+                    .row_time_at(self.start_index, self.sample as usize);
+                Some((t, VectorMetricsDocument::Metrics(self.scratch.clone())))
             }
         }
     }
@@ -0,0 +1,354 @@
+// Copyright [2024] [Mark Benvenuto]
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::anyhow;
+use anyhow::Context;
+use anyhow::Result;
+use bson::Document;
+
+use crate::controller::Collector;
+
+/// Clock ticks per second of the times in /proc/stat, USER_HZ is 100 on every Linux platform
+const USER_HZ: i64 = 100;
+
+/// Columns of the aggregate cpu line of /proc/stat, in order
+const CPU_FIELDS: [&str; 10] = [
+    "user_ms",
+    "nice_ms",
+    "system_ms",
+    "idle_ms",
+    "iowait_ms",
+    "irq_ms",
+    "softirq_ms",
+    "steal_ms",
+    "guest_ms",
+    "guest_nice_ms",
+];
+
+/// Columns of /proc/diskstats after the device name, in order
+const DISK_FIELDS: [&str; 11] = [
+    "reads",
+    "reads_merged",
+    "read_sectors",
+    "read_time_ms",
+    "writes",
+    "writes_merged",
+    "write_sectors",
+    "write_time_ms",
+    "io_in_progress",
+    "io_time_ms",
+    "io_queued_ms",
+];
+
+/// Columns of /proc/net/dev after the interface name, in order
+const NET_FIELDS: [&str; 16] = [
+    "rx_bytes",
+    "rx_packets",
+    "rx_errs",
+    "rx_drop",
+    "rx_fifo",
+    "rx_frame",
+    "rx_compressed",
+    "rx_multicast",
+    "tx_bytes",
+    "tx_packets",
+    "tx_errs",
+    "tx_drop",
+    "tx_fifo",
+    "tx_colls",
+    "tx_carrier",
+    "tx_compressed",
+];
+
+/// Parses the contents of a /proc file into a section
+type SectionParser = fn(&str) -> Result<Document>;
+
+fn parse_i64(s: &str) -> Result<i64> {
+    s.parse::<i64>()
+        .with_context(|| format!("invalid number '{}'", s))
+}
+
+/**
+ * Parse /proc/stat into the `cpu` section of mongod's systemMetrics
+ *
+ * Times of the aggregate cpu line are converted from ticks to milliseconds, older kernels
+ * without the later columns just have fewer fields.
+ */
+pub fn parse_proc_stat(s: &str) -> Result<Document> {
+    let mut doc = Document::new();
+    let mut num_cpus = 0;
+
+    for line in s.lines() {
+        let mut fields = line.split_whitespace();
+        let Some(key) = fields.next() else {
+            continue;
+        };
+
+        match key {
+            "cpu" => {
+                for (name, ticks) in CPU_FIELDS.iter().zip(fields) {
+                    doc.insert(*name, parse_i64(ticks)? * 1000 / USER_HZ);
+                }
+            }
+            "ctxt" | "btime" | "processes" | "procs_running" | "procs_blocked" => {
+                if let Some(value) = fields.next() {
+                    doc.insert(key, parse_i64(value)?);
+                }
+            }
+            _ if key.starts_with("cpu") => num_cpus += 1,
+            _ => {}
+        }
+    }
+
+    doc.insert("num_cpus", num_cpus as i64);
+    Ok(doc)
+}
+
+/// Parse /proc/meminfo into the `memory` section, values in kB get a `_kb` suffix
+pub fn parse_meminfo(s: &str) -> Result<Document> {
+    let mut doc = Document::new();
+
+    for line in s.lines() {
+        let Some((key, value)) = line.split_once(':') else {
+            continue;
+        };
+
+        let mut fields = value.split_whitespace();
+        let Some(number) = fields.next() else {
+            continue;
+        };
+
+        match fields.next() {
+            Some("kB") => doc.insert(format!("{}_kb", key), parse_i64(number)?),
+            _ => doc.insert(key, parse_i64(number)?),
+        };
+    }
+
+    Ok(doc)
+}
+
+/// Parse /proc/diskstats into the `disks` section, loop and ram disks are skipped
+pub fn parse_diskstats(s: &str) -> Result<Document> {
+    let mut doc = Document::new();
+
+    for line in s.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 3 + DISK_FIELDS.len() {
+            continue;
+        }
+
+        let name = fields[2];
+        if name.starts_with("loop") || name.starts_with("ram") {
+            continue;
+        }
+
+        let mut disk = Document::new();
+        for (field, value) in DISK_FIELDS.iter().zip(&fields[3..]) {
+            disk.insert(*field, parse_i64(value)?);
+        }
+        doc.insert(name, disk);
+    }
+
+    Ok(doc)
+}
+
+/// Parse /proc/net/dev into the `network` section, one document per interface
+pub fn parse_net_dev(s: &str) -> Result<Document> {
+    let mut doc = Document::new();
+
+    // The first two lines are the column headers
+    for line in s.lines().skip(2) {
+        let Some((name, values)) = line.split_once(':') else {
+            continue;
+        };
+
+        let mut interface = Document::new();
+        for (field, value) in NET_FIELDS.iter().zip(values.split_whitespace()) {
+            interface.insert(*field, parse_i64(value)?);
+        }
+        doc.insert(name.trim(), interface);
+    }
+
+    Ok(doc)
+}
+
+/**
+ * Parse /proc/loadavg into the `loadavg` section
+ *
+ * FTDC stores doubles truncated to integers, so the load averages are kept in hundredths.
+ */
+pub fn parse_loadavg(s: &str) -> Result<Document> {
+    let fields: Vec<&str> = s.split_whitespace().collect();
+    let [load1, load5, load15, tasks, ..] = fields[..] else {
+        return Err(anyhow!("invalid loadavg '{}'", s.trim()));
+    };
+
+    let hundredths = |v: &str| -> Result<i64> {
+        let load = v
+            .parse::<f64>()
+            .with_context(|| format!("invalid load average '{}'", v))?;
+        Ok((load * 100.0).round() as i64)
+    };
+    let (runnable, total) = tasks
+        .split_once('/')
+        .ok_or_else(|| anyhow!("invalid task counts '{}'", tasks))?;
+
+    let mut doc = Document::new();
+    doc.insert("load1_x100", hundredths(load1)?);
+    doc.insert("load5_x100", hundredths(load5)?);
+    doc.insert("load15_x100", hundredths(load15)?);
+    doc.insert("runnable", parse_i64(runnable)?);
+    doc.insert("tasks", parse_i64(total)?);
+    Ok(doc)
+}
+
+/// Parse /proc/vmstat into the `vmstat` section
+pub fn parse_vmstat(s: &str) -> Result<Document> {
+    let mut doc = Document::new();
+
+    for line in s.lines() {
+        if let Some((key, value)) = line.split_once(' ') {
+            doc.insert(key, parse_i64(value.trim())?);
+        }
+    }
+
+    Ok(doc)
+}
+
+/// Contents of a file under the proc directory, None if this kernel or container does not have it
+fn read_proc_file(proc_dir: &Path, name: &str) -> Result<Option<String>> {
+    match fs::read_to_string(proc_dir.join(name)) {
+        Ok(s) => Ok(Some(s)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("reading {}", name)),
+    }
+}
+
+/**
+ * Collect Linux system metrics from /proc in the shape of mongod's `systemMetrics` section
+ *
+ * Sections are `cpu`, `memory`, `disks`, `network`, `loadavg` and `vmstat`, a section whose file
+ * does not exist is left out.
+ */
+pub struct SystemMetricsCollector {
+    proc_dir: PathBuf,
+}
+
+impl Default for SystemMetricsCollector {
+    fn default() -> Self {
+        SystemMetricsCollector::new()
+    }
+}
+
+impl SystemMetricsCollector {
+    pub fn new() -> SystemMetricsCollector {
+        SystemMetricsCollector::with_proc_dir(Path::new("/proc"))
+    }
+
+    /// Read the files from a directory laid out like /proc
+    pub fn with_proc_dir(proc_dir: &Path) -> SystemMetricsCollector {
+        SystemMetricsCollector {
+            proc_dir: proc_dir.to_path_buf(),
+        }
+    }
+}
+
+impl Collector for SystemMetricsCollector {
+    fn name(&self) -> &str {
+        "systemMetrics"
+    }
+
+    fn collect(&mut self) -> Result<Document> {
+        let sections: [(&str, &str, SectionParser); 6] = [
+            ("cpu", "stat", parse_proc_stat),
+            ("memory", "meminfo", parse_meminfo),
+            ("disks", "diskstats", parse_diskstats),
+            ("network", "net/dev", parse_net_dev),
+            ("loadavg", "loadavg", parse_loadavg),
+            ("vmstat", "vmstat", parse_vmstat),
+        ];
+
+        let mut doc = Document::new();
+        for (section, file, parse) in sections {
+            if let Some(s) = read_proc_file(&self.proc_dir, file)? {
+                doc.insert(
+                    section,
+                    parse(&s).with_context(|| format!("parsing {}", file))?,
+                );
+            }
+        }
+
+        Ok(doc)
+    }
+}
+
+/// Host name, kernel and memory size for the metadata document, like mongod's `hostInfo`
+pub struct HostInfoCollector {
+    proc_dir: PathBuf,
+}
+
+impl Default for HostInfoCollector {
+    fn default() -> Self {
+        HostInfoCollector::new()
+    }
+}
+
+impl HostInfoCollector {
+    pub fn new() -> HostInfoCollector {
+        HostInfoCollector::with_proc_dir(Path::new("/proc"))
+    }
+
+    /// Read the files from a directory laid out like /proc
+    pub fn with_proc_dir(proc_dir: &Path) -> HostInfoCollector {
+        HostInfoCollector {
+            proc_dir: proc_dir.to_path_buf(),
+        }
+    }
+}
+
+impl Collector for HostInfoCollector {
+    fn name(&self) -> &str {
+        "hostInfo"
+    }
+
+    fn collect(&mut self) -> Result<Document> {
+        let mut doc = Document::new();
+
+        for (key, file) in [
+            ("hostname", "sys/kernel/hostname"),
+            ("os_type", "sys/kernel/ostype"),
+            ("kernel_version", "sys/kernel/osrelease"),
+        ] {
+            if let Some(s) = read_proc_file(&self.proc_dir, file)? {
+                doc.insert(key, s.trim());
+            }
+        }
+
+        if let Some(s) = read_proc_file(&self.proc_dir, "stat")? {
+            doc.insert("num_cpus", parse_proc_stat(&s)?.get_i64("num_cpus")?);
+        }
+        if let Some(s) = read_proc_file(&self.proc_dir, "meminfo")? {
+            if let Ok(total) = parse_meminfo(&s)?.get_i64("MemTotal_kb") {
+                doc.insert("mem_total_kb", total);
+            }
+        }
+
+        Ok(doc)
+    }
+}
@@ -0,0 +1,231 @@
+// Copyright [2024] [Mark Benvenuto]
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::VecDeque;
+use std::str::FromStr;
+
+use anyhow::anyhow;
+use anyhow::Result;
+use bson::RawDocument;
+use chrono::DateTime;
+use chrono::Duration;
+use chrono::Utc;
+
+use crate::error::FtdcError;
+use crate::reader::AsRawBlock;
+use crate::reader::RawBSONBlock;
+
+/// Inclusive window of time, either end may be open
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TimeRange {
+    pub start: Option<DateTime<Utc>>,
+    pub end: Option<DateTime<Utc>>,
+}
+
+impl TimeRange {
+    pub fn new(start: Option<DateTime<Utc>>, end: Option<DateTime<Utc>>) -> TimeRange {
+        TimeRange { start, end }
+    }
+
+    pub fn is_unbounded(&self) -> bool {
+        self.start.is_none() && self.end.is_none()
+    }
+
+    pub fn contains(&self, t: DateTime<Utc>) -> bool {
+        self.start.is_none_or(|s| t >= s) && self.end.is_none_or(|e| t <= e)
+    }
+
+    /// Check the `start` date of a sample document, documents without one are always included
+    pub fn contains_doc(&self, doc: &RawDocument) -> bool {
+        match doc.get_datetime("start") {
+            Ok(t) => self.contains(t.to_chrono()),
+            Err(_) => true,
+        }
+    }
+
+    pub fn contains_millis(&self, millis: i64) -> bool {
+        match DateTime::from_timestamp_millis(millis) {
+            Some(t) => self.contains(t),
+            None => false,
+        }
+    }
+
+    /**
+     * Could a span that starts at `first` and ends before `next` have samples in the window
+     *
+     * `next` is None when the end of the span is not known.
+     */
+    pub fn overlaps(&self, first: DateTime<Utc>, next: Option<DateTime<Utc>>) -> bool {
+        if self.end.is_some_and(|e| first > e) {
+            return false;
+        }
+
+        !matches!((self.start, next), (Some(s), Some(n)) if n <= s)
+    }
+}
+
+/**
+ * A point in time given on the command line, either RFC 3339 or relative to the end of the data
+ * such as `-15m`
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimeSpec {
+    Absolute(DateTime<Utc>),
+    FromEnd(Duration),
+}
+
+impl TimeSpec {
+    pub fn resolve(&self, end_of_data: DateTime<Utc>) -> DateTime<Utc> {
+        match self {
+            TimeSpec::Absolute(t) => *t,
+            TimeSpec::FromEnd(d) => end_of_data - *d,
+        }
+    }
+
+    pub fn is_relative(&self) -> bool {
+        matches!(self, TimeSpec::FromEnd(_))
+    }
+}
+
+impl FromStr for TimeSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<TimeSpec> {
+        if let Some(rel) = s.strip_prefix('-') {
+            let split = rel
+                .find(|c: char| !c.is_ascii_digit())
+                .ok_or_else(|| anyhow!("missing unit in relative time '{}'", s))?;
+            let (count, unit) = rel.split_at(split);
+            let count: i64 = count.parse()?;
+
+            let d = match unit {
+                "s" => Duration::seconds(count),
+                "m" => Duration::minutes(count),
+                "h" => Duration::hours(count),
+                "d" => Duration::days(count),
+                _ => return Err(anyhow!("unknown unit '{}' in relative time '{}'", unit, s)),
+            };
+
+            return Ok(TimeSpec::FromEnd(d));
+        }
+
+        Ok(TimeSpec::Absolute(
+            DateTime::parse_from_rfc3339(s)?.with_timezone(&Utc),
+        ))
+    }
+}
+
+/// The `_id` date of a block, the time of the first sample for metrics blocks
+pub fn block_date(block: &RawBSONBlock) -> Option<DateTime<Utc>> {
+    let doc = match block {
+        RawBSONBlock::Metadata(doc)
+        | RawBSONBlock::Metrics(doc)
+        | RawBSONBlock::PeriodicMetadata(doc) => doc,
+    };
+
+    doc.get_datetime("_id").ok().map(|d| d.to_chrono())
+}
+
+/**
+ * Skip blocks that cannot have samples in a time range without inflating them
+ *
+ * A metrics block covers the time from its `_id` up to the `_id` of the next metrics block so the
+ * filter reads one metrics block ahead. Metadata blocks up to the end of the range are always
+ * returned so callers can rebuild the metadata in effect at the start of the window. Samples at
+ * the edges of the window are trimmed by the metrics readers, see `MetricsReader::with_time_range`.
+ */
+pub struct TimeFilter<I: Iterator> {
+    inner: I,
+    range: TimeRange,
+    queue: VecDeque<I::Item>,
+    inner_done: bool,
+}
+
+impl<I, T> TimeFilter<I>
+where
+    I: Iterator<Item = Result<T, FtdcError>>,
+    T: AsRawBlock,
+{
+    pub fn new(inner: I, range: TimeRange) -> TimeFilter<I> {
+        TimeFilter {
+            inner,
+            range,
+            queue: VecDeque::new(),
+            inner_done: false,
+        }
+    }
+
+    /// Date of the first metrics block after the front of the queue, reading ahead as needed
+    fn next_metrics_date(&mut self) -> Option<DateTime<Utc>> {
+        let mut idx = 1;
+        loop {
+            while self.queue.len() <= idx {
+                if self.inner_done {
+                    return None;
+                }
+                match self.inner.next() {
+                    Some(item) => self.queue.push_back(item),
+                    None => {
+                        self.inner_done = true;
+                        return None;
+                    }
+                }
+            }
+
+            if let Ok(b) = &self.queue[idx] {
+                if let RawBSONBlock::Metrics(_) = b.raw_block() {
+                    return block_date(b.raw_block());
+                }
+            }
+            idx += 1;
+        }
+    }
+}
+
+impl<I, T> Iterator for TimeFilter<I>
+where
+    I: Iterator<Item = Result<T, FtdcError>>,
+    T: AsRawBlock,
+{
+    type Item = Result<T, FtdcError>;
+
+    fn next(&mut self) -> Option<Result<T, FtdcError>> {
+        loop {
+            if self.queue.is_empty() {
+                self.queue.push_back(self.inner.next()?);
+            }
+
+            let keep = match self.queue.front()? {
+                Err(_) => true,
+                Ok(b) => match (b.raw_block(), block_date(b.raw_block())) {
+                    (_, None) => true,
+                    (RawBSONBlock::Metrics(_), Some(first)) => {
+                        // Only the start of the range needs to know where the block ends
+                        let next = match self.range.start {
+                            Some(_) => self.next_metrics_date(),
+                            None => None,
+                        };
+                        self.range.overlaps(first, next)
+                    }
+                    (_, Some(date)) => self.range.end.is_none_or(|e| date <= e),
+                },
+            };
+
+            let item = self.queue.pop_front()?;
+            if keep {
+                return Some(item);
+            }
+        }
+    }
+}
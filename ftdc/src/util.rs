@@ -1,3 +1,6 @@
+use std::collections::HashMap;
+use std::fmt;
+
 use bson::doc;
 use bson::spec::BinarySubtype;
 use bson::Binary;
@@ -11,8 +14,11 @@ use bson::RawDocumentBuf;
 use bson::RawJavaScriptCodeWithScope;
 use bson::Regex;
 use chrono::DateTime;
+use chrono::SecondsFormat;
 use chrono::Utc;
 
+use crate::metric_path::MetricPath;
+
 pub(crate) fn gen_metadata_document(doc: &Document, date: DateTime<Utc>) -> Document {
     doc! {
         "_id" : date,
@@ -21,6 +27,14 @@ pub(crate) fn gen_metadata_document(doc: &Document, date: DateTime<Utc>) -> Docu
     }
 }
 
+pub(crate) fn gen_periodic_metadata_document(delta: &Document, date: DateTime<Utc>) -> Document {
+    doc! {
+        "_id" : date,
+        "type": 2,
+        "doc" : delta
+    }
+}
+
 pub(crate) fn gen_metrics_document(chunk: &[u8], date: DateTime<Utc>) -> Document {
     doc! {
         "_id" : date,
@@ -29,10 +43,77 @@ pub(crate) fn gen_metrics_document(chunk: &[u8], date: DateTime<Utc>) -> Documen
     }
 }
 
+/// Exponent bias of an IEEE 754-2008 decimal128
+const DECIMAL128_EXPONENT_BIAS: i32 = 6176;
+
+/// Largest coefficient of a canonical decimal128, 10^34 - 1
+const DECIMAL128_MAX_COEFFICIENT: u128 = 9_999_999_999_999_999_999_999_999_999_999_999;
+
+/**
+ * Convert a Decimal128 metric to an integer the same way mongod does, by truncating toward zero
+ *
+ * Values outside the range of an i64 saturate, NaN and non-canonical encodings are 0.
+ */
+pub fn decimal128_to_i64(d: bson::Decimal128) -> i64 {
+    let bits = u128::from_le_bytes(d.bytes());
+    let negative = bits >> 127 == 1;
+
+    // Infinity and NaN use the 11 combination prefix followed by 11
+    if (bits >> 123) & 0xf == 0xf {
+        return match (bits >> 122) & 0x1f {
+            0x1e if negative => i64::MIN,
+            0x1e => i64::MAX,
+            _ => 0,
+        };
+    }
+
+    // The other 11 prefix form always has a coefficient above 10^34 - 1 so it is non-canonical
+    if (bits >> 125) & 0x3 == 0x3 {
+        return 0;
+    }
+
+    let exponent = ((bits >> 113) & 0x3fff) as i32 - DECIMAL128_EXPONENT_BIAS;
+    let mut coefficient = bits & ((1u128 << 113) - 1);
+    if coefficient > DECIMAL128_MAX_COEFFICIENT {
+        return 0;
+    }
+
+    // Scale to an integer, stopping once the value cannot fit an i64 or has no digits left
+    for _ in 0..exponent.unsigned_abs() {
+        if exponent > 0 {
+            if coefficient == 0 || coefficient > i64::MAX as u128 + 1 {
+                break;
+            }
+            coefficient *= 10;
+        } else {
+            if coefficient == 0 {
+                break;
+            }
+            coefficient /= 10;
+        }
+    }
+
+    match (negative, i64::try_from(coefficient)) {
+        (false, Ok(v)) => v,
+        (true, Ok(v)) => -v,
+        (false, Err(_)) => i64::MAX,
+        (true, Err(_)) => i64::MIN,
+    }
+}
+
+/// Decimal128 with an exponent of 0 holding an integer, the inverse of `decimal128_to_i64`
+pub fn i64_to_decimal128(v: i64) -> bson::Decimal128 {
+    let sign = if v < 0 { 1u128 << 127 } else { 0 };
+    let bits = sign | ((DECIMAL128_EXPONENT_BIAS as u128) << 113) | v.unsigned_abs() as u128;
+
+    bson::Decimal128::from_bytes(bits.to_le_bytes())
+}
+
 fn extract_metrics_bson_int(value: &Bson, metrics: &mut Vec<u64>) {
     match value {
         &Bson::Double(f) => {
-            metrics.push(f as u64);
+            // Doubles are stored as int64 the same as mongod, going through u64 would lose the sign
+            metrics.push(f as i64 as u64);
         }
         &Bson::Int64(f) => {
             metrics.push(f as u64);
@@ -40,8 +121,8 @@ fn extract_metrics_bson_int(value: &Bson, metrics: &mut Vec<u64>) {
         &Bson::Int32(f) => {
             metrics.push(f as u64);
         }
-        &Bson::Decimal128(_) => {
-            panic!("Decimal128 not implemented")
+        &Bson::Decimal128(f) => {
+            metrics.push(decimal128_to_i64(f) as u64);
         }
         &Bson::Boolean(f) => {
             metrics.push(f as u64);
@@ -86,10 +167,136 @@ pub fn extract_metrics(doc: &Document) -> Vec<u64> {
     metrics
 }
 
-fn extract_metrics_bson_raw_int(value: &RawBsonRef, metrics: &mut Vec<u64>) {
+fn same_schema_bson_int(reference: &Bson, value: &Bson) -> bool {
+    if reference.element_type() != value.element_type() {
+        return false;
+    }
+
+    match (reference, value) {
+        (Bson::Document(r), Bson::Document(v)) => same_schema(r, v),
+        (Bson::Array(r), Bson::Array(v)) => {
+            r.len() == v.len()
+                && r.iter()
+                    .zip(v.iter())
+                    .all(|(r, v)| same_schema_bson_int(r, v))
+        }
+        (
+            Bson::Double(_)
+            | Bson::Int64(_)
+            | Bson::Int32(_)
+            | Bson::Decimal128(_)
+            | Bson::Boolean(_)
+            | Bson::DateTime(_)
+            | Bson::Timestamp(_),
+            _,
+        ) => true,
+        // Readers take everything that is not a metric from the reference document
+        _ => reference == value,
+    }
+}
+
+/**
+ * True if `doc` can be delta encoded against `reference`
+ *
+ * Like mongod, the field names, their order and the exact types have to match, an Int32 that
+ * becomes a Double is a new schema. Fields that are not metrics, like strings, must also have the
+ * same value since they are only stored in the reference document.
+ */
+pub fn same_schema(reference: &Document, doc: &Document) -> bool {
+    reference.len() == doc.len()
+        && reference
+            .iter()
+            .zip(doc.iter())
+            .all(|((rk, rv), (k, v))| rk == k && same_schema_bson_int(rv, v))
+}
+
+/// Compare one element against the reference, appending its metrics to `metrics` if given
+fn match_schema_bson_raw_int(
+    reference: &RawBsonRef,
+    value: &RawBsonRef,
+    metrics: Option<&mut Vec<u64>>,
+) -> bool {
+    if reference.element_type() != value.element_type() {
+        return false;
+    }
+
+    match (reference, value) {
+        (RawBsonRef::Document(r), RawBsonRef::Document(v)) => match_schema_raw_int(r, v, metrics),
+        (RawBsonRef::Array(r), RawBsonRef::Array(v)) => {
+            let mut metrics = metrics;
+            let mut r = r.into_iter();
+            let mut v = v.into_iter();
+            loop {
+                match (r.next(), v.next()) {
+                    (None, None) => return true,
+                    (Some(Ok(r)), Some(Ok(v)))
+                        if match_schema_bson_raw_int(&r, &v, metrics.as_deref_mut()) => {}
+                    _ => return false,
+                }
+            }
+        }
+        (
+            RawBsonRef::Double(_)
+            | RawBsonRef::Int64(_)
+            | RawBsonRef::Int32(_)
+            | RawBsonRef::Decimal128(_)
+            | RawBsonRef::Boolean(_)
+            | RawBsonRef::DateTime(_)
+            | RawBsonRef::Timestamp(_),
+            _,
+        ) => {
+            // Scalars are read by the iterator, extracting them cannot fail
+            match metrics {
+                Some(metrics) => extract_metrics_bson_raw_int(value, metrics).is_ok(),
+                None => true,
+            }
+        }
+        _ => reference == value,
+    }
+}
+
+fn match_schema_raw_int(
+    reference: &RawDocument,
+    doc: &RawDocument,
+    mut metrics: Option<&mut Vec<u64>>,
+) -> bool {
+    let mut r = reference.iter();
+    let mut d = doc.iter();
+    loop {
+        match (r.next(), d.next()) {
+            (None, None) => return true,
+            (Some(Ok((rk, rv))), Some(Ok((k, v))))
+                if rk == k && match_schema_bson_raw_int(&rv, &v, metrics.as_deref_mut()) => {}
+            _ => return false,
+        }
+    }
+}
+
+/// `same_schema` for raw documents, a malformed document never matches
+pub fn same_schema_raw(reference: &RawDocument, doc: &RawDocument) -> bool {
+    match_schema_raw_int(reference, doc, None)
+}
+
+/**
+ * `same_schema_raw` that also appends the metrics of `doc` to `metrics` in the same pass
+ *
+ * When the schemas differ `metrics` holds whatever was extracted before the difference.
+ */
+pub(crate) fn extract_metrics_same_schema_raw(
+    reference: &RawDocument,
+    doc: &RawDocument,
+    metrics: &mut Vec<u64>,
+) -> bool {
+    match_schema_raw_int(reference, doc, Some(metrics))
+}
+
+fn extract_metrics_bson_raw_int(
+    value: &RawBsonRef,
+    metrics: &mut Vec<u64>,
+) -> Result<(), bson::raw::Error> {
     match value {
         &RawBsonRef::Double(f) => {
-            metrics.push(f as u64);
+            metrics.push(f as i64 as u64);
         }
         &RawBsonRef::Int64(f) => {
             metrics.push(f as u64);
@@ -97,8 +304,8 @@ fn extract_metrics_bson_raw_int(value: &RawBsonRef, metrics: &mut Vec<u64>) {
         &RawBsonRef::Int32(f) => {
             metrics.push(f as u64);
         }
-        &RawBsonRef::Decimal128(_) => {
-            panic!("Decimal128 not implemented")
+        &RawBsonRef::Decimal128(f) => {
+            metrics.push(decimal128_to_i64(f) as u64);
         }
         &RawBsonRef::Boolean(f) => {
             metrics.push(f as u64);
@@ -111,11 +318,11 @@ fn extract_metrics_bson_raw_int(value: &RawBsonRef, metrics: &mut Vec<u64>) {
             metrics.push(f.increment as u64);
         }
         RawBsonRef::Document(o) => {
-            extract_metrics_raw_int(o, metrics);
+            extract_metrics_raw_int(o, metrics)?;
         }
         RawBsonRef::Array(a) => {
             for b in *a {
-                extract_metrics_bson_raw_int(&b.expect("valid array element"), metrics);
+                extract_metrics_bson_raw_int(&b?, metrics)?;
             }
         }
 
@@ -130,35 +337,108 @@ fn extract_metrics_bson_raw_int(value: &RawBsonRef, metrics: &mut Vec<u64>) {
         | &RawBsonRef::Symbol(_)
         | &RawBsonRef::RegularExpression(_) => {}
     }
+
+    Ok(())
 }
 
-fn extract_metrics_raw_int(doc: &RawDocument, metrics: &mut Vec<u64>) {
+fn extract_metrics_raw_int(
+    doc: &RawDocument,
+    metrics: &mut Vec<u64>,
+) -> Result<(), bson::raw::Error> {
     for item in doc.iter() {
-        let value = item.expect("Valid raw bson").1;
-        extract_metrics_bson_raw_int(&value, metrics);
+        let value = item?.1;
+        extract_metrics_bson_raw_int(&value, metrics)?;
     }
+
+    Ok(())
 }
 
-pub fn extract_metrics_raw(doc: &RawDocument) -> Vec<u64> {
+/// Metrics of a raw document, an error if an element of it is malformed
+pub fn extract_metrics_raw(doc: &RawDocument) -> Result<Vec<u64>, bson::raw::Error> {
     let mut metrics: Vec<u64> = Vec::new();
-    extract_metrics_raw_int(doc, &mut metrics);
-    metrics
+    extract_metrics_raw_int(doc, &mut metrics)?;
+    Ok(metrics)
 }
 
-fn concat2(a1: &str, a2: &str) -> String {
-    let mut s = a1.to_string();
-    s.push_str(a2);
-    s
+/// `extract_metrics_raw` appending to a buffer the caller reuses
+pub(crate) fn extract_metrics_raw_into(
+    doc: &RawDocument,
+    metrics: &mut Vec<u64>,
+) -> Result<(), bson::raw::Error> {
+    extract_metrics_raw_int(doc, metrics)
+}
+
+/**
+ * Find the index in the metrics array of a top level date field, used to locate the per sample
+ * `start` time
+ */
+pub fn find_top_level_date_index_raw(doc: &RawDocument, key: &str) -> Option<usize> {
+    let mut metrics: Vec<u64> = Vec::new();
+
+    for item in doc.iter() {
+        let (name, value) = item.ok()?;
+
+        if name == key {
+            return match value {
+                RawBsonRef::DateTime(_) => Some(metrics.len()),
+                _ => None,
+            };
+        }
+
+        extract_metrics_bson_raw_int(&value, &mut metrics).ok()?;
+    }
+
+    None
 }
 
-fn concat3(a1: &str, a2: &str, a3: &str) -> String {
-    let mut s = a1.to_string();
-    s.push_str(a2);
-    s.push('.');
-    s.push_str(a3);
-    s
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+fn fnv1a(mut hash: u64, bytes: &[u8]) -> u64 {
+    for b in bytes {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+    hash
 }
 
+fn schema_fingerprint_int(doc: &RawDocument, mut hash: u64) -> u64 {
+    for item in doc.iter() {
+        let Ok((name, value)) = item else {
+            return fnv1a(hash, &[0xff]);
+        };
+
+        hash = fnv1a(hash, &[value.element_type() as u8]);
+        hash = fnv1a(hash, name.as_bytes());
+        hash = fnv1a(hash, &[0]);
+
+        match value {
+            RawBsonRef::Document(d) => hash = schema_fingerprint_int(d, hash),
+            RawBsonRef::Array(a) => match RawDocument::from_bytes(a.as_bytes()) {
+                Ok(d) => hash = schema_fingerprint_int(d, hash),
+                Err(_) => return fnv1a(hash, &[0xff]),
+            },
+            _ => {}
+        }
+
+        // Mark the end of nested documents so {a: {b}, c} and {a: {b, c}} differ
+        hash = fnv1a(hash, &[0]);
+    }
+
+    hash
+}
+
+/**
+ * Hash of the field names and types of a document, but not their values
+ *
+ * Two reference documents with the same fingerprint have the same schema. The hash is FNV-1a so
+ * it is stable across runs and can be stored on disk.
+ */
+pub fn schema_fingerprint(doc: &RawDocument) -> u64 {
+    schema_fingerprint_int(doc, FNV_OFFSET_BASIS)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum MetricType {
     Double,
     Int64,
@@ -166,80 +446,174 @@ pub enum MetricType {
     Boolean,
     DateTime,
     Timestamp,
+    Decimal128,
+}
+
+/**
+ * A metric value converted back from its stored integer according to its `MetricType`
+ *
+ * FTDC stores every metric as a 64 bit integer, doubles and decimals are truncated so `F64`
+ * never has a fractional part.
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MetricValue {
+    /// Int32, Int64 and Decimal128
+    I64(i64),
+    F64(f64),
+    Bool(bool),
+    DateTime(DateTime<Utc>),
+    /// One half of a BSON Timestamp, the `t` seconds or the `i` increment
+    Timestamp(u32),
+}
+
+impl MetricValue {
+    pub fn from_raw(metric_type: MetricType, raw: u64) -> MetricValue {
+        match metric_type {
+            MetricType::Int64 | MetricType::Int32 | MetricType::Decimal128 => {
+                MetricValue::I64(raw as i64)
+            }
+            MetricType::Double => MetricValue::F64(raw as i64 as f64),
+            MetricType::Boolean => MetricValue::Bool(raw != 0),
+            MetricType::DateTime => {
+                let millis = raw as i64;
+                // chrono covers a smaller range than BSON dates, clamp rather than fail
+                MetricValue::DateTime(DateTime::from_timestamp_millis(millis).unwrap_or(
+                    if millis < 0 {
+                        DateTime::<Utc>::MIN_UTC
+                    } else {
+                        DateTime::<Utc>::MAX_UTC
+                    },
+                ))
+            }
+            MetricType::Timestamp => MetricValue::Timestamp(raw as u32),
+        }
+    }
+
+    /// Numeric value, booleans are 0 or 1 and dates are milliseconds since the epoch
+    pub fn as_i64(&self) -> i64 {
+        match *self {
+            MetricValue::I64(v) => v,
+            MetricValue::F64(v) => v as i64,
+            MetricValue::Bool(v) => v as i64,
+            MetricValue::DateTime(v) => v.timestamp_millis(),
+            MetricValue::Timestamp(v) => v as i64,
+        }
+    }
+
+    /// Numeric value as a double, see `as_i64`
+    pub fn as_f64(&self) -> f64 {
+        match *self {
+            MetricValue::F64(v) => v,
+            _ => self.as_i64() as f64,
+        }
+    }
+}
+
+impl fmt::Display for MetricValue {
+    /// Signed numbers, `true`/`false` and RFC 3339 dates in UTC with milliseconds
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MetricValue::I64(v) => write!(f, "{}", v),
+            MetricValue::F64(v) => write!(f, "{}", v),
+            MetricValue::Bool(v) => write!(f, "{}", v),
+            MetricValue::DateTime(v) => {
+                write!(f, "{}", v.to_rfc3339_opts(SecondsFormat::Millis, true))
+            }
+            MetricValue::Timestamp(v) => write!(f, "{}", v),
+        }
+    }
+}
+
+/// Convert a row of stored metrics to typed values, `metrics` and `values` are in the same order
+pub fn typed_metrics(metrics: &[MetricTypeInfo], values: &[u64]) -> Vec<MetricValue> {
+    metrics
+        .iter()
+        .zip(values)
+        .map(|(m, &v)| MetricValue::from_raw(m.metric_type, v))
+        .collect()
 }
 
+/**
+ * Where a metric comes from in a reference document
+ *
+ * A metric is identified by its position, the same index its value has in the metrics array.
+ * Documents may repeat a key, so the path alone is only unique together with `occurrence`.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct MetricTypeInfo {
-    pub name: String,
+    /// Keys and array indexes from the top of the document down to the metric, the parts of a
+    /// Timestamp are the keys `t` and `i`
+    pub path: MetricPath,
     pub metric_type: MetricType,
+
+    /// Position of the metric in the metrics array
+    pub index: usize,
+
+    /// Number of earlier metrics in the document with the same path, only non zero when a
+    /// document repeats a key
+    pub occurrence: usize,
+}
+
+impl MetricTypeInfo {
+    /// Text form of `path` with a `#<occurrence>` suffix on repeated keys so it is unique within
+    /// a document, `MetricPath::parse_with_occurrence` reverses it
+    pub fn unique_name(&self) -> String {
+        match self.occurrence {
+            0 => self.path.to_string(),
+            n => format!("{}#{}", self.path, n),
+        }
+    }
+}
+
+fn push_metric_path(path: &MetricPath, metric_type: MetricType, metrics: &mut Vec<MetricTypeInfo>) {
+    metrics.push(MetricTypeInfo {
+        path: path.clone(),
+        metric_type,
+        index: metrics.len(),
+        occurrence: 0,
+    });
+}
+
+fn push_timestamp_paths(path: &mut MetricPath, metrics: &mut Vec<MetricTypeInfo>) {
+    for part in ["t", "i"] {
+        path.push_key(part);
+        push_metric_path(path, MetricType::Timestamp, metrics);
+        path.pop();
+    }
+}
+
+/// Number repeated paths in the order they appear
+fn number_duplicate_paths(metrics: &mut [MetricTypeInfo]) {
+    let mut seen: HashMap<MetricPath, usize> = HashMap::new();
+
+    for m in metrics.iter_mut() {
+        let count = seen.entry(m.path.clone()).or_insert(0);
+        m.occurrence = *count;
+        *count += 1;
+    }
 }
 
 fn extract_metrics_paths_bson_int(
-    value: &(&String, &Bson),
-    prefix: &str,
+    value: &Bson,
+    path: &mut MetricPath,
     metrics: &mut Vec<MetricTypeInfo>,
 ) {
-    let prefix_dot_str = prefix.to_string() + ".";
-    let prefix_dot = prefix_dot_str.as_str();
-    let name = &value.0;
-    match value.1 {
-        &Bson::Double(_) => {
-            let a1 = concat2(prefix_dot, name.as_str());
-            metrics.push(MetricTypeInfo {
-                name: a1,
-                metric_type: MetricType::Double,
-            });
-        }
-        &Bson::Int64(_) => {
-            let a1 = concat2(prefix_dot, name.as_str());
-            metrics.push(MetricTypeInfo {
-                name: a1,
-                metric_type: MetricType::Int64,
-            });
-        }
-        &Bson::Int32(_) => {
-            let a1 = concat2(prefix_dot, name.as_str());
-            metrics.push(MetricTypeInfo {
-                name: a1,
-                metric_type: MetricType::Int32,
-            });
-        }
-        &Bson::Boolean(_) => {
-            let a1 = concat2(prefix_dot, name.as_str());
-            metrics.push(MetricTypeInfo {
-                name: a1,
-                metric_type: MetricType::Boolean,
-            });
-        }
-        &Bson::DateTime(_) => {
-            let a1 = concat2(prefix_dot, name.as_str());
-            metrics.push(MetricTypeInfo {
-                name: a1,
-                metric_type: MetricType::DateTime,
-            });
-        }
-        &Bson::Decimal128(_) => {
-            panic!("Decimal128 not implemented")
-        }
-        &Bson::Timestamp(_) => {
-            metrics.push(MetricTypeInfo {
-                name: concat3(prefix_dot, name.as_str(), "t"),
-                metric_type: MetricType::Timestamp,
-            });
-            metrics.push(MetricTypeInfo {
-                name: concat3(prefix_dot, name.as_str(), "i"),
-                metric_type: MetricType::Timestamp,
-            });
-        }
+    match value {
+        &Bson::Double(_) => push_metric_path(path, MetricType::Double, metrics),
+        &Bson::Int64(_) => push_metric_path(path, MetricType::Int64, metrics),
+        &Bson::Int32(_) => push_metric_path(path, MetricType::Int32, metrics),
+        &Bson::Boolean(_) => push_metric_path(path, MetricType::Boolean, metrics),
+        &Bson::DateTime(_) => push_metric_path(path, MetricType::DateTime, metrics),
+        &Bson::Decimal128(_) => push_metric_path(path, MetricType::Decimal128, metrics),
+        &Bson::Timestamp(_) => push_timestamp_paths(path, metrics),
         Bson::Document(o) => {
-            extract_metrics_paths_int(o, concat2(prefix_dot, name.as_str()).as_str(), metrics);
+            extract_metrics_paths_int(o, path, metrics);
         }
         Bson::Array(a) => {
-            for b in a.iter().enumerate() {
-                extract_metrics_paths_bson_int(
-                    &(*name, b.1),
-                    concat3(prefix_dot, name.as_str(), &b.0.to_string()).as_str(),
-                    metrics,
-                );
+            for (idx, b) in a.iter().enumerate() {
+                path.push_index(idx);
+                extract_metrics_paths_bson_int(b, path, metrics);
+                path.pop();
             }
         }
 
@@ -254,90 +628,46 @@ fn extract_metrics_paths_bson_int(
     }
 }
 
-fn extract_metrics_paths_int(doc: &Document, prefix: &str, metrics: &mut Vec<MetricTypeInfo>) {
-    for item in doc {
-        // if item.0 == "/boot/efi" {
-        //     println!("MCB")
-        // }
-        extract_metrics_paths_bson_int(&item, prefix, metrics);
+fn extract_metrics_paths_int(
+    doc: &Document,
+    path: &mut MetricPath,
+    metrics: &mut Vec<MetricTypeInfo>,
+) {
+    for (key, value) in doc {
+        path.push_key(key.clone());
+        extract_metrics_paths_bson_int(value, path, metrics);
+        path.pop();
     }
 }
 
 pub fn extract_metrics_paths(doc: &Document) -> Vec<MetricTypeInfo> {
     let mut metrics: Vec<MetricTypeInfo> = Vec::new();
-    extract_metrics_paths_int(doc, "", &mut metrics);
+    extract_metrics_paths_int(doc, &mut MetricPath::new(), &mut metrics);
+    number_duplicate_paths(&mut metrics);
     metrics
 }
 
 fn extract_metrics_paths_bson_raw_int(
-    value: (&str, RawBsonRef),
-    prefix: &str,
+    value: RawBsonRef,
+    path: &mut MetricPath,
     metrics: &mut Vec<MetricTypeInfo>,
-) {
-    let prefix_dot_str = prefix.to_string() + ".";
-    let prefix_dot = prefix_dot_str.as_str();
-    let name = &value.0;
-    match value.1 {
-        RawBsonRef::Double(_) => {
-            let a1 = concat2(prefix_dot, name);
-            metrics.push(MetricTypeInfo {
-                name: a1,
-                metric_type: MetricType::Double,
-            });
-        }
-        RawBsonRef::Int64(_) => {
-            let a1 = concat2(prefix_dot, name);
-            metrics.push(MetricTypeInfo {
-                name: a1,
-                metric_type: MetricType::Int64,
-            });
-        }
-        RawBsonRef::Int32(_) => {
-            let a1 = concat2(prefix_dot, name);
-            metrics.push(MetricTypeInfo {
-                name: a1,
-                metric_type: MetricType::Int32,
-            });
-        }
-        RawBsonRef::Boolean(_) => {
-            let a1 = concat2(prefix_dot, name);
-            metrics.push(MetricTypeInfo {
-                name: a1,
-                metric_type: MetricType::Boolean,
-            });
-        }
-        RawBsonRef::DateTime(_) => {
-            let a1 = concat2(prefix_dot, name);
-            metrics.push(MetricTypeInfo {
-                name: a1,
-                metric_type: MetricType::DateTime,
-            });
-        }
-        RawBsonRef::Decimal128(_) => {
-            panic!("Decimal128 not implemented")
-        }
-        RawBsonRef::Timestamp(_) => {
-            metrics.push(MetricTypeInfo {
-                name: concat3(prefix_dot, name, "t"),
-                metric_type: MetricType::Timestamp,
-            });
-            metrics.push(MetricTypeInfo {
-                name: concat3(prefix_dot, name, "i"),
-                metric_type: MetricType::Timestamp,
-            });
-        }
+) -> Result<(), bson::raw::Error> {
+    match value {
+        RawBsonRef::Double(_) => push_metric_path(path, MetricType::Double, metrics),
+        RawBsonRef::Int64(_) => push_metric_path(path, MetricType::Int64, metrics),
+        RawBsonRef::Int32(_) => push_metric_path(path, MetricType::Int32, metrics),
+        RawBsonRef::Boolean(_) => push_metric_path(path, MetricType::Boolean, metrics),
+        RawBsonRef::DateTime(_) => push_metric_path(path, MetricType::DateTime, metrics),
+        RawBsonRef::Decimal128(_) => push_metric_path(path, MetricType::Decimal128, metrics),
+        RawBsonRef::Timestamp(_) => push_timestamp_paths(path, metrics),
         RawBsonRef::Document(o) => {
-            extract_metrics_paths_raw_int(o, concat2(prefix_dot, name).as_str(), metrics);
+            extract_metrics_paths_raw_int(o, path, metrics)?;
         }
         RawBsonRef::Array(a) => {
-            let mut idx = 0;
-            for b in a {
-                extract_metrics_paths_bson_raw_int(
-                    (*name, b.expect("valid raw bson array element in extract")),
-                    concat3(prefix_dot, name, &idx.to_string()).as_str(),
-                    metrics,
-                );
-                idx += 1;
+            for (idx, b) in a.into_iter().enumerate() {
+                path.push_index(idx);
+                extract_metrics_paths_bson_raw_int(b?, path, metrics)?;
+                path.pop();
             }
         }
 
@@ -353,31 +683,43 @@ fn extract_metrics_paths_bson_raw_int(
         | RawBsonRef::Symbol(_)
         | RawBsonRef::RegularExpression(_) => {}
     }
+
+    Ok(())
 }
 
 fn extract_metrics_paths_raw_int(
     doc: &RawDocument,
-    prefix: &str,
+    path: &mut MetricPath,
     metrics: &mut Vec<MetricTypeInfo>,
-) {
+) -> Result<(), bson::raw::Error> {
     for item in doc {
-        extract_metrics_paths_bson_raw_int(
-            item.expect("Valid bson for extract metrics"),
-            prefix,
-            metrics,
-        );
+        let (key, value) = item?;
+
+        path.push_key(key);
+        extract_metrics_paths_bson_raw_int(value, path, metrics)?;
+        path.pop();
     }
+
+    Ok(())
 }
 
-pub fn extract_metrics_paths_raw(doc: &RawDocument) -> Vec<MetricTypeInfo> {
+/**
+ * Describe every metric in a reference document, in the same order as `extract_metrics_raw`
+ *
+ * Unlike `bson::Document` a raw document keeps repeated keys, each gets its own entry.
+ */
+pub fn extract_metrics_paths_raw(
+    doc: &RawDocument,
+) -> Result<Vec<MetricTypeInfo>, bson::raw::Error> {
     let mut metrics: Vec<MetricTypeInfo> = Vec::new();
-    extract_metrics_paths_raw_int(doc, "", &mut metrics);
-    metrics
+    extract_metrics_paths_raw_int(doc, &mut MetricPath::new(), &mut metrics)?;
+    number_duplicate_paths(&mut metrics);
+    Ok(metrics)
 }
 
 fn fill_to_bson_int(ref_field: (&String, &Bson), it: &mut dyn Iterator<Item = &u64>) -> Bson {
     match ref_field.1 {
-        &Bson::Double(_) => Bson::Double(*it.next().unwrap() as f64),
+        &Bson::Double(_) => Bson::Double(*it.next().unwrap() as i64 as f64),
         &Bson::Int64(_) => Bson::Int64(*it.next().unwrap() as i64),
         &Bson::Int32(_) => Bson::Int32(*it.next().unwrap() as i32),
         &Bson::Boolean(_) => Bson::Boolean(*it.next().unwrap() != 0),
@@ -395,9 +737,7 @@ fn fill_to_bson_int(ref_field: (&String, &Bson), it: &mut dyn Iterator<Item = &u
                 increment: *p2 as u32,
             })
         }
-        &Bson::Decimal128(_) => {
-            panic!("Decimal128 not implemented")
-        }
+        &Bson::Decimal128(_) => Bson::Decimal128(i64_to_decimal128(*it.next().unwrap() as i64)),
         Bson::Document(o) => {
             let mut doc_nested = Document::new();
             for ref_field2 in o {
@@ -456,9 +796,9 @@ pub fn fill_document(ref_doc: &Document, metrics: &[u64]) -> Document {
 fn fill_to_raw_bson_int(
     ref_field: (&str, RawBsonRef),
     it: &mut dyn Iterator<Item = &u64>,
-) -> RawBson {
-    match ref_field.1 {
-        RawBsonRef::Double(_) => RawBson::Double(*it.next().unwrap() as f64),
+) -> Result<RawBson, bson::raw::Error> {
+    Ok(match ref_field.1 {
+        RawBsonRef::Double(_) => RawBson::Double(*it.next().unwrap() as i64 as f64),
         RawBsonRef::Int64(_) => RawBson::Int64(*it.next().unwrap() as i64),
         RawBsonRef::Int32(_) => RawBson::Int32(*it.next().unwrap() as i32),
         RawBsonRef::Boolean(_) => RawBson::Boolean(*it.next().unwrap() != 0),
@@ -477,12 +817,12 @@ fn fill_to_raw_bson_int(
             })
         }
         RawBsonRef::Decimal128(_) => {
-            panic!("Decimal128 not implemented")
+            RawBson::Decimal128(i64_to_decimal128(*it.next().unwrap() as i64))
         }
         RawBsonRef::Document(o) => {
             let mut doc_nested = RawDocumentBuf::new();
             for ref_field2 in o {
-                fill_document_bson_raw_int(ref_field2.expect("valid field"), it, &mut doc_nested);
+                fill_document_bson_raw_int(ref_field2?, it, &mut doc_nested)?;
             }
             RawBson::Document(doc_nested)
         }
@@ -493,8 +833,8 @@ fn fill_to_raw_bson_int(
             let c_str: &str = &c;
 
             for b in a {
-                let tuple = (c_str, b.expect("valid array field for fill"));
-                arr.push(fill_to_raw_bson_int(tuple, it));
+                let tuple = (c_str, b?);
+                arr.push(fill_to_raw_bson_int(tuple, it)?);
             }
 
             RawBson::Array(arr)
@@ -523,25 +863,31 @@ fn fill_to_raw_bson_int(
         RawBsonRef::MaxKey => RawBson::MaxKey,
         RawBsonRef::MinKey => RawBson::MinKey,
         RawBsonRef::Undefined => RawBson::Undefined,
-    }
+    })
 }
 
 fn fill_document_bson_raw_int(
     ref_field: (&str, RawBsonRef),
     it: &mut dyn Iterator<Item = &u64>,
     doc: &mut RawDocumentBuf,
-) {
-    doc.append(ref_field.0, fill_to_raw_bson_int(ref_field, it));
+) -> Result<(), bson::raw::Error> {
+    doc.append(ref_field.0, fill_to_raw_bson_int(ref_field, it)?);
+    Ok(())
 }
 
-pub fn fill_document_raw(ref_doc: &RawDocument, metrics: &[u64]) -> RawDocumentBuf {
+/// Rebuild a sample from the reference document and its metrics, an error if an element of the
+/// reference document is malformed
+pub fn fill_document_raw(
+    ref_doc: &RawDocument,
+    metrics: &[u64],
+) -> Result<RawDocumentBuf, bson::raw::Error> {
     let mut doc = RawDocumentBuf::new();
 
     let mut cur = metrics.iter();
 
     for item in ref_doc {
-        fill_document_bson_raw_int(item.expect("valid bson for fill"), &mut cur, &mut doc);
+        fill_document_bson_raw_int(item?, &mut cur, &mut doc)?;
     }
 
-    doc
+    Ok(doc)
 }
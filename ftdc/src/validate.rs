@@ -0,0 +1,300 @@
+// Copyright [2024] [Mark Benvenuto]
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::io::Read;
+
+use bson::spec::BinarySubtype;
+use bson::RawDocument;
+use chrono::DateTime;
+use chrono::Utc;
+use libflate::zlib::Decoder;
+
+use crate::error::FtdcError;
+use crate::limits::DecodeLimits;
+use crate::reader::decode_varint;
+use crate::reader::RawBSONBlock;
+use crate::time_range::block_date;
+use crate::util::extract_metrics_raw;
+
+/// The invariants `validate_block` checks, each issue names the one it broke
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Check {
+    /// The block could not be read from the stream at all
+    Read,
+    /// `_id` is missing or earlier than the previous metrics block
+    DateOrder,
+    /// `data` is missing or is not a generic binary
+    Subtype,
+    /// The zlib payload does not inflate to the size in the chunk header
+    UncompressedSize,
+    /// The reference document, metric count or sample count is missing or malformed
+    Header,
+    /// The reference document does not have the metric count in the header
+    MetricCount,
+    /// The delta stream ends early or has bytes after the last sample
+    StreamLength,
+    /// A run of zeros continues past the last sample of the last metric
+    ZeroRun,
+}
+
+impl Check {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Check::Read => "read",
+            Check::DateOrder => "date_order",
+            Check::Subtype => "subtype",
+            Check::UncompressedSize => "uncompressed_size",
+            Check::Header => "header",
+            Check::MetricCount => "metric_count",
+            Check::StreamLength => "stream_length",
+            Check::ZeroRun => "zero_run",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ValidationIssue {
+    pub check: Check,
+    pub message: String,
+}
+
+/// What `validate_block` found in one block
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BlockReport {
+    pub offset: u64,
+
+    /// The FTDC `type` field, None if the block could not be read
+    pub block_type: Option<i32>,
+    pub date: Option<DateTime<Utc>>,
+
+    /// Counts from the chunk header of a metrics block
+    pub metrics_count: Option<i32>,
+    pub sample_count: Option<i32>,
+
+    pub issues: Vec<ValidationIssue>,
+}
+
+impl BlockReport {
+    /// Report for an error the block reader returned instead of a block
+    pub fn read_error(err: &FtdcError) -> BlockReport {
+        let mut report = BlockReport {
+            offset: err.offset(),
+            ..BlockReport::default()
+        };
+        report.issue(Check::Read, err.to_string());
+        report
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.issues.is_empty()
+    }
+
+    fn issue(&mut self, check: Check, message: impl Into<String>) {
+        self.issues.push(ValidationIssue {
+            check,
+            message: message.into(),
+        });
+    }
+}
+
+/**
+ * Check a block against the invariants of the FTDC format without stopping at the first problem
+ *
+ * Unlike `decode_metric_block` nothing is allocated for the decoded metrics, the delta stream is
+ * only walked. `previous` is the `_id` of the last metrics block before this one, metrics blocks
+ * must not go back in time. Metadata blocks are written when a file is opened so their dates are
+ * not checked against the samples.
+ */
+pub fn validate_block(
+    block: &RawBSONBlock,
+    offset: u64,
+    previous: Option<DateTime<Utc>>,
+) -> BlockReport {
+    let (block_type, doc) = match block {
+        RawBSONBlock::Metadata(doc) => (0, doc),
+        RawBSONBlock::Metrics(doc) => (1, doc),
+        RawBSONBlock::PeriodicMetadata(doc) => (2, doc),
+    };
+
+    let mut report = BlockReport {
+        offset,
+        block_type: Some(block_type),
+        date: block_date(block),
+        ..BlockReport::default()
+    };
+
+    match (report.date, previous) {
+        (None, _) => report.issue(Check::DateOrder, "missing _id date"),
+        (Some(date), Some(prev)) if block_type == 1 && date < prev => report.issue(
+            Check::DateOrder,
+            format!(
+                "_id {} is before the previous metrics block {}",
+                date.to_rfc3339(),
+                prev.to_rfc3339()
+            ),
+        ),
+        _ => {}
+    }
+
+    if block_type == 1 {
+        validate_metrics_chunk(doc, &mut report);
+    }
+
+    report
+}
+
+fn validate_metrics_chunk(doc: &RawDocument, report: &mut BlockReport) {
+    let blob = match doc.get_binary("data") {
+        Ok(blob) => blob,
+        Err(e) => return report.issue(Check::Subtype, format!("no binary data field: {}", e)),
+    };
+    if blob.subtype != BinarySubtype::Generic {
+        report.issue(
+            Check::Subtype,
+            format!("unexpected binary subtype {:?}", blob.subtype),
+        );
+    }
+
+    let Some(header) = blob.bytes.get(..4) else {
+        return report.issue(Check::UncompressedSize, "metrics chunk too small");
+    };
+    let declared = i32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+
+    // Inflate at most one byte past the limit so a zlib bomb cannot exhaust memory
+    let limit = DecodeLimits::default().max_decompressed_size;
+    let mut data = Vec::new();
+    let inflated = Decoder::new(&blob.bytes[4..])
+        .and_then(|d| d.take(limit as u64 + 1).read_to_end(&mut data));
+    if let Err(e) = inflated {
+        return report.issue(
+            Check::UncompressedSize,
+            format!("zlib payload does not inflate: {}", e),
+        );
+    }
+    if data.len() > limit {
+        return report.issue(
+            Check::UncompressedSize,
+            format!("inflates to more than {} bytes", limit),
+        );
+    }
+    if declared < 0 || declared as usize != data.len() {
+        report.issue(
+            Check::UncompressedSize,
+            format!(
+                "header says {} bytes, payload inflates to {}",
+                declared,
+                data.len()
+            ),
+        );
+    }
+
+    let read_i32 = |pos: usize| {
+        data.get(pos..pos + 4)
+            .map(|b| i32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    };
+
+    let Some(ref_len) = read_i32(0).filter(|&l| l >= 0 && l as usize <= data.len()) else {
+        return report.issue(
+            Check::Header,
+            "missing or invalid reference document length",
+        );
+    };
+    // Walk every element now, extracting metrics from a malformed nested document would panic
+    let ref_doc = match RawDocument::from_bytes(&data[..ref_len as usize])
+        .and_then(|d| bson::Document::try_from(d).map(|_| d))
+    {
+        Ok(d) => d,
+        Err(e) => return report.issue(Check::Header, format!("bad reference document: {}", e)),
+    };
+
+    let pos = ref_len as usize;
+    let (Some(metrics_count), Some(sample_count)) = (read_i32(pos), read_i32(pos + 4)) else {
+        return report.issue(Check::Header, "missing metric or sample count");
+    };
+    report.metrics_count = Some(metrics_count);
+    report.sample_count = Some(sample_count);
+    if metrics_count < 0 || sample_count < 0 {
+        return report.issue(
+            Check::Header,
+            format!(
+                "negative counts, metrics {} samples {}",
+                metrics_count, sample_count
+            ),
+        );
+    }
+
+    let ref_metrics = match extract_metrics_raw(ref_doc) {
+        Ok(metrics) => metrics.len(),
+        Err(e) => return report.issue(Check::Header, format!("bad reference document: {}", e)),
+    };
+    if ref_metrics != metrics_count as usize {
+        report.issue(
+            Check::MetricCount,
+            format!(
+                "header says {} metrics, reference document has {}",
+                metrics_count, ref_metrics
+            ),
+        );
+    }
+
+    walk_metric_stream(
+        &data[pos + 8..],
+        metrics_count as u64 * sample_count as u64,
+        report,
+    );
+}
+
+/// Walk the delta/RLE stream of `values` deltas, a run of zeros is skipped in one step
+fn walk_metric_stream(buf: &[u8], values: u64, report: &mut BlockReport) {
+    let mut pos = 0;
+    let mut remaining = values;
+
+    while remaining > 0 {
+        let Some(val) = decode_varint(buf, &mut pos) else {
+            return report.issue(
+                Check::StreamLength,
+                format!(
+                    "stream ended with {} of {} values missing",
+                    remaining, values
+                ),
+            );
+        };
+        remaining -= 1;
+
+        if val == 0 {
+            let Some(zeros) = decode_varint(buf, &mut pos) else {
+                return report.issue(Check::StreamLength, "stream ended inside a run of zeros");
+            };
+            if zeros > remaining {
+                report.issue(
+                    Check::ZeroRun,
+                    format!(
+                        "run of zeros is {} values longer than the block",
+                        zeros - remaining
+                    ),
+                );
+                remaining = 0;
+            } else {
+                remaining -= zeros;
+            }
+        }
+    }
+
+    if pos != buf.len() {
+        report.issue(
+            Check::StreamLength,
+            format!("{} trailing bytes after the last value", buf.len() - pos),
+        );
+    }
+}
@@ -12,38 +12,120 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::ffi::OsString;
+use std::fs;
 use std::fs::File;
 use std::io::BufReader;
 use std::io::BufWriter;
 use std::io::Read;
 use std::io::Write;
+use std::path::Path;
 use std::path::PathBuf;
 
+use anyhow::anyhow;
 use anyhow::Result;
-use bson::Bson;
 use bson::Document;
+use bson::RawDocument;
+use bson::RawDocumentBuf;
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use chrono::DateTime;
+use chrono::Duration;
 use chrono::TimeZone;
 use chrono::Utc;
+use libflate::lz77::DefaultLz77Encoder;
+use libflate::zlib::EncodeOptions;
 use libflate::zlib::Encoder;
 use std::io::Cursor;
 
+use crate::util::extract_metrics_raw_into;
+use crate::util::extract_metrics_same_schema_raw;
 use crate::util::gen_metadata_document;
 use crate::util::gen_metrics_document;
+use crate::util::gen_periodic_metadata_document;
+
+/// mongod samples once a second and writes a block every 5 minutes
+pub const DEFAULT_MAX_SAMPLES: usize = 300;
+
+/// zlib's own default
+pub const DEFAULT_COMPRESSION_LEVEL: u32 = 6;
+
+/**
+ * When the writer closes a block and how it compresses it
+ *
+ * A block always closes when the schema of the samples changes. The defaults only bound the
+ * number of samples, like mongod.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WriterOptions {
+    /// Samples per block, including the reference document
+    pub max_samples: usize,
+
+    /// Longest time from the reference document to the last sample of a block
+    pub max_block_duration: Option<Duration>,
+
+    /// Start a new block when a sample is this much later than the one before it, so a collector
+    /// that paused does not leave a block spanning the gap
+    pub max_sample_gap: Option<Duration>,
+
+    /**
+     * zlib level from 0 to 9, higher values are treated as 9
+     *
+     * libflate does not implement zlib's levels. 0 stores blocks uncompressed, 1 to 3 use a
+     * smaller LZ77 window to trade size for speed and 4 to 9 all use the default encoder.
+     */
+    pub compression_level: u32,
+}
+
+impl Default for WriterOptions {
+    fn default() -> WriterOptions {
+        WriterOptions {
+            max_samples: DEFAULT_MAX_SAMPLES,
+            max_block_duration: None,
+            max_sample_gap: None,
+            compression_level: DEFAULT_COMPRESSION_LEVEL,
+        }
+    }
+}
 
+fn zlib_options(level: u32) -> EncodeOptions<DefaultLz77Encoder> {
+    match level {
+        0 => EncodeOptions::new().no_compression(),
+        1..=3 => EncodeOptions::with_lz77(DefaultLz77Encoder::with_window_size(1 << (9 + level))),
+        _ => EncodeOptions::new(),
+    }
+}
+
+/**
+ * Delta encode samples into FTDC metric chunks
+ *
+ * Deltas are computed as samples arrive and kept in one column-major buffer. The buffer doubles
+ * as a block grows and is reused by the next block, so once it has grown adding a sample and
+ * compressing a block allocate nothing per sample.
+ */
 pub struct BSONMetricsCompressor {
-    // samples: usize,
-    max_samples: usize,
+    options: WriterOptions,
 
     metrics: usize,
 
-    // TODO - change this to deltas array so we keep this column oriented in memory
-    // also store the un delta encoded metrics as we accumulate samples
-    metric_vec: Vec<Vec<u64>>,
-    ref_doc: Document,
+    /// Samples after the reference document in the current block
+    samples: usize,
+
+    /// `metrics` columns of `column_capacity` deltas, only the first `samples` of each are set
+    deltas: Vec<u64>,
+    column_capacity: usize,
+
+    /// Metrics of the last sample added, the base of the next delta
+    last: Vec<u64>,
+
+    /// Metrics of the sample being added
+    scratch: Vec<u64>,
+
+    ref_doc: RawDocumentBuf,
     ref_doc_vec: Vec<u64>,
-    ref_date: DateTime<Utc>, // blocks : Vec<Vec<u8>>,
+    ref_date: DateTime<Utc>,
+
+    /// Date of the last sample added
+    last_date: DateTime<Utc>,
 }
 
 #[derive(Debug, PartialEq)]
@@ -52,141 +134,233 @@ pub enum AddResult {
     ExistingBlock,
 }
 
+/// Deltas per metric allocated for a new block before it grows, samples usually come in bulk
+const INITIAL_COLUMN_CAPACITY: usize = 16;
+
+/// Append `v` as an unsigned LEB128 varint
+fn push_varint(out: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        out.push((v as u8) | 0x80);
+        v >>= 7;
+    }
+    out.push(v as u8);
+}
+
 // TODO - rename this as metric block compressor
-// Create FTDC file writer
 impl BSONMetricsCompressor {
     pub fn new(max_samples: usize) -> BSONMetricsCompressor {
-        BSONMetricsCompressor {
-            // samples : 0,
+        BSONMetricsCompressor::with_options(WriterOptions {
             max_samples,
+            ..WriterOptions::default()
+        })
+    }
+
+    pub fn with_options(options: WriterOptions) -> BSONMetricsCompressor {
+        BSONMetricsCompressor {
+            options,
             metrics: 0,
-            metric_vec: Vec::new(),
-            ref_doc: Document::new(),
+            samples: 0,
+            deltas: Vec::new(),
+            column_capacity: 0,
+            last: Vec::new(),
+            scratch: Vec::new(),
+            ref_doc: RawDocumentBuf::new(),
             ref_doc_vec: Vec::new(),
             ref_date: Utc.timestamp_nanos(0),
+            last_date: Utc.timestamp_nanos(0),
         }
     }
 
-    // TODO - report if new block was started
+    pub fn options(&self) -> &WriterOptions {
+        &self.options
+    }
+
     pub fn add_doc(&mut self, doc: &Document, date: DateTime<Utc>) -> Result<AddResult> {
-        let mut met_vec = Vec::new();
-        extract_metrics_int(doc, &mut met_vec);
+        self.add_raw_doc(&RawDocumentBuf::from_document(doc)?, date)
+    }
 
-        // first document
-        if self.ref_doc.is_empty() {
-            self.ref_doc = doc.clone();
-            self.ref_date = date;
+    /// Deltas one metric can hold in a block, the sample count of a block is an i32
+    fn column_len(&self) -> usize {
+        self.options
+            .max_samples
+            .saturating_sub(1)
+            .min(i32::MAX as usize)
+    }
 
-            self.metric_vec.clear();
-            self.metrics = met_vec.len();
+    /// Resize `deltas` to `metrics` columns of `capacity`, keeping the deltas already added
+    fn resize_columns(&mut self, capacity: usize) -> Result<()> {
+        let len = self.metrics.checked_mul(capacity).ok_or_else(|| {
+            anyhow!(
+                "{} metrics x {} samples is too large",
+                self.metrics,
+                capacity
+            )
+        })?;
+
+        if self.samples == 0 {
+            // Stale deltas of the last block are never read
+            self.deltas.resize(len, 0);
+        } else {
+            let mut deltas = vec![0; len];
+            for (new, old) in deltas
+                .chunks_exact_mut(capacity)
+                .zip(self.deltas.chunks_exact(self.column_capacity))
+            {
+                new[..self.samples].copy_from_slice(&old[..self.samples]);
+            }
+            self.deltas = deltas;
+        }
 
-            self.ref_doc_vec = met_vec;
-            // self.samples = 0;
+        self.column_capacity = capacity;
+        Ok(())
+    }
 
-            // self.metric_vec.push(met_vec);
+    /// True if a sample taken at `date` still fits in the time bounds of the current block
+    fn within_time_bounds(&self, date: DateTime<Utc>) -> bool {
+        let block_ok = self
+            .options
+            .max_block_duration
+            .is_none_or(|max| date.signed_duration_since(self.ref_date) <= max);
+        let gap_ok = self
+            .options
+            .max_sample_gap
+            .is_none_or(|max| date.signed_duration_since(self.last_date) <= max);
+
+        block_ok && gap_ok
+    }
+
+    /**
+     * Add a sample that is already serialized, the output is the same as for `add_doc`
+     *
+     * A malformed `doc` is an error and leaves the block being built as it was.
+     */
+    pub fn add_raw_doc(&mut self, doc: &RawDocument, date: DateTime<Utc>) -> Result<AddResult> {
+        self.scratch.clear();
+
+        // first document
+        if self.ref_doc.is_empty() {
+            extract_metrics_raw_into(doc, &mut self.scratch)?;
+            self.start_block(doc, date)?;
             return Ok(AddResult::NewBlock(None));
         }
 
-        // If metric count the same?
-        if self.metrics == met_vec.len() && self.metric_vec.len() < self.max_samples - 1 {
-            self.metric_vec.push(met_vec);
+        // A sample with a different shape cannot be rebuilt from the reference document
+        if self.samples < self.column_len()
+            && self.within_time_bounds(date)
+            && extract_metrics_same_schema_raw(&self.ref_doc, doc, &mut self.scratch)
+        {
+            if self.samples == self.column_capacity {
+                let capacity = (self.column_capacity * 2).min(self.column_len());
+                self.resize_columns(capacity)?;
+            }
+
+            let column_capacity = self.column_capacity;
+            for (m, (last, &v)) in self.last.iter_mut().zip(&self.scratch).enumerate() {
+                self.deltas[m * column_capacity + self.samples] = v.wrapping_sub(*last);
+                *last = v;
+            }
+            self.samples += 1;
+            self.last_date = date;
 
             Ok(AddResult::ExistingBlock)
         } else {
+            // Check the sample before the current block is given up for it
+            self.scratch.clear();
+            extract_metrics_raw_into(doc, &mut self.scratch)?;
+
             // New block, flush chunk
             let block = self.flush_block()?;
+            let block_date = self.ref_date;
 
-            self.ref_doc = doc.clone();
-            self.ref_date = date;
-
-            self.metric_vec.clear();
-            self.metrics = met_vec.len();
+            self.start_block(doc, date)?;
 
-            self.ref_doc_vec = met_vec;
-
-            Ok(AddResult::NewBlock(Some((block, date))))
+            Ok(AddResult::NewBlock(Some((block, block_date))))
         }
     }
 
-    fn flush(&mut self) -> Result<Option<(Vec<u8>, DateTime<Utc>)>> {
-        if self.metric_vec.is_empty() {
-            return Ok(None);
-        }
-
-        Ok(Some((self.flush_block()?, self.ref_date)))
+    /// Make the sample in `scratch` the reference document of a new block
+    fn start_block(&mut self, doc: &RawDocument, date: DateTime<Utc>) -> Result<()> {
+        self.ref_doc = doc.to_raw_document_buf();
+        self.ref_date = date;
+        self.last_date = date;
+
+        self.metrics = self.scratch.len();
+        self.samples = 0;
+        std::mem::swap(&mut self.ref_doc_vec, &mut self.scratch);
+        self.last.clone_from(&self.ref_doc_vec);
+
+        // Start at the size the last block grew to
+        let capacity = self
+            .column_capacity
+            .max(INITIAL_COLUMN_CAPACITY)
+            .min(self.column_len());
+        self.resize_columns(capacity)
     }
 
-    // Compress Metric Vectors
-    fn compress_metric_vec(&mut self) -> Vec<u8> {
-        if self.metric_vec.is_empty() {
-            return Vec::new();
+    pub(crate) fn flush(&mut self) -> Result<Option<(Vec<u8>, DateTime<Utc>)>> {
+        // A lone reference document is still a sample
+        if self.ref_doc.is_empty() {
+            return Ok(None);
         }
-        // assert!(!self.metric_vec.is_empty());
 
-        // eprintln!("rdv: {:?}", self.ref_doc_vec);
-        // eprintln!("ccc: {:?}", self.metric_vec);
+        let block = self.flush_block()?;
 
-        // Do delta calculations
-        let metric_count = self.ref_doc_vec.len();
-        let sample_count = self.metric_vec.len();
+        // The next sample starts a new block instead of extending the one just written
+        self.ref_doc = RawDocumentBuf::new();
+        self.samples = 0;
 
-        for s in (1..sample_count).rev() {
-            for m in 0..metric_count {
-                self.metric_vec[s][m] =
-                    self.metric_vec[s][m].wrapping_sub(self.metric_vec[s - 1][m]);
-            }
-        }
-        // eprintln!("ccc: {:?}", self.metric_vec);
+        Ok(Some((block, self.ref_date)))
+    }
 
-        for m in 0..metric_count {
-            self.metric_vec[0][m] = self.metric_vec[0][m].wrapping_sub(self.ref_doc_vec[m]);
+    /// Samples in the block being built, including the reference document
+    pub fn sample_count(&self) -> usize {
+        if self.ref_doc.is_empty() {
+            0
+        } else {
+            self.samples + 1
         }
+    }
 
-        // eprintln!("ccc: {:?}", self.metric_vec);
+    /**
+     * Compress the block being built without finishing it, None if there are no samples
+     *
+     * Used for `metrics.interim`, later samples keep being added to the same block.
+     */
+    pub fn current_block(&self) -> Result<Option<(Vec<u8>, DateTime<Utc>)>> {
+        if self.ref_doc.is_empty() {
+            return Ok(None);
+        }
 
-        // Do RLE
-        let mut count_zeros = 0;
-        let mut out = vec![0; metric_count * sample_count * 8];
+        Ok(Some((self.flush_block()?, self.ref_date)))
+    }
 
-        let mut offset = 0;
+    /// Run length encode the zero deltas of each column in turn and append the stream to `out`
+    fn compress_deltas(&self, out: &mut Vec<u8>) {
+        let mut count_zeros: u64 = 0;
 
-        for m in 0..metric_count {
-            for s in 0..sample_count {
-                let v = self.metric_vec[s][m];
+        if self.samples > 0 {
+            for column in self.deltas.chunks_exact(self.column_capacity) {
+                for &v in &column[..self.samples] {
+                    if v == 0 {
+                        count_zeros += 1;
+                        continue;
+                    }
 
-                if v != 0 {
                     if count_zeros > 0 {
-                        let write_size = varinteger::encode_with_offset(0, &mut out, offset);
-                        offset += write_size;
-
-                        let write_size =
-                            varinteger::encode_with_offset(count_zeros - 1, &mut out, offset);
-                        offset += write_size;
-
+                        push_varint(out, 0);
+                        push_varint(out, count_zeros - 1);
                         count_zeros = 0;
                     }
 
-                    let write_size = varinteger::encode_with_offset(v, &mut out, offset);
-                    offset += write_size;
-                } else {
-                    count_zeros += 1;
+                    push_varint(out, v);
                 }
             }
         }
 
         if count_zeros > 0 {
-            // eprintln!("cz: {:?}", count_zeros);
-
-            let write_size = varinteger::encode_with_offset(0, &mut out, offset);
-            offset += write_size;
-
-            let write_size = varinteger::encode_with_offset(count_zeros - 1, &mut out, offset);
-            offset += write_size;
+            push_varint(out, 0);
+            push_varint(out, count_zeros - 1);
         }
-
-        out.resize(offset, 0);
-
-        out
     }
 
     ///
@@ -199,23 +373,23 @@ impl BSONMetricsCompressor {
     /// i32 metric
     /// i32 sample
     /// bytes block
-    pub fn flush_block(&mut self) -> Result<Vec<u8>> {
-        let ref_vec = bson::to_vec(&self.ref_doc)?;
+    pub fn flush_block(&self) -> Result<Vec<u8>> {
+        let ref_vec = self.ref_doc.as_bytes();
 
         let mut uncompressed_block: Vec<u8> =
-            Vec::with_capacity(4 + ref_vec.len() + self.metrics * self.metric_vec.len());
+            Vec::with_capacity(ref_vec.len() + 8 + self.metrics * self.samples);
 
-        uncompressed_block.write_all(&ref_vec)?;
+        uncompressed_block.write_all(ref_vec)?;
 
         uncompressed_block.write_i32::<LittleEndian>(self.metrics as i32)?;
-        uncompressed_block.write_i32::<LittleEndian>(self.metric_vec.len() as i32)?;
+        uncompressed_block.write_i32::<LittleEndian>(self.samples as i32)?;
 
         // Delta & RLE encode
-        let encoded_block = self.compress_metric_vec();
-        uncompressed_block.write_all(&encoded_block)?;
+        self.compress_deltas(&mut uncompressed_block);
 
         // Compress
-        let mut encoder = Encoder::new(Vec::new())?;
+        let mut encoder =
+            Encoder::with_options(Vec::new(), zlib_options(self.options.compression_level))?;
         encoder.write_all(&uncompressed_block)?;
         let encoded_data = encoder.finish().into_result()?;
 
@@ -228,31 +402,157 @@ impl BSONMetricsCompressor {
     }
 }
 
+/// Side file of a single FTDC file writer, `<file>.interim`
+pub fn interim_file_path(file_name: &Path) -> PathBuf {
+    let mut name = OsString::from(file_name.as_os_str());
+    name.push(".interim");
+    PathBuf::from(name)
+}
+
+/**
+ * File the block being built is rewritten to every few samples, like mongod's `metrics.interim`
+ *
+ * It only ever holds samples that are not in the main file yet, so a reader can append its blocks
+ * to the main file after a crash.
+ */
+pub(crate) struct InterimFile {
+    path: PathBuf,
+    samples: usize,
+    since_write: usize,
+}
+
+impl InterimFile {
+    pub(crate) fn new(path: PathBuf, samples: usize) -> InterimFile {
+        InterimFile {
+            path,
+            samples: samples.max(1),
+            since_write: 0,
+        }
+    }
+
+    /// Count a sample, true when it is time to rewrite the file
+    pub(crate) fn tick(&mut self) -> bool {
+        self.since_write += 1;
+        self.since_write >= self.samples
+    }
+
+    /**
+     * Replace the file with the block being built, or empty it if there is none
+     *
+     * The block is written and synced to a temporary file that is then renamed over the interim
+     * file, so a crash leaves either the old block or the new one, never a torn one.
+     */
+    pub(crate) fn write(&mut self, compressor: &BSONMetricsCompressor) -> Result<()> {
+        self.since_write = 0;
+
+        let mut temp_name = OsString::from(self.path.as_os_str());
+        temp_name.push(".temp");
+        let temp_path = PathBuf::from(temp_name);
+
+        let mut temp = File::create(&temp_path)?;
+        if let Some((block, date)) = compressor.current_block()? {
+            write_doc_to_writer(&mut temp, &gen_metrics_document(&block, date))?;
+        }
+        temp.sync_all()?;
+
+        fs::rename(&temp_path, &self.path)?;
+        Ok(())
+    }
+
+    pub(crate) fn remove(&self) -> Result<()> {
+        match fs::remove_file(&self.path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/**
+ * Write FTDC blocks to a stream
+ *
+ * Samples are buffered until a block is full. `close` writes the last block and reports any
+ * error, dropping the writer without closing it does the same but ignores errors. With
+ * `with_interim_file` the samples of the unfinished block also survive the process exiting
+ * without either.
+ */
 pub struct BSONBlockWriter<W: Write> {
-    writer: BufWriter<W>,
+    // Only None after into_inner
+    writer: Option<BufWriter<W>>,
     compressor: BSONMetricsCompressor,
+    interim: Option<InterimFile>,
+
+    /// Makes written blocks durable before the interim file drops them, a flush unless `W` is a file
+    sync_data: fn(&mut BufWriter<W>) -> std::io::Result<()>,
+    closed: bool,
+}
+
+fn flush_writer<W: Write>(writer: &mut BufWriter<W>) -> std::io::Result<()> {
+    writer.flush()
+}
+
+fn sync_file_data(writer: &mut BufWriter<File>) -> std::io::Result<()> {
+    writer.flush()?;
+    writer.get_ref().sync_data()
 }
 
 impl BSONBlockWriter<File> {
+    /// Create `file_name`, an interim file left next to it by an earlier writer is removed
     pub fn new_file(file_name: &PathBuf, max_samples: usize) -> Result<BSONBlockWriter<File>> {
         let ff = File::create(file_name)?;
+        InterimFile::new(interim_file_path(file_name), 1).remove()?;
+
+        Ok(BSONBlockWriter::new(
+            ff,
+            WriterOptions {
+                max_samples,
+                ..WriterOptions::default()
+            },
+        ))
+    }
 
-        Ok(BSONBlockWriter {
-            writer: BufWriter::new(ff),
-            compressor: BSONMetricsCompressor::new(max_samples),
-        })
+    /// Flush buffered blocks and sync the file to disk, the unfinished block is not written
+    pub fn sync_all(&mut self) -> Result<()> {
+        let writer = self.writer();
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        Ok(())
+    }
+
+    /**
+     * Rewrite the unfinished block to `path` every `samples` samples
+     *
+     * `interim_file_path` is the name `DiagnosticDataReader` looks for next to a single file, it
+     * reads the interim blocks after the file. The interim file is emptied right away so one left
+     * by an earlier writer is not read after this file, and it is removed on `close`. Finished
+     * blocks are synced to the file before they are dropped from the interim file.
+     */
+    pub fn with_interim_file(
+        mut self,
+        path: PathBuf,
+        samples: usize,
+    ) -> Result<BSONBlockWriter<File>> {
+        let mut interim = InterimFile::new(path, samples);
+        interim.write(&self.compressor)?;
+
+        self.interim = Some(interim);
+        self.sync_data = sync_file_data;
+        Ok(self)
     }
 }
 
 impl BSONBlockWriter<bytes::buf::Writer<Vec<u8>>> {
+    #[deprecated(note = "use BSONBlockWriter::new, it takes any Write")]
     pub fn new_bytes(
         buf_mut: &mut bytes::buf::Writer<Vec<u8>>,
         max_samples: usize,
     ) -> Result<BSONBlockWriter<&mut bytes::buf::Writer<Vec<u8>>>> {
-        Ok(BSONBlockWriter {
-            writer: BufWriter::new(buf_mut),
-            compressor: BSONMetricsCompressor::new(max_samples),
-        })
+        Ok(BSONBlockWriter::new(
+            buf_mut,
+            WriterOptions {
+                max_samples,
+                ..WriterOptions::default()
+            },
+        ))
     }
 }
 
@@ -264,16 +564,54 @@ fn write_doc_to_writer(writer: &mut dyn Write, doc: &Document) -> Result<()> {
     Ok(())
 }
 
-// TODO - reduce copies by using raw bson api?
 impl<W: Write> BSONBlockWriter<W> {
+    /// Write blocks to `writer`, it is buffered so a `File` or `TcpStream` can be passed as is
+    pub fn new(writer: W, options: WriterOptions) -> BSONBlockWriter<W> {
+        BSONBlockWriter {
+            writer: Some(BufWriter::new(writer)),
+            compressor: BSONMetricsCompressor::with_options(options),
+            interim: None,
+            sync_data: flush_writer,
+            closed: false,
+        }
+    }
+
+    fn writer(&mut self) -> &mut BufWriter<W> {
+        self.writer
+            .as_mut()
+            .expect("writer is only taken by into_inner")
+    }
+
+    /// See `WriterOptions`, must be set before the first sample
+    pub fn with_options(mut self, options: WriterOptions) -> BSONBlockWriter<W> {
+        self.compressor = BSONMetricsCompressor::with_options(options);
+        self
+    }
+
     pub fn add_metdata_doc(&mut self, doc: &Document, date: DateTime<Utc>) -> Result<()> {
         let md_doc = gen_metadata_document(doc, date);
 
-        write_doc_to_writer(&mut self.writer, &md_doc)
+        write_doc_to_writer(self.writer(), &md_doc)
+    }
+
+    /// Write the metadata fields that changed since the last metadata document, see `MetadataTracker`
+    pub fn add_periodic_metadata_doc(
+        &mut self,
+        delta: &Document,
+        date: DateTime<Utc>,
+    ) -> Result<()> {
+        let md_doc = gen_periodic_metadata_document(delta, date);
+
+        write_doc_to_writer(self.writer(), &md_doc)
     }
 
     pub fn add_sample(&mut self, doc: &Document, sample_date: DateTime<Utc>) -> Result<()> {
-        let result = self.compressor.add_doc(doc, sample_date)?;
+        self.add_raw_sample(&RawDocumentBuf::from_document(doc)?, sample_date)
+    }
+
+    /// Add a sample that is already serialized, see `BSONMetricsCompressor::add_raw_doc`
+    pub fn add_raw_sample(&mut self, doc: &RawDocument, sample_date: DateTime<Utc>) -> Result<()> {
+        let result = self.compressor.add_raw_doc(doc, sample_date)?;
 
         match result {
             AddResult::ExistingBlock => {
@@ -283,23 +621,129 @@ impl<W: Write> BSONBlockWriter<W> {
                 if let Some((block, date)) = block_opt {
                     let metric_doc = gen_metrics_document(&block, date);
 
-                    write_doc_to_writer(&mut self.writer, &metric_doc)?
+                    write_doc_to_writer(self.writer(), &metric_doc)?;
+
+                    // The interim file holds samples that are now in the main file
+                    if self.interim.is_some() {
+                        let sync_data = self.sync_data;
+                        sync_data(self.writer())?;
+                    }
+                    if let Some(interim) = &mut self.interim {
+                        interim.write(&self.compressor)?;
+                    }
+                    return Ok(());
                 }
             }
         }
 
+        if let Some(interim) = &mut self.interim {
+            if interim.tick() {
+                interim.write(&self.compressor)?;
+            }
+        }
+
         Ok(())
     }
 
+    /// Write the unfinished block, later samples start a new block
     pub fn flush(&mut self) -> Result<()> {
         if let Some((block, date)) = self.compressor.flush()? {
             let metric_doc = gen_metrics_document(&block, date);
 
-            write_doc_to_writer(&mut self.writer, &metric_doc)?
+            write_doc_to_writer(self.writer(), &metric_doc)?
+        }
+
+        // Synced with an interim file, whose block is now in the stream
+        let sync_data = self.sync_data;
+        sync_data(self.writer())?;
+
+        if let Some(interim) = &mut self.interim {
+            interim.write(&self.compressor)?;
         }
 
         Ok(())
     }
+
+    /// Write the unfinished block, flush the stream and remove the interim file
+    pub fn close(mut self) -> Result<()> {
+        self.finish()
+    }
+
+    /// Like `close`, but hand back the stream, e.g. the bytes of a `Vec<u8>`
+    pub fn into_inner(mut self) -> Result<W> {
+        self.finish()?;
+
+        let writer = self
+            .writer
+            .take()
+            .expect("writer is only taken by into_inner");
+        writer.into_inner().map_err(|e| e.into_error().into())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.closed = true;
+        self.flush()?;
+
+        if let Some(interim) = &self.interim {
+            interim.remove()?;
+        }
+
+        Ok(())
+    }
+}
+
+impl<W: Write> Drop for BSONBlockWriter<W> {
+    fn drop(&mut self) {
+        if !self.closed {
+            // Errors can only be reported by close
+            let _ = self.finish();
+        }
+    }
+}
+
+/**
+ * A complete FTDC stream built in memory
+ *
+ * Blocks go through the same `BSONBlockWriter` as a file, `into_reader` reads them back with
+ * `reader::BSONBlockReader`.
+ */
+pub struct FtdcBuffer {
+    writer: BSONBlockWriter<Vec<u8>>,
+}
+
+impl Default for FtdcBuffer {
+    fn default() -> Self {
+        FtdcBuffer::new(WriterOptions::default())
+    }
+}
+
+impl FtdcBuffer {
+    pub fn new(options: WriterOptions) -> FtdcBuffer {
+        FtdcBuffer {
+            writer: BSONBlockWriter::new(Vec::new(), options),
+        }
+    }
+
+    pub fn add_metadata_doc(&mut self, doc: &Document, date: DateTime<Utc>) -> Result<()> {
+        self.writer.add_metdata_doc(doc, date)
+    }
+
+    pub fn add_sample(&mut self, doc: &Document, sample_date: DateTime<Utc>) -> Result<()> {
+        self.writer.add_sample(doc, sample_date)
+    }
+
+    pub fn add_raw_sample(&mut self, doc: &RawDocument, sample_date: DateTime<Utc>) -> Result<()> {
+        self.writer.add_raw_sample(doc, sample_date)
+    }
+
+    /// The stream with the unfinished block written
+    pub fn into_bytes(self) -> Result<Vec<u8>> {
+        self.writer.into_inner()
+    }
+
+    pub fn into_reader(self) -> Result<crate::reader::BSONBlockReader<Cursor<Vec<u8>>>> {
+        crate::reader::BSONBlockReader::new_reader(Cursor::new(self.into_bytes()?))
+    }
 }
 
 pub struct BSONBlockReader<R: Read> {
@@ -387,54 +831,3 @@ impl<R: Read> Iterator for BSONBlockReader<R> {
         Some(RawBSONBlock::Metrics(doc))
     }
 }
-
-fn extract_metrics_bson_int(value: &Bson, metrics: &mut Vec<u64>) {
-    match value {
-        &Bson::Double(f) => {
-            metrics.push(f as u64);
-        }
-        &Bson::Int64(f) => {
-            metrics.push(f as u64);
-        }
-        &Bson::Int32(f) => {
-            metrics.push(f as u64);
-        }
-        &Bson::Decimal128(_) => {
-            panic!("Decimal128 not implemented")
-        }
-        &Bson::Boolean(f) => {
-            metrics.push(f as u64);
-        }
-        &Bson::DateTime(f) => {
-            metrics.push(f.timestamp_millis() as u64);
-        }
-        &Bson::Timestamp(f) => {
-            metrics.push(f.time as u64);
-            metrics.push(f.increment as u64);
-        }
-        Bson::Document(o) => {
-            extract_metrics_int(o, metrics);
-        }
-        Bson::Array(a) => {
-            for b in a {
-                extract_metrics_bson_int(b, metrics);
-            }
-        }
-
-        &Bson::JavaScriptCode(_) => {}
-        &Bson::JavaScriptCodeWithScope(_) => {}
-        &Bson::Binary(_) => {}
-        &Bson::ObjectId(_) => {}
-        &Bson::DbPointer(_) => {}
-        &Bson::MaxKey | &Bson::MinKey | &Bson::Undefined => {}
-        &Bson::String(_) | &Bson::Null | &Bson::Symbol(_) | &Bson::RegularExpression(_) => {}
-    }
-}
-
-fn extract_metrics_int(doc: &Document, metrics: &mut Vec<u64>) {
-    for item in doc {
-        let value = item.1;
-        // eprintln!("ee:{:?}",item);
-        extract_metrics_bson_int(value, metrics);
-    }
-}
@@ -20,20 +20,42 @@ use std::io::BufRead;
 use std::io::BufReader;
 use std::io::BufWriter;
 use std::io::Write;
+use std::path::Path;
 use std::path::PathBuf;
+use std::time::Duration;
+use std::time::Instant;
 
 use bson::to_document;
 use bson::RawDocument;
+use chrono::DateTime;
 use chrono::TimeZone;
 use chrono::Utc;
-use clap::{Parser, Subcommand, ValueEnum};
+use clap::{ArgGroup, Args, Parser, Subcommand, ValueEnum};
 
+use anyhow::anyhow;
 use anyhow::Result;
+use ftdc::controller::FtdcController;
+use ftdc::diagnostic_data::DEFAULT_INTERIM_SAMPLES;
+use ftdc::reader::DecodedMetricBlock;
+use ftdc::system_metrics::HostInfoCollector;
+use ftdc::system_metrics::SystemMetricsCollector;
+use ftdc::time_range::block_date;
 use ftdc::util::extract_metrics_paths_raw;
 use ftdc::util::extract_metrics_raw;
+use ftdc::util::typed_metrics;
+use ftdc::validate_block;
+use ftdc::writer::interim_file_path;
 use ftdc::writer::BSONBlockWriter;
+use ftdc::BlockReport;
+use ftdc::DiagnosticDataWriter;
+use ftdc::FtdcError;
+use ftdc::MetricPath;
+use ftdc::MetricValue;
 use ftdc::MetricsDocument;
+use ftdc::TimeRange;
+use ftdc::TimeSpec;
 use ftdc::VectorMetricsDocument;
+use ftdc::WriterOptions;
 use indexmap::IndexMap;
 use std::collections::HashMap;
 
@@ -46,7 +68,7 @@ use tikv_jemallocator::Jemalloc;
 #[global_allocator]
 static GLOBAL: Jemalloc = Jemalloc;
 
-/**
+/*
  * TODO:
  * 3. add regex filtering
  * 4. find arg parsing crate
@@ -69,6 +91,21 @@ struct Cli {
     /// Verbose logging
     #[arg(short = 'v', long = "verbose")]
     verbose: bool,
+
+    /// Skip past corrupt blocks instead of stopping, errors are reported on stderr
+    #[arg(long = "recover", global = true)]
+    recover: bool,
+
+    /// Seek to the --start/--end window with a block index kept next to each FTDC file
+    #[arg(long = "index", global = true)]
+    index: bool,
+}
+
+/// How input files are read, from the global flags
+#[derive(Clone, Copy, Debug)]
+struct ReadOptions {
+    recover: bool,
+    index: bool,
 }
 
 #[derive(ValueEnum, Copy, Clone, Debug, PartialEq, Eq)]
@@ -79,17 +116,36 @@ enum OutputFormat {
 
 #[derive(ValueEnum, Copy, Clone, Debug, PartialEq, Eq)]
 enum FlatOutputFormat {
+    #[allow(clippy::upper_case_acronyms)]
     CSV,
     // Parquet,
     Prometheus,
 }
 
+#[derive(ValueEnum, Copy, Clone, Debug, PartialEq, Eq)]
+enum ReportFormat {
+    Text,
+    Json,
+}
+
+/// Restrict output to samples in a window of time
+#[derive(Debug, Args)]
+struct TimeWindow {
+    /// Start of the window, RFC 3339 or relative to the end of the data such as -15m
+    #[arg(long, allow_hyphen_values = true)]
+    start: Option<TimeSpec>,
+
+    /// End of the window, RFC 3339 or relative to the end of the data such as -5m
+    #[arg(long, allow_hyphen_values = true)]
+    end: Option<TimeSpec>,
+}
+
 #[derive(Debug, Subcommand)]
 enum Commands {
     /// Decompress FTDC to JSON
     #[command(arg_required_else_help = true)]
     Convert {
-        /// Input file
+        /// Input file or diagnostic.data directory
         #[arg(required = true, short, long)]
         input: PathBuf,
 
@@ -104,12 +160,19 @@ enum Commands {
         /// Output file, stdout if not present
         #[arg(required = false, short, long)]
         output: Option<PathBuf>,
+
+        /// Threads used to decode blocks, one per CPU by default
+        #[arg(long)]
+        threads: Option<usize>,
+
+        #[command(flatten)]
+        window: TimeWindow,
     },
 
     /// Decompress FTDC to CSV, Parquet or Promethesus
     #[command(arg_required_else_help = true)]
     ConvertFlat {
-        /// Input file
+        /// Input file or diagnostic.data directory
         #[arg(required = true, short, long)]
         input: PathBuf,
 
@@ -128,13 +191,23 @@ enum Commands {
         /// Sample records in a metric batch
         #[arg(required = false, short, long)]
         sample: Option<u16>,
+
+        /// Threads used to decode blocks, one per CPU by default
+        #[arg(long)]
+        threads: Option<usize>,
+
+        #[command(flatten)]
+        window: TimeWindow,
     },
 
     /// Analyze timings of FTDC capture
     Timings {
-        /// Input file
+        /// Input file or diagnostic.data directory
         #[arg(required = true, short, long)]
         input: PathBuf,
+
+        #[command(flatten)]
+        window: TimeWindow,
         // /// Output file, stdout if not present
         // #[arg(required = false, short, long)]
         // output: Option<PathBuf>,
@@ -142,9 +215,16 @@ enum Commands {
 
     /// Stats about FTDC files
     Stats {
-        /// Input file
+        /// Input file or diagnostic.data directory
         #[arg(required = true, short, long)]
         input: PathBuf,
+
+        /// Threads used to decode blocks, one per CPU by default
+        #[arg(long)]
+        threads: Option<usize>,
+
+        #[command(flatten)]
+        window: TimeWindow,
         // /// Output file, stdout if not present
         // #[arg(required = false, short, long)]
         // output: Option<PathBuf>,
@@ -152,14 +232,34 @@ enum Commands {
 
     /// Block Stats about Metric Chunks
     BlockStats {
-        /// Input file
+        /// Input file or diagnostic.data directory
         #[arg(required = true, short, long)]
         input: PathBuf,
+
+        #[command(flatten)]
+        window: TimeWindow,
         // /// Output file, stdout if not present
         // #[arg(required = false, short, long)]
         // output: Option<PathBuf>,
     },
 
+    /// Build or refresh the block index of FTDC files and print it
+    Index {
+        /// Input file or diagnostic.data directory
+        #[arg(required = true, short, long)]
+        input: PathBuf,
+    },
+
+    /// Check every block of FTDC files for corruption and print a report per block
+    Validate {
+        /// Input file or diagnostic.data directory
+        #[arg(required = true, short, long)]
+        input: PathBuf,
+
+        #[arg(short, long, default_value_t = ReportFormat::Text, value_enum)]
+        format: ReportFormat,
+    },
+
     /// Convert Prometheus exposition format file to FTDC
     #[command(arg_required_else_help = true)]
     ConvertProm {
@@ -171,6 +271,26 @@ enum Commands {
         #[arg(required = false, short, long)]
         output: PathBuf,
     },
+
+    /// Collect Linux system metrics from /proc to FTDC
+    #[command(group(ArgGroup::new("destination").required(true).args(["output", "dir"])))]
+    Collect {
+        /// Output file, the unfinished block is kept in an interim file next to it
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+
+        /// diagnostic.data directory to write rotating files to
+        #[arg(short, long)]
+        dir: Option<PathBuf>,
+
+        /// Milliseconds between samples
+        #[arg(long, default_value_t = 1000, value_parser = clap::value_parser!(u64).range(1..))]
+        period_ms: u64,
+
+        /// Stop after this many seconds, run until killed if not present
+        #[arg(long)]
+        seconds: Option<u64>,
+    },
 }
 
 // fn analyze_doc(doc: &Document, names: &mut HashSet<String>) -> HashMap<String, i64> {
@@ -227,8 +347,8 @@ fn analyze_ref(doc: &RawDocument, deltas: &mut HashMap<String, Vec<i64>>) -> Res
         if sub_delta > 10 {
             println!(
                 "sub_delta: {}, {}, {}, {}",
-                sub.get_datetime("start")?.to_string(),
-                sub.get_datetime("end")?.to_string(),
+                sub.get_datetime("start")?,
+                sub.get_datetime("end")?,
                 key,
                 sub_delta
             );
@@ -251,7 +371,8 @@ fn analyze_doc(doc: &RawDocument, deltas: &mut HashMap<String, Vec<i64>>) -> Res
     // println!("delta: {:?}", delta);
     // print!("{}", serde_json  ::to_string_pretty(doc)?);
 
-    deltas.get_mut("base").unwrap().push(delta);
+    // The reference document may have been trimmed by a time window so keys may be new here
+    deltas.entry("base".to_owned()).or_default().push(delta);
     // v.insert("base".to_owned(), delta);
 
     for element_ret in doc.iter_elements() {
@@ -267,12 +388,129 @@ fn analyze_doc(doc: &RawDocument, deltas: &mut HashMap<String, Vec<i64>>) -> Res
             - sub.get_datetime("start")?.timestamp_millis();
 
         // println!("sub_delta2: {:?}: {:?}", key, sub_delta);
-        deltas.get_mut(key).unwrap().push(sub_delta);
+        deltas.entry(key.to_owned()).or_default().push(sub_delta);
     }
 
     Ok(())
 }
 
+type BlockReader = ftdc::TimeFilter<ftdc::DiagnosticDataReader>;
+
+/**
+ * Open either a single FTDC file or a whole diagnostic.data directory
+ */
+fn open_reader(input: &Path, opts: ReadOptions, range: TimeRange) -> Result<BlockReader> {
+    Ok(ftdc::DiagnosticDataReader::new(input)?
+        .with_recovery(opts.recover)
+        .with_index(opts.index)
+        .with_time_range(range))
+}
+
+type DecodedReader = ftdc::ParallelDecoder<BlockReader, ftdc::SourcedBlock>;
+
+/**
+ * Open a reader that decodes metrics blocks on a pool of threads
+ */
+fn open_decoder(
+    input: &Path,
+    opts: ReadOptions,
+    range: TimeRange,
+    threads: Option<usize>,
+) -> Result<DecodedReader> {
+    Ok(ftdc::ParallelDecoder::new(
+        open_reader(input, opts, range)?,
+        threads.unwrap_or_else(ftdc::parallel::default_threads),
+    ))
+}
+
+/// The decoded metrics of a metrics block from a `DecodedReader`
+fn decoded(
+    metrics: Option<Result<DecodedMetricBlock, FtdcError>>,
+) -> Result<DecodedMetricBlock, FtdcError> {
+    metrics.expect("ParallelDecoder decodes every metrics block")
+}
+
+/**
+ * Find the time of the last sample, used to resolve times relative to the end of the data
+ */
+fn last_sample_time(input: &Path, opts: ReadOptions) -> Result<Option<DateTime<Utc>>> {
+    let recover = opts.recover;
+
+    if opts.index {
+        let rdr = ftdc::DiagnosticDataReader::new(input)?;
+        for file in rdr.files().iter().rev() {
+            if let Some(t) =
+                ftdc::BlockIndex::open(file, recover, ftdc::DecodeLimits::default())?.last_sample()
+            {
+                return Ok(Some(t));
+            }
+        }
+        return Ok(None);
+    }
+
+    let mut last_date = None;
+    let mut last_metrics = None;
+
+    for item in open_reader(input, opts, TimeRange::default())? {
+        let Some(block) = recoverable(item, recover)? else {
+            continue;
+        };
+
+        last_date = block_date(&block.block).or(last_date);
+        if let ftdc::RawBSONBlock::Metrics(doc) = block.block {
+            last_metrics = Some(doc);
+        }
+    }
+
+    if let Some(doc) = last_metrics {
+        let db = ftdc::reader::decode_metric_block(&doc)?;
+
+        if let Some(idx) = db.start_metric_index() {
+            let millis = if db.sample_count > 0 {
+                db.sample_value(db.sample_count - 1, idx as i32) as i64
+            } else {
+                db.ref_doc.get_datetime("start")?.timestamp_millis()
+            };
+
+            return Ok(DateTime::from_timestamp_millis(millis));
+        }
+    }
+
+    Ok(last_date)
+}
+
+fn resolve_window(input: &Path, opts: ReadOptions, window: &TimeWindow) -> Result<TimeRange> {
+    let relative = [window.start, window.end]
+        .iter()
+        .flatten()
+        .any(|t| t.is_relative());
+
+    let end_of_data = if relative {
+        last_sample_time(input, opts)?.unwrap_or_else(Utc::now)
+    } else {
+        Utc::now()
+    };
+
+    Ok(TimeRange::new(
+        window.start.map(|t| t.resolve(end_of_data)),
+        window.end.map(|t| t.resolve(end_of_data)),
+    ))
+}
+
+/**
+ * In recovery mode report the error and skip the item, otherwise fail
+ */
+fn recoverable<T>(result: Result<T, FtdcError>, recover: bool) -> Result<Option<T>> {
+    match result {
+        Ok(v) => Ok(Some(v)),
+        Err(e) if recover => {
+            eprintln!("Warning: {}", e);
+            Ok(None)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
 fn format_doc(format: OutputFormat, doc: &RawDocument, writer: &mut dyn Write) -> Result<()> {
     match format {
         OutputFormat::Bson => {
@@ -287,26 +525,38 @@ fn format_doc(format: OutputFormat, doc: &RawDocument, writer: &mut dyn Write) -
 }
 
 fn convert_file(
-    rdr: &mut ftdc::BSONBlockReader<File>,
+    rdr: &mut DecodedReader,
     format: OutputFormat,
+    recover: bool,
+    range: TimeRange,
     writer: &mut dyn Write,
 ) -> Result<()> {
     let mut buf_writer = BufWriter::new(writer);
 
-    let mut scratch = Vec::<u8>::new();
-    scratch.reserve(1024 * 1024);
-
     for item in rdr {
-        match item {
-            ftdc::RawBSONBlock::Metadata(doc) => {
+        let Some(ftdc::DecodedBlock { block, metrics }) = recoverable(item, recover)? else {
+            continue;
+        };
+        match block.block {
+            ftdc::RawBSONBlock::Metadata(doc) | ftdc::RawBSONBlock::PeriodicMetadata(doc) => {
                 format_doc(format, &doc, &mut buf_writer)?;
             }
             ftdc::RawBSONBlock::Metrics(doc) => {
-                let rdr = ftdc::MetricsReader::new(&doc)?;
-                for m_item in rdr.into_iter() {
+                let Some(rdr) = recoverable(
+                    decoded(metrics).map(|db| {
+                        ftdc::MetricsReader::from_decoded(&doc, db).with_time_range(range)
+                    }),
+                    recover,
+                )?
+                else {
+                    continue;
+                };
+                for (_, m_item) in rdr.into_iter() {
                     match m_item {
                         MetricsDocument::Reference(d1) => {
-                            format_doc(format, &d1, &mut buf_writer)?;
+                            if range.contains_doc(&d1) {
+                                format_doc(format, &d1, &mut buf_writer)?;
+                            }
                         }
                         MetricsDocument::Metrics(d1) => {
                             format_doc(format, &d1, &mut buf_writer)?;
@@ -327,12 +577,12 @@ const SENTINEL_VALUE: usize = 0xffffffff;
 // }
 
 trait FlatOutputWriter {
-    fn write_header(&mut self, header_names: &Vec<String>) -> Result<()>;
+    fn write_header(&mut self, header_names: &[String]) -> Result<()>;
     fn write_row(
         &mut self,
-        metrics: &Vec<u64>,
-        map_vec: &Vec<usize>,
-        start_time: u64,
+        metrics: &[MetricValue],
+        map_vec: &[usize],
+        sample_time: DateTime<Utc>,
     ) -> Result<()>;
 }
 
@@ -341,38 +591,38 @@ struct CSVWriter<'a> {
 }
 
 impl<'a> FlatOutputWriter for CSVWriter<'a> {
-    fn write_header(&mut self, header_names: &Vec<String>) -> Result<()> {
+    fn write_header(&mut self, header_names: &[String]) -> Result<()> {
         let header_names_comma = header_names.join(",");
 
         // println!("Commas {}", count_commas(&header_names_comma));
 
         // Make csv header
-        self.buf_writer.write(header_names_comma.as_bytes())?;
-        self.buf_writer.write("\n".as_bytes())?;
+        self.buf_writer.write_all(header_names_comma.as_bytes())?;
+        self.buf_writer.write_all("\n".as_bytes())?;
 
         Ok(())
     }
 
     fn write_row(
         &mut self,
-        metrics: &Vec<u64>,
-        map_vec: &Vec<usize>,
-        _start_time: u64,
+        metrics: &[MetricValue],
+        map_vec: &[usize],
+        _sample_time: DateTime<Utc>,
     ) -> Result<()> {
         // let mut s = String::new();
-        for (_, &mapping) in map_vec.iter().enumerate() {
+        for &mapping in map_vec.iter() {
             if mapping != SENTINEL_VALUE {
                 write!(self.buf_writer, "{},", metrics[mapping])?;
                 // s.push_str(&format!("{},", metrics[mapping] ));
             } else {
-                self.buf_writer.write("0,".as_bytes())?;
+                self.buf_writer.write_all("0,".as_bytes())?;
                 // s.push_str("0,");
             }
         }
 
         // println!("Commas2 {}", count_commas(&s));
 
-        self.buf_writer.write("0\n".as_bytes())?;
+        self.buf_writer.write_all("0\n".as_bytes())?;
 
         Ok(())
     }
@@ -384,7 +634,7 @@ struct PrometheusWriter<'a> {
 }
 
 impl<'a> FlatOutputWriter for PrometheusWriter<'a> {
-    fn write_header(&mut self, header_names_ref: &Vec<String>) -> Result<()> {
+    fn write_header(&mut self, header_names_ref: &[String]) -> Result<()> {
         let header_names: Vec<String> = header_names_ref
             .iter()
             .map(|x| x.replace(" ", "_"))
@@ -392,7 +642,7 @@ impl<'a> FlatOutputWriter for PrometheusWriter<'a> {
 
         for header in header_names.iter() {
             self.buf_writer
-                .write(format!("# TYPE {} counter\n", header).as_bytes())?;
+                .write_all(format!("# TYPE {} counter\n", header).as_bytes())?;
         }
 
         self.header_names = Some(header_names);
@@ -402,33 +652,44 @@ impl<'a> FlatOutputWriter for PrometheusWriter<'a> {
 
     fn write_row(
         &mut self,
-        metrics: &Vec<u64>,
-        map_vec: &Vec<usize>,
-        start_time: u64,
+        metrics: &[MetricValue],
+        map_vec: &[usize],
+        sample_time: DateTime<Utc>,
     ) -> Result<()> {
+        let timestamp = sample_time.timestamp_millis();
         let header_names = self.header_names.as_ref().unwrap();
         for (header_index, &mapping) in map_vec.iter().enumerate() {
             if mapping != SENTINEL_VALUE {
-                write!(
-                    self.buf_writer,
-                    "{} {} {}\n",
-                    header_names[header_index], metrics[mapping], start_time
-                )?;
+                // Prometheus only has numbers, booleans are 0/1 and dates are milliseconds
+                let header = &header_names[header_index];
+                match metrics[mapping] {
+                    MetricValue::F64(v) => {
+                        writeln!(self.buf_writer, "{} {} {}", header, v, timestamp)?
+                    }
+                    v => writeln!(self.buf_writer, "{} {} {}", header, v.as_i64(), timestamp)?,
+                }
             }
         }
-        write!(self.buf_writer, "\n")?;
+        writeln!(self.buf_writer)?;
 
         Ok(())
     }
 }
 
+/// Identifies a flat output column, a metric's path and its occurrence
+type ColumnKey = (MetricPath, usize);
+
 fn convert_flat_file(
     input: PathBuf,
     format: FlatOutputFormat,
     sample: u16,
+    opts: ReadOptions,
+    range: TimeRange,
+    threads: Option<usize>,
     writer: &mut dyn Write,
 ) -> Result<()> {
-    let first_rdr = ftdc::BSONBlockReader::new(input.to_str().unwrap()).unwrap();
+    let recover = opts.recover;
+    let first_rdr = open_decoder(&input, opts, range, threads)?;
 
     let mut flat_writer: Box<dyn FlatOutputWriter> = match format {
         FlatOutputFormat::CSV => Box::new(CSVWriter {
@@ -440,111 +701,96 @@ fn convert_flat_file(
         }),
     };
 
-    let mut scratch = Vec::<u8>::new();
-    scratch.reserve(1024 * 1024);
-
-    let mut path_set: BTreeSet<String> = BTreeSet::new();
+    let mut path_set: BTreeSet<(String, MetricPath, usize)> = BTreeSet::new();
 
     // Get the list of columns across ALL blocks
     for item in first_rdr {
-        match item {
-            ftdc::RawBSONBlock::Metadata(_) => {
+        let Some(ftdc::DecodedBlock { block, metrics }) = recoverable(item, recover)? else {
+            continue;
+        };
+        match block.block {
+            ftdc::RawBSONBlock::Metadata(_) | ftdc::RawBSONBlock::PeriodicMetadata(_) => {
                 println!("Skipping metadata blocks")
             }
             ftdc::RawBSONBlock::Metrics(doc) => {
-                let rdr = ftdc::VectorMetricsReader::new(&doc)?;
-                for m_item in rdr.into_iter() {
-                    match m_item {
-                        VectorMetricsDocument::Reference(d1) => {
-                            let paths = extract_metrics_paths_raw(&d1);
-
-                            let mut dups: BTreeSet<String> = BTreeSet::new();
-
-                            for p in paths.iter() {
-                                if !dups.insert(p.name.clone()) {
-                                    println!("Duplicate: {}", p.name);
-                                }
-                            }
-                            // println!("Paths1: {}", paths.len());
-                            let mut ps: BTreeSet<String> =
-                                paths.into_iter().map(|x| x.name).collect();
-                            // println!("Paths1s: {}", ps.len());
-
-                            path_set.append(&mut ps);
-
-                            break;
-                        }
-                        VectorMetricsDocument::Metrics(_) => {
-                            panic!("Should not hit this since we break early");
-                        }
-                    };
+                let Some(rdr) = recoverable(
+                    decoded(metrics).map(|db| {
+                        ftdc::VectorMetricsReader::from_decoded(&doc, db).with_time_range(range)
+                    }),
+                    recover,
+                )?
+                else {
+                    continue;
+                };
+                // Repeated keys and keys with dots are distinct columns
+                for p in rdr.decoded_block.metrics() {
+                    path_set.insert((
+                        p.unique_name().replace(",", ""),
+                        p.path.clone(),
+                        p.occurrence,
+                    ));
                 }
             }
         }
     }
 
-    // Make a map of name -> column #
-    let mut header_names: Vec<String> = path_set
-        .iter()
-        .map(|x| x.clone().replace(",", ""))
-        .collect();
-    header_names.sort();
+    // Make a map of metric -> column #, columns are sorted by name
+    let mut header_names: Vec<String> = path_set.iter().map(|x| x.0.clone()).collect();
 
-    let path_index: HashMap<String, usize> = header_names
-        .iter()
+    let path_index: HashMap<ColumnKey, usize> = path_set
+        .into_iter()
         .enumerate()
-        .map(|(x, y)| (y.clone(), x))
+        .map(|(x, (_, path, occurrence))| ((path, occurrence), x))
         .collect();
 
     // Be lazy so I don't have to track the first or last comma
     header_names.push("ignore_trailer".into());
 
     // println!("Paths: {}", header_names.len());
-    let mut start_index = 0;
-
-    for (idx, hn) in header_names.iter().enumerate() {
+    for hn in header_names.iter() {
         if hn.contains(",") {
             panic!("Header name has a comma which is not escaped {}", hn);
         }
-
-        if hn == ".start" {
-            start_index = idx;
-        }
     }
 
     flat_writer.write_header(&header_names)?;
 
-    let second_rdr = ftdc::BSONBlockReader::new(input.to_str().unwrap()).unwrap();
+    let second_rdr = open_decoder(&input, opts, range, threads)?;
 
     for item in second_rdr {
-        match item {
-            ftdc::RawBSONBlock::Metadata(_) => {
+        let Some(ftdc::DecodedBlock { block, metrics }) = recoverable(item, recover)? else {
+            continue;
+        };
+        match block.block {
+            ftdc::RawBSONBlock::Metadata(_) | ftdc::RawBSONBlock::PeriodicMetadata(_) => {
                 // ignore
             }
             ftdc::RawBSONBlock::Metrics(doc) => {
-                let rdr = ftdc::VectorMetricsReader::new(&doc)?;
+                let Some(rdr) = recoverable(
+                    decoded(metrics).map(|db| {
+                        ftdc::VectorMetricsReader::from_decoded(&doc, db).with_time_range(range)
+                    }),
+                    recover,
+                )?
+                else {
+                    continue;
+                };
 
                 let mut col_list_map: Vec<usize> = vec![SENTINEL_VALUE; path_index.len()];
-                let mut start_time_idx = 0;
+                let mut paths = Vec::new();
 
-                for (idx, m_item) in rdr.into_iter().enumerate() {
+                for (idx, (t, m_item)) in rdr.into_iter().enumerate() {
                     match m_item {
                         VectorMetricsDocument::Reference(d1) => {
-                            let paths = extract_metrics_paths_raw(&d1);
-
-                            // println!("Paths: {}", paths.len());
-                            // list of col names
-                            let block_cols: Vec<String> =
-                                paths.into_iter().map(|x| x.name.replace(",", "")).collect();
+                            paths = extract_metrics_paths_raw(&d1)?;
 
-                            // block col name -> global col index
-                            let block_col_to_global_index: Vec<usize> = block_cols
+                            // block col -> global col index
+                            let block_col_to_global_index: Vec<usize> = paths
                                 .iter()
                                 .map(|x| {
-                                    path_index
-                                        .get(x)
+                                    *path_index
+                                        .get(&(x.path.clone(), x.occurrence))
                                         .expect("Corruption between first and second pass")
-                                        .clone()
                                 })
                                 .collect();
 
@@ -552,26 +798,26 @@ fn convert_flat_file(
                                 block_col_to_global_index.iter().enumerate()
                             {
                                 col_list_map[global_block_idx] = local_block_index;
-
-                                if global_block_idx == start_index {
-                                    start_time_idx = local_block_index
-                                }
                             }
 
-                            let metrics = extract_metrics_raw(&d1);
+                            if !range.contains_doc(&d1) {
+                                continue;
+                            }
 
-                            let start_time = metrics[start_time_idx];
+                            let metrics = extract_metrics_raw(&d1)?;
 
-                            flat_writer.write_row(&metrics, &col_list_map, start_time)?;
+                            flat_writer.write_row(
+                                &typed_metrics(&paths, &metrics),
+                                &col_list_map,
+                                t,
+                            )?;
                         }
                         VectorMetricsDocument::Metrics(d1) => {
                             if idx.rem_euclid(sample as usize) != 0 {
                                 continue;
                             }
 
-                            let start_time = d1[start_time_idx];
-
-                            flat_writer.write_row(&d1, &col_list_map, start_time)?;
+                            flat_writer.write_row(&typed_metrics(&paths, &d1), &col_list_map, t)?;
                         }
                     };
                 }
@@ -612,12 +858,32 @@ fn parse_prom_line(line: &str) -> Option<PromRecord> {
     })
 }
 
+fn write_prom_sample(
+    writer: &mut BSONBlockWriter<File>,
+    records: &mut Vec<(String, f64)>,
+    timestamp: i64,
+) -> Result<()> {
+    records.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let samples: IndexMap<String, f64> = records.drain(..).collect();
+
+    let start = Utc.timestamp_millis_opt(timestamp).unwrap();
+
+    let doc = bson::doc![
+        "start" : start,
+        "serverStatus" : to_document(&samples).expect("Expect conversion to bson for metrics never fails"),
+        "end" : start,
+    ];
+
+    writer.add_sample(&doc, start)
+}
+
 fn convert_prom_file(input: PathBuf, output: PathBuf) -> Result<()> {
     let file = File::open(input)?;
 
     let reader = BufReader::new(file);
 
-    let mut writer = BSONBlockWriter::new_file(&output, 10).unwrap();
+    let mut writer = BSONBlockWriter::new_file(&output, 10)?;
 
     let mut records: Vec<(String, f64)> = Vec::with_capacity(500);
     let mut last_timestamp: i64 = 0;
@@ -625,82 +891,238 @@ fn convert_prom_file(input: PathBuf, output: PathBuf) -> Result<()> {
     for line in reader.lines() {
         let line = line?;
         if let Some(record) = parse_prom_line(&line) {
-            if last_timestamp == 0 {
-                last_timestamp = record.timestamp;
-                continue;
+            if last_timestamp != record.timestamp && !records.is_empty() {
+                write_prom_sample(&mut writer, &mut records, last_timestamp)?;
             }
 
-            if last_timestamp != record.timestamp {
-                records.sort_by(|a, b| a.0.cmp(&b.0));
+            last_timestamp = record.timestamp;
+            records.push((record.label, record.value));
+        }
+    }
 
-                let samples: IndexMap<String, f64> = records.iter().cloned().collect();
+    if !records.is_empty() {
+        write_prom_sample(&mut writer, &mut records, last_timestamp)?;
+    }
+
+    writer.close()
+}
 
-                let start = Utc.timestamp_millis_opt(last_timestamp).unwrap();
+/// Sample /proc until `seconds` have passed, or until the process is killed if None
+fn collect_system_metrics(
+    controller: FtdcController,
+    period: Duration,
+    seconds: Option<u64>,
+) -> Result<()> {
+    let mut controller = controller
+        .with_period(period)
+        .with_collector(Box::new(SystemMetricsCollector::new()))
+        .with_metadata_collector(Box::new(HostInfoCollector::new()));
 
-                let doc = bson::doc![
-                    "start" : start,
-                    "serverStatus" : to_document(&samples).expect("Expect conversion to bson for metrics never fails"),
-                    "end" : start,
-                ];
+    controller.start()?;
 
-                writer.add_sample(&doc, start)?;
+    let deadline = seconds.map(|s| Instant::now() + Duration::from_secs(s));
+    while controller.is_running() && deadline.is_none_or(|d| Instant::now() < d) {
+        std::thread::sleep(Duration::from_millis(100).min(period));
+    }
 
-                records.clear();
-                last_timestamp = record.timestamp;
-            } else {
-                records.push((record.label, record.value));
+    controller.shutdown()
+}
+
+fn block_type_name(block_type: Option<i32>) -> &'static str {
+    match block_type {
+        Some(0) => "metadata",
+        Some(1) => "metrics",
+        Some(2) => "periodic_metadata",
+        Some(_) => "unknown",
+        None => "unreadable",
+    }
+}
+
+fn write_report(
+    file: &Path,
+    report: &BlockReport,
+    format: ReportFormat,
+    writer: &mut dyn Write,
+) -> Result<()> {
+    let date = report.date.map(|d| d.to_rfc3339());
+
+    match format {
+        ReportFormat::Text => {
+            write!(
+                writer,
+                "{}: offset {} {}",
+                file.display(),
+                report.offset,
+                block_type_name(report.block_type)
+            )?;
+            if let Some(date) = date {
+                write!(writer, " {}", date)?;
+            }
+            if let (Some(m), Some(s)) = (report.metrics_count, report.sample_count) {
+                write!(writer, " metrics={} samples={}", m, s)?;
+            }
+            writeln!(
+                writer,
+                " {}",
+                if report.is_valid() { "ok" } else { "FAILED" }
+            )?;
+
+            for issue in &report.issues {
+                writeln!(writer, "  {}: {}", issue.check.name(), issue.message)?;
+            }
+        }
+        ReportFormat::Json => {
+            let issues: Vec<serde_json::Value> = report
+                .issues
+                .iter()
+                .map(|i| serde_json::json!({"check": i.check.name(), "message": i.message}))
+                .collect();
+
+            serde_json::to_writer(
+                &mut *writer,
+                &serde_json::json!({
+                    "file": file.display().to_string(),
+                    "offset": report.offset,
+                    "type": block_type_name(report.block_type),
+                    "date": date,
+                    "metrics_count": report.metrics_count,
+                    "sample_count": report.sample_count,
+                    "valid": report.is_valid(),
+                    "issues": issues,
+                }),
+            )?;
+            writeln!(writer)?;
+        }
+    }
+
+    Ok(())
+}
+
+/**
+ * Validate every block of every file in order, reading past corrupt blocks so each one is
+ * reported. Fails at the end if any block had a problem.
+ */
+fn validate_files(input: &Path, format: ReportFormat, writer: &mut dyn Write) -> Result<()> {
+    let mut writer = BufWriter::new(writer);
+    let mut blocks = 0;
+    let mut failed = 0;
+    let mut previous: Option<DateTime<Utc>> = None;
+
+    for file in ftdc::DiagnosticDataReader::new(input)?.files() {
+        let mut rdr = ftdc::BSONBlockReader::new_reader(File::open(file)?)?.with_recovery(true);
+
+        loop {
+            let offset = rdr.offset();
+            let report = match rdr.next() {
+                None => break,
+                Some(Ok(block)) => {
+                    let report = validate_block(&block, offset, previous);
+                    if let (ftdc::RawBSONBlock::Metrics(_), Some(date)) = (&block, report.date) {
+                        previous = Some(date);
+                    }
+                    report
+                }
+                Some(Err(e)) => BlockReport::read_error(&e),
+            };
+
+            blocks += 1;
+            if !report.is_valid() {
+                failed += 1;
             }
+            write_report(file, &report, format, &mut writer)?;
         }
     }
 
+    if format == ReportFormat::Text {
+        writeln!(writer, "{} blocks, {} with problems", blocks, failed)?;
+    }
+    writer.flush()?;
+
+    if failed > 0 {
+        return Err(anyhow!("{} of {} blocks failed validation", failed, blocks));
+    }
+
     Ok(())
 }
 
 fn main() -> Result<()> {
     let args = Cli::parse();
     // println!("{:?}", args);
+    let recover = args.recover;
+    let opts = ReadOptions {
+        recover,
+        index: args.index,
+    };
 
     match args.command {
         Commands::Convert {
             input,
             format,
             output,
+            threads,
+            window,
         } => {
-            let mut rdr = ftdc::BSONBlockReader::new(input.to_str().unwrap()).unwrap();
+            let range = resolve_window(&input, opts, &window)?;
+            let mut rdr = open_decoder(&input, opts, range, threads)?;
 
             match output {
                 Some(f) => {
-                    convert_file(&mut rdr, format, &mut File::create(f)?)?;
+                    convert_file(&mut rdr, format, recover, range, &mut File::create(f)?)?;
                 }
                 None => {
-                    convert_file(&mut rdr, format, &mut stdout().lock())?;
+                    convert_file(&mut rdr, format, recover, range, &mut stdout().lock())?;
                 }
             };
         }
-        Commands::Stats { input } => {
+        Commands::Stats {
+            input,
+            threads,
+            window,
+        } => {
+            let range = resolve_window(&input, opts, &window)?;
             let mut total = 0;
             let mut blocks = 0;
             let mut metadata = 0;
+            let mut periodic_metadata = 0;
             let mut metric_docs = 0;
             let mut reference_docs = 0;
 
-            let rdr = ftdc::BSONBlockReader::new(input.to_str().unwrap()).unwrap();
+            let rdr = open_decoder(&input, opts, range, threads)?;
 
             for item in rdr {
+                let Some(ftdc::DecodedBlock { block, metrics }) = recoverable(item, recover)?
+                else {
+                    continue;
+                };
+
                 blocks += 1;
 
-                match item {
+                match block.block {
                     ftdc::RawBSONBlock::Metadata(_) => {
                         metadata += 1;
                         total += 1;
                     }
+                    ftdc::RawBSONBlock::PeriodicMetadata(_) => {
+                        periodic_metadata += 1;
+                        total += 1;
+                    }
                     ftdc::RawBSONBlock::Metrics(doc) => {
-                        let rdr = ftdc::MetricsReader::new(&doc)?;
-                        for m_item in rdr.into_iter() {
+                        let Some(rdr) = recoverable(
+                            decoded(metrics).map(|db| {
+                                ftdc::MetricsReader::from_decoded(&doc, db).with_time_range(range)
+                            }),
+                            recover,
+                        )?
+                        else {
+                            continue;
+                        };
+                        for (_, m_item) in rdr.into_iter() {
                             match m_item {
-                                MetricsDocument::Reference(_) => {
-                                    total += 1;
-                                    reference_docs += 1;
+                                MetricsDocument::Reference(d1) => {
+                                    if range.contains_doc(&d1) {
+                                        total += 1;
+                                        reference_docs += 1;
+                                    }
                                 }
                                 MetricsDocument::Metrics(_) => {
                                     total += 1;
@@ -712,24 +1134,34 @@ fn main() -> Result<()> {
                 }
             }
 
-            println!("Blocks, Metadata, Reference Docs, Metrics Docs, Total");
+            println!("Blocks, Metadata, Periodic Metadata, Reference Docs, Metrics Docs, Total");
             println!(
-                "{}, {}, {}, {}, {}",
-                blocks, metadata, reference_docs, metric_docs, total
+                "{}, {}, {}, {}, {}, {}",
+                blocks, metadata, periodic_metadata, reference_docs, metric_docs, total
             );
         }
-        Commands::BlockStats { input } => {
-            let rdr = ftdc::BSONBlockReader::new(input.to_str().unwrap()).unwrap();
+        Commands::BlockStats { input, window } => {
+            let range = resolve_window(&input, opts, &window)?;
+            let rdr = open_reader(&input, opts, range)?;
 
             println!("Type, Chunk Size, Ref Size, Metrics, Samples");
 
             for item in rdr {
-                match item {
+                let Some(block) = recoverable(item, recover)? else {
+                    continue;
+                };
+                match block.block {
                     ftdc::RawBSONBlock::Metadata(_) => {
                         println!("Metadata, {}, {}, {}, {}", 0, 0, 0, 0);
                     }
+                    ftdc::RawBSONBlock::PeriodicMetadata(_) => {
+                        println!("PeriodicMetadata, {}, {}, {}, {}", 0, 0, 0, 0);
+                    }
                     ftdc::RawBSONBlock::Metrics(doc) => {
-                        let rdr = ftdc::MetricsReader::new(&doc)?;
+                        let Some(rdr) = recoverable(ftdc::MetricsReader::new(&doc), recover)?
+                        else {
+                            continue;
+                        };
 
                         println!(
                             "Metrics, {}, {}, {}, {}",
@@ -742,20 +1174,32 @@ fn main() -> Result<()> {
                 }
             }
         }
-        Commands::Timings { input } => {
+        Commands::Timings { input, window } => {
+            let range = resolve_window(&input, opts, &window)?;
             let mut deltas = HashMap::<String, Vec<i64>>::new();
 
-            let rdr = ftdc::BSONBlockReader::new(input.to_str().unwrap()).unwrap();
+            let rdr = open_reader(&input, opts, range)?;
 
             for item in rdr {
-                match item {
-                    ftdc::RawBSONBlock::Metadata(_) => {}
+                let Some(block) = recoverable(item, recover)? else {
+                    continue;
+                };
+                match block.block {
+                    ftdc::RawBSONBlock::Metadata(_) | ftdc::RawBSONBlock::PeriodicMetadata(_) => {}
                     ftdc::RawBSONBlock::Metrics(doc) => {
-                        let rdr = ftdc::MetricsReader::new(&doc)?;
-                        for m_item in rdr.into_iter() {
+                        let Some(rdr) = recoverable(
+                            ftdc::MetricsReader::new(&doc).map(|r| r.with_time_range(range)),
+                            recover,
+                        )?
+                        else {
+                            continue;
+                        };
+                        for (_, m_item) in rdr.into_iter() {
                             match m_item {
                                 MetricsDocument::Reference(d1) => {
-                                    analyze_ref(&d1, &mut deltas)?;
+                                    if range.contains_doc(&d1) {
+                                        analyze_ref(&d1, &mut deltas)?;
+                                    }
                                 }
                                 MetricsDocument::Metrics(d1) => {
                                     analyze_doc(&d1, &mut deltas)?;
@@ -773,19 +1217,92 @@ fn main() -> Result<()> {
             format,
             output,
             sample,
+            threads,
+            window,
         } => {
+            let range = resolve_window(&input, opts, &window)?;
             match output {
                 Some(f) => {
-                    convert_flat_file(input, format, sample.unwrap_or(1), &mut File::create(f)?)?;
+                    convert_flat_file(
+                        input,
+                        format,
+                        sample.unwrap_or(1),
+                        opts,
+                        range,
+                        threads,
+                        &mut File::create(f)?,
+                    )?;
                 }
                 None => {
-                    convert_flat_file(input, format, sample.unwrap_or(1), &mut stdout().lock())?;
+                    convert_flat_file(
+                        input,
+                        format,
+                        sample.unwrap_or(1),
+                        opts,
+                        range,
+                        threads,
+                        &mut stdout().lock(),
+                    )?;
                 }
             };
         }
+        Commands::Index { input } => {
+            let rdr = ftdc::DiagnosticDataReader::new(&input)?;
+
+            println!(
+                "File, Offset, Type, Date, First Sample, Last Sample, Metrics, Samples, Schema"
+            );
+
+            for file in rdr.files() {
+                let index = ftdc::BlockIndex::open(file, recover, ftdc::DecodeLimits::default())?;
+
+                for e in index.entries() {
+                    let fmt =
+                        |t: Option<DateTime<Utc>>| t.map(|t| t.to_rfc3339()).unwrap_or_default();
+
+                    println!(
+                        "{}, {}, {}, {}, {}, {}, {}, {}, {:016x}",
+                        file.display(),
+                        e.offset,
+                        e.block_type,
+                        fmt(e.date),
+                        fmt(e.first_sample),
+                        fmt(e.last_sample),
+                        e.metrics_count,
+                        e.sample_count,
+                        e.schema
+                    );
+                }
+            }
+        }
+        Commands::Validate { input, format } => {
+            validate_files(&input, format, &mut stdout().lock())?;
+        }
         Commands::ConvertProm { input, output } => {
             convert_prom_file(input, output)?;
         }
+        Commands::Collect {
+            output,
+            dir,
+            period_ms,
+            seconds,
+        } => {
+            let controller = match (output, dir) {
+                (Some(output), _) => {
+                    let writer =
+                        BSONBlockWriter::new(File::create(&output)?, WriterOptions::default())
+                            .with_interim_file(
+                                interim_file_path(&output),
+                                DEFAULT_INTERIM_SAMPLES,
+                            )?;
+                    FtdcController::new(writer)
+                }
+                (None, Some(dir)) => FtdcController::new(DiagnosticDataWriter::new(&dir)?),
+                (None, None) => unreachable!("clap requires an output or a directory"),
+            };
+
+            collect_system_metrics(controller, Duration::from_millis(period_ms), seconds)?;
+        }
     }
 
     Ok(())